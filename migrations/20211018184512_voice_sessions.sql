@@ -0,0 +1,22 @@
+-- Add migration script here
+CREATE TABLE "voice_sessions" (
+  "guild_id" bigint PRIMARY KEY,
+  "voice_channel" bigint NOT NULL,
+  "text_channel" bigint NOT NULL,
+  "elapsed" bigint NOT NULL DEFAULT 0,
+  "updated_at" timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE "voice_queue" (
+  "guild_id" bigint NOT NULL,
+  "position" int NOT NULL,
+  "source_url" varchar NOT NULL,
+  "title" varchar,
+  "artist" varchar,
+  "thumbnail" varchar,
+  "duration" bigint,
+  "requester" bigint NOT NULL,
+  PRIMARY KEY ("guild_id", "position")
+);
+
+ALTER TABLE "voice_queue" ADD FOREIGN KEY ("guild_id") REFERENCES "voice_sessions" ("guild_id") ON DELETE CASCADE;
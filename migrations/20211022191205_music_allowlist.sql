@@ -0,0 +1,23 @@
+-- Add migration script here
+CREATE TABLE "music_allowlist" (
+  "guild_id" bigint PRIMARY KEY,
+  "added_by" bigint,
+  "added_at" timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE "bot_settings" (
+  "name" varchar PRIMARY KEY,
+  "value" varchar NOT NULL
+);
+
+-- guilds which were allowed before the allowlist moved into the database
+INSERT INTO "music_allowlist" ("guild_id") VALUES
+  (228625269101953035),
+  (290284538733658112),
+  (781421814601089055),
+  (418093857394262020),
+  (381880193251409931),
+  (828548609322254357),
+  (720025586034147338),
+  (749034687392907344),
+  (553948136629075968);
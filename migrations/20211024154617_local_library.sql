@@ -0,0 +1,14 @@
+-- Add migration script here
+CREATE TABLE "local_tracks" (
+  "path" varchar PRIMARY KEY,
+  "title" varchar,
+  "artist" varchar,
+  "album" varchar,
+  "duration" bigint,
+  "hash" varchar NOT NULL,
+  "size" bigint NOT NULL,
+  "modified" bigint NOT NULL,
+  "scanned_at" timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX ON "local_tracks" ("hash");
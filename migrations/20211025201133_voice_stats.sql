@@ -0,0 +1,25 @@
+-- Add migration script here
+CREATE TABLE "voice_stat_sessions" (
+  "id" serial PRIMARY KEY,
+  "guild_id" bigint NOT NULL,
+  "peak_speakers" int NOT NULL DEFAULT 0,
+  "started_at" timestamptz NOT NULL DEFAULT now(),
+  "ended_at" timestamptz
+);
+
+CREATE TABLE "voice_stats" (
+  "session_id" int NOT NULL REFERENCES "voice_stat_sessions" ("id") ON DELETE CASCADE,
+  "guild_id" bigint NOT NULL,
+  "user_id" bigint NOT NULL,
+  "talk_time" bigint NOT NULL DEFAULT 0,
+  "channel_time" bigint NOT NULL DEFAULT 0,
+  "updated_at" timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY ("session_id", "user_id")
+);
+
+CREATE INDEX ON "voice_stats" ("guild_id", "updated_at");
+
+CREATE TABLE "voice_stats_opt_out" (
+  "user_id" bigint PRIMARY KEY,
+  "opted_out_at" timestamptz NOT NULL DEFAULT now()
+);
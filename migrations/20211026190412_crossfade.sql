@@ -0,0 +1,3 @@
+-- Add migration script here
+alter table guilds
+add crossfade int;
@@ -0,0 +1,14 @@
+-- Add migration script here
+CREATE TABLE "play_history" (
+  "id" serial PRIMARY KEY,
+  "guild_id" bigint NOT NULL,
+  "source_url" varchar NOT NULL,
+  "title" varchar,
+  "artist" varchar,
+  "thumbnail" varchar,
+  "duration" bigint,
+  "requester" bigint NOT NULL,
+  "played_at" timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX ON "play_history" ("guild_id", "played_at");
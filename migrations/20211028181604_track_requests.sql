@@ -0,0 +1,4 @@
+-- Add migration script here
+alter table voice_queue
+add requested_in bigint,
+add requested_at timestamptz;
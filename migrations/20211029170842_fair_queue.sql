@@ -0,0 +1,5 @@
+-- Add migration script here
+alter table guilds
+add fair_queue boolean,
+add max_pending int,
+add max_track_length int;
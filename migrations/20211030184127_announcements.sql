@@ -0,0 +1,4 @@
+-- Add migration script here
+alter table guilds
+add announce_mode varchar,
+add announce_channel bigint;
@@ -0,0 +1,13 @@
+-- Add migration script here
+ALTER TABLE "users" ADD "lastfm_session" varchar;
+
+CREATE TABLE "scrobble_queue" (
+  "id" serial PRIMARY KEY,
+  "user_id" bigint NOT NULL REFERENCES "users" ("id") ON DELETE CASCADE,
+  "artist" varchar NOT NULL,
+  "title" varchar NOT NULL,
+  "duration" int,
+  "started_at" timestamptz NOT NULL
+);
+
+CREATE INDEX ON "scrobble_queue" ("started_at");
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE "voice_sessions" ADD "current_track" varchar;
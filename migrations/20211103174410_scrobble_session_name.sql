@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE "users" ADD "lastfm_session_name" varchar;
@@ -0,0 +1,9 @@
+-- Add migration script here
+ALTER TABLE "voice_stats" ADD "day" date;
+UPDATE "voice_stats" SET "day" = ("updated_at" AT TIME ZONE 'utc')::date;
+ALTER TABLE "voice_stats" ALTER "day" SET NOT NULL;
+
+ALTER TABLE "voice_stats" DROP CONSTRAINT "voice_stats_pkey";
+ALTER TABLE "voice_stats" ADD PRIMARY KEY ("session_id", "user_id", "day");
+
+CREATE INDEX ON "voice_stats" ("guild_id", "day");
@@ -12,14 +12,24 @@ use crate::keys::ConnectionPool;
 use crate::utils::html::clean_url;
 use crate::commands::settings::user_lastfm;
 use chrono::naive::NaiveDateTime;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use md5::{Digest, Md5};
 use sqlx;
 
 use std::env;
+use std::fmt;
 use std::option::Option;
+use std::time::Duration;
 
 const FM_RECENT_TRACKS_URL: &str = "http://ws.audioscrobbler.com/2.0/?method=user.getRecentTracks&user={USER}&api_key={KEY}&format=json&limit=10";
 const FM_TOP_TRACKS_URL: &str = "http://ws.audioscrobbler.com/2.0/?method=user.gettoptracks&user={USER}&api_key={KEY}&format=json&limit=10&period={PERIOD}";
+const FM_API_URL: &str = "http://ws.audioscrobbler.com/2.0/";
+/// Where people go to let the bot scrobble for them.
+const FM_AUTH_URL: &str = "https://www.last.fm/api/auth/";
+/// last.fm error codes which mean the same call might work later.
+const FM_TEMPORARY_ERRORS: [i64; 4] = [8, 11, 16, 29];
+/// The last.fm error code for a session key which has been taken back.
+const FM_INVALID_SESSION: i64 = 9;
 
 #[derive(Deserialize, Serialize, Debug)]
 struct User {
@@ -451,3 +461,456 @@ async fn send_last_fm_embed(
         })
         .await;
 }
+
+/// The last.fm API, which can be pointed somewhere else with `LASTFM_API_URL`.
+fn fm_api_url() -> String {
+    env::var("LASTFM_API_URL").unwrap_or_else(|_| FM_API_URL.to_string())
+}
+
+/// Where last.fm is, and the key and secret the bot calls it with.
+struct FmApi {
+    url: String,
+    key: Option<String>,
+    secret: Option<String>,
+}
+
+impl FmApi {
+    /// The API from `LASTFM_API_URL`, with the key in `LASTFM_KEY` and the secret in
+    /// `LASTFM_SECRET`.
+    fn from_env() -> Self {
+        FmApi {
+            url: fm_api_url(),
+            key: env::var("LASTFM_KEY").ok(),
+            secret: env::var("LASTFM_SECRET").ok(),
+        }
+    }
+}
+
+/// A track last.fm thinks is like another one.
+#[derive(Clone, Debug)]
+pub struct SimilarTrack {
+    pub title: String,
+    pub artist: String,
+}
+
+/// Asks last.fm for up to `limit` tracks like the given one, most alike first.
+pub async fn similar_tracks(
+    artist: &str,
+    title: &str,
+    limit: usize,
+) -> Result<Vec<SimilarTrack>, String> {
+    similar_tracks_at(&FmApi::from_env(), artist, title, limit).await
+}
+
+async fn similar_tracks_at(
+    api: &FmApi,
+    artist: &str,
+    title: &str,
+    limit: usize,
+) -> Result<Vec<SimilarTrack>, String> {
+    let fm_key = api
+        .key
+        .as_deref()
+        .ok_or_else(|| "There's no last.fm key set up.".to_string())?;
+    let limit = limit.to_string();
+
+    let resp = reqwest::Client::new()
+        .get(&api.url)
+        .query(&[
+            ("method", "track.getSimilar"),
+            ("artist", artist),
+            ("track", title),
+            ("autocorrect", "1"),
+            ("limit", &limit),
+            ("api_key", fm_key),
+            ("format", "json"),
+        ])
+        .send()
+        .await
+        .map_err(|why| {
+            error!("Could not reach last.fm: {:?}", why);
+            "I couldn't reach last.fm right now.".to_string()
+        })?
+        .json::<Value>()
+        .await
+        .map_err(|_| "last.fm sent back something I didn't understand.".to_string())?;
+
+    // last.fm answers errors with a 200 and a message, as often as not
+    if let Some(message) = resp.get("message").and_then(|m| m.as_str()) {
+        return Err(format!("last.fm said: {}", message));
+    }
+
+    let tracks = resp
+        .get("similartracks")
+        .and_then(|s| s.get("track"))
+        .and_then(|t| t.as_array())
+        .map(|tracks| {
+            tracks
+                .iter()
+                .filter_map(|t| {
+                    Some(SimilarTrack {
+                        title: t.get("name")?.as_str()?.to_string(),
+                        artist: t.get("artist")?.get("name")?.as_str()?.to_string(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(tracks)
+}
+
+/// Why a signed last.fm call didn't go through.
+#[derive(Debug)]
+pub enum FmError {
+    /// last.fm, or the connection to it, is having trouble, so trying again later might work.
+    Temporary(String),
+    /// The user took back the bot's access to their account.
+    InvalidSession,
+    /// last.fm won't take the call as it is.
+    Rejected(String),
+}
+
+impl fmt::Display for FmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FmError::Temporary(why) | FmError::Rejected(why) => write!(f, "{}", why),
+            FmError::InvalidSession => write!(
+                f,
+                "The bot's access to that last.fm account was taken back."
+            ),
+        }
+    }
+}
+
+/// A track as last.fm gets told about it.
+#[derive(Clone, Debug)]
+pub struct ScrobbleTrack {
+    pub artist: String,
+    pub title: String,
+    pub duration: Option<Duration>,
+    pub started_at: DateTime<Utc>,
+}
+
+/// Signs a call the way last.fm wants: every parameter sorted by name and run together,
+/// followed by the API secret, then hashed with MD5.
+fn api_signature(params: &[(&str, &str)], secret: &str) -> String {
+    let mut sorted = params.to_vec();
+    sorted.sort_by_key(|(name, _)| *name);
+
+    let mut signed = sorted
+        .iter()
+        .map(|(name, value)| format!("{}{}", name, value))
+        .collect::<String>();
+    signed.push_str(secret);
+
+    format!("{:x}", Md5::digest(signed.as_bytes()))
+}
+
+/// Makes a call signed with the API secret, which anything done on behalf of a user needs.
+async fn signed_call(api: &FmApi, method: &str, params: &[(&str, &str)]) -> Result<Value, FmError> {
+    let fm_key = api
+        .key
+        .as_deref()
+        .ok_or_else(|| FmError::Rejected("There's no last.fm key set up.".to_string()))?;
+    let fm_secret = api
+        .secret
+        .as_deref()
+        .ok_or_else(|| FmError::Rejected("There's no last.fm secret set up.".to_string()))?;
+
+    let mut params: Vec<(&str, &str)> = params.to_vec();
+    params.push(("method", method));
+    params.push(("api_key", fm_key));
+    let signature = api_signature(&params, fm_secret);
+    params.push(("api_sig", signature.as_str()));
+    // the format isn't part of the signature
+    params.push(("format", "json"));
+
+    let resp = reqwest::Client::new()
+        .post(&api.url)
+        .form(&params)
+        .send()
+        .await
+        .map_err(|why| {
+            error!("Could not reach last.fm: {:?}", why);
+            FmError::Temporary("I couldn't reach last.fm right now.".to_string())
+        })?
+        .json::<Value>()
+        .await
+        .map_err(|_| {
+            FmError::Temporary("last.fm sent back something I didn't understand.".to_string())
+        })?;
+
+    match resp.get("error").and_then(|e| e.as_i64()) {
+        None => Ok(resp),
+        Some(FM_INVALID_SESSION) => Err(FmError::InvalidSession),
+        Some(code) => {
+            let message = resp
+                .get("message")
+                .and_then(|m| m.as_str())
+                .unwrap_or("Something went wrong on last.fm's end.")
+                .to_string();
+
+            if FM_TEMPORARY_ERRORS.contains(&code) {
+                Err(FmError::Temporary(message))
+            } else {
+                Err(FmError::Rejected(message))
+            }
+        }
+    }
+}
+
+/// Starts letting someone give the bot access to their account. Returns the token to finish
+/// up with in [`auth_session`], and the page they need to allow access on.
+pub async fn auth_token() -> Result<(String, String), FmError> {
+    let resp = signed_call(&FmApi::from_env(), "auth.getToken", &[]).await?;
+    let token = resp
+        .get("token")
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| FmError::Temporary("last.fm didn't send back a token.".to_string()))?
+        .to_string();
+
+    let fm_key = env::var("LASTFM_KEY").unwrap_or_default();
+    let url = format!("{}?api_key={}&token={}", FM_AUTH_URL, fm_key, token);
+
+    Ok((token, url))
+}
+
+/// Finishes what [`auth_token`] started, once access has been allowed. Returns the name of
+/// the account and the session key to scrobble to it with, which doesn't expire.
+pub async fn auth_session(token: &str) -> Result<(String, String), FmError> {
+    let resp = signed_call(&FmApi::from_env(), "auth.getSession", &[("token", token)]).await?;
+    let session = resp
+        .get("session")
+        .ok_or_else(|| FmError::Rejected("last.fm didn't send back a session.".to_string()))?;
+
+    let name = session.get("name").and_then(|n| n.as_str());
+    let key = session.get("key").and_then(|k| k.as_str());
+    match (name, key) {
+        (Some(name), Some(key)) => Ok((name.to_string(), key.to_string())),
+        _ => Err(FmError::Rejected(
+            "last.fm didn't send back a session.".to_string(),
+        )),
+    }
+}
+
+fn track_params(track: &ScrobbleTrack) -> Vec<(&'static str, String)> {
+    let mut params = vec![
+        ("artist", track.artist.clone()),
+        ("track", track.title.clone()),
+    ];
+    if let Some(duration) = track.duration {
+        params.push(("duration", duration.as_secs().to_string()));
+    }
+
+    params
+}
+
+/// Shows a track as what someone is listening to on their profile.
+pub async fn update_now_playing(session_key: &str, track: &ScrobbleTrack) -> Result<(), FmError> {
+    let mut params = track_params(track);
+    params.push(("sk", session_key.to_string()));
+    let params = params
+        .iter()
+        .map(|(n, v)| (*n, v.as_str()))
+        .collect::<Vec<_>>();
+
+    signed_call(&FmApi::from_env(), "track.updateNowPlaying", &params).await?;
+
+    Ok(())
+}
+
+/// Adds a track someone listened to to their profile.
+pub async fn scrobble(session_key: &str, track: &ScrobbleTrack) -> Result<(), FmError> {
+    scrobble_at(&FmApi::from_env(), session_key, track).await
+}
+
+async fn scrobble_at(api: &FmApi, session_key: &str, track: &ScrobbleTrack) -> Result<(), FmError> {
+    let mut params = track_params(track);
+    params.push(("timestamp", track.started_at.timestamp().to_string()));
+    params.push(("sk", session_key.to_string()));
+    let params = params
+        .iter()
+        .map(|(n, v)| (*n, v.as_str()))
+        .collect::<Vec<_>>();
+
+    signed_call(api, "track.scrobble", &params).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::serve_json;
+    use chrono::TimeZone;
+
+    fn test_api(url: &str) -> FmApi {
+        FmApi {
+            url: url.to_string(),
+            key: Some("test-key".to_string()),
+            secret: Some("test-secret".to_string()),
+        }
+    }
+
+    fn test_track() -> ScrobbleTrack {
+        ScrobbleTrack {
+            artist: "Daft Punk".to_string(),
+            title: "One More Time".to_string(),
+            duration: Some(Duration::from_secs(320)),
+            started_at: Utc.timestamp(1635800000, 0),
+        }
+    }
+
+    #[tokio::test]
+    async fn similar_tracks_reads_get_similar() {
+        let (url, request) = serve_json(
+            r#"{"similartracks": {"track": [
+                {"name": "Digital Love", "artist": {"name": "Daft Punk"}, "match": 1.0},
+                {"name": "No Artist"},
+                {"name": "Music Sounds Better With You", "artist": {"name": "Stardust"}, "match": 0.8}
+            ], "@attr": {"artist": "Daft Punk"}}}"#,
+        )
+        .await;
+
+        let similar = similar_tracks_at(&test_api(&url), "Daft Punk", "One More Time", 10).await;
+
+        let request = request.await.unwrap();
+        assert!(request.starts_with("GET /?"));
+        for param in &[
+            "method=track.getSimilar",
+            "artist=Daft+Punk",
+            "track=One+More+Time",
+            "limit=10",
+            "api_key=test-key",
+            "format=json",
+        ] {
+            assert!(
+                request.contains(param),
+                "{} should be in {}",
+                param,
+                request
+            );
+        }
+
+        let similar = similar.unwrap();
+        assert_eq!(similar.len(), 2);
+        assert_eq!(similar[0].title, "Digital Love");
+        assert_eq!(similar[0].artist, "Daft Punk");
+        assert_eq!(similar[1].artist, "Stardust");
+    }
+
+    #[tokio::test]
+    async fn similar_tracks_passes_on_errors() {
+        let (url, request) =
+            serve_json(r#"{"error": 6, "message": "Track not found", "links": []}"#).await;
+
+        let similar = similar_tracks_at(&test_api(&url), "Nobody", "Nothing", 5).await;
+        let _ = request.await;
+
+        assert_eq!(similar.unwrap_err(), "last.fm said: Track not found");
+    }
+
+    #[test]
+    fn signs_calls_like_last_fm() {
+        // the example from last.fm's authentication docs
+        assert_eq!(
+            api_signature(
+                &[
+                    ("method", "auth.getSession"),
+                    ("api_key", "xxxxxxxx"),
+                    ("token", "yyyyyy")
+                ],
+                "ilovecher"
+            ),
+            "1333ebf6f7dec747486b6ce965cca66b"
+        );
+    }
+
+    #[test]
+    fn signatures_dont_depend_on_parameter_order() {
+        let params = [
+            ("track", "One More Time"),
+            ("timestamp", "1635800000"),
+            ("artist", "Daft Punk"),
+            ("sk", "session"),
+        ];
+        let mut reversed = params;
+        reversed.reverse();
+
+        assert_eq!(
+            api_signature(&params, "secret"),
+            "d0e70ad0661b87f05c79a5d08f96c9b1"
+        );
+        assert_eq!(
+            api_signature(&reversed, "secret"),
+            api_signature(&params, "secret")
+        );
+    }
+
+    #[tokio::test]
+    async fn similar_tracks_needs_a_key() {
+        let api = FmApi {
+            key: None,
+            ..test_api("http://127.0.0.1:9/")
+        };
+
+        assert!(similar_tracks_at(&api, "Daft Punk", "One More Time", 5)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn scrobbles_go_through() {
+        let (url, request) = serve_json(
+            r#"{"scrobbles": {"@attr": {"accepted": 1, "ignored": 0}, "scrobble": {}}}"#,
+        )
+        .await;
+
+        let scrobbled = scrobble_at(&test_api(&url), "session", &test_track()).await;
+
+        assert_eq!(request.await.unwrap(), "POST / HTTP/1.1");
+        assert!(scrobbled.is_ok());
+    }
+
+    #[tokio::test]
+    async fn scrobbles_are_tried_again_when_last_fm_is_having_trouble() {
+        for code in &[11, 16] {
+            let (url, request) = serve_json(&format!(
+                r#"{{"error": {}, "message": "Service Offline"}}"#,
+                code
+            ))
+            .await;
+
+            let scrobbled = scrobble_at(&test_api(&url), "session", &test_track()).await;
+            let _ = request.await;
+
+            assert!(
+                matches!(scrobbled, Err(FmError::Temporary(_))),
+                "error {} should be tried again",
+                code
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn scrobbles_to_taken_back_sessions_are_refused() {
+        let (url, request) = serve_json(r#"{"error": 9, "message": "Invalid session key"}"#).await;
+
+        let scrobbled = scrobble_at(&test_api(&url), "session", &test_track()).await;
+        let _ = request.await;
+
+        assert!(matches!(scrobbled, Err(FmError::InvalidSession)));
+    }
+
+    #[tokio::test]
+    async fn scrobbles_last_fm_wont_take_are_dropped() {
+        let (url, request) =
+            serve_json(r#"{"error": 13, "message": "Invalid method signature supplied"}"#).await;
+
+        let scrobbled = scrobble_at(&test_api(&url), "session", &test_track()).await;
+        let _ = request.await;
+
+        assert!(matches!(scrobbled, Err(FmError::Rejected(_))));
+    }
+}
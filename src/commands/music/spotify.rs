@@ -3,6 +3,35 @@ use serenity::framework::standard::macros::command;
 use serenity::framework::standard::{Args, CommandError, CommandResult};
 use serenity::model::prelude::*;
 use serenity::prelude::*;
+use std::env;
+
+/// Where the Spotify Web API is.
+const SPOTIFY_API_URL: &str = "https://api.spotify.com/v1";
+
+lazy_static! {
+    /// One client for everything that talks to Spotify, so they share a token.
+    static ref CLIENT: Option<Client> = ClientCredentials::from_env().map(Client::new);
+}
+
+/// The Spotify client, made from `CLIENT_ID` and `CLIENT_SECRET` if they're set.
+pub fn spotify_client() -> Option<&'static Client> {
+    CLIENT.as_ref()
+}
+
+/// The Web API, which can be pointed somewhere else with `SPOTIFY_API_URL`.
+///
+/// aspotify always goes to api.spotify.com, so this is for requests made without it, using
+/// the token from [`spotify_token`].
+pub fn spotify_api_url() -> String {
+    env::var("SPOTIFY_API_URL")
+        .map(|url| url.trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| SPOTIFY_API_URL.to_string())
+}
+
+/// The shared client's access token, which it gets a new one of when it runs out.
+pub async fn spotify_token(client: &Client) -> Result<String, aspotify::Error> {
+    Ok(client.access_token().await?.token.clone())
+}
 
 #[command]
 #[aliases(s, sp, spot)]
@@ -16,11 +45,7 @@ async fn spotify(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
 #[command("songs")]
 #[aliases(s)]
 async fn spotify_songs(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-    let credentials =
-        ClientCredentials::from_env().expect("CLIENT_ID and CLIENT_SECRET not found.");
-
-    // Create a Spotify client.
-    let spoticlient = Client::new(credentials);
+    let spoticlient = spotify_client().expect("CLIENT_ID and CLIENT_SECRET not found.");
 
     let result = spoticlient
         .search()
@@ -85,11 +110,7 @@ async fn spotify_songs(ctx: &Context, msg: &Message, args: Args) -> CommandResul
 #[command("albums")]
 #[aliases(a)]
 async fn spotify_album(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-    let credentials =
-        ClientCredentials::from_env().expect("CLIENT_ID and CLIENT_SECRET not found.");
-
-    // Create a Spotify client.
-    let spoticlient = Client::new(credentials);
+    let spoticlient = spotify_client().expect("CLIENT_ID and CLIENT_SECRET not found.");
 
     let result = spoticlient
         .search()
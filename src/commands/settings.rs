@@ -8,11 +8,29 @@ use regex::Regex;
 use serde;
 use serde::{Deserialize, Serialize};
 
+use crate::commands::music::lastfm::{auth_session, auth_token};
+use crate::commands::voice::{
+    announce::{announce_settings, AnnounceMode},
+    autoplay::autoplay,
+    dj_role,
+    inactivity::{always_on, inactivity_timeout, MAX_INACTIVITY_TIMEOUT},
+    limits::{fair_queue, queue_limits, MAX_LENGTH_LIMIT, MAX_PENDING_LIMIT},
+    play::playlist_limit,
+    scrobble::forget_session,
+    session::guild_queue,
+    transition::{crossfade, MAX_CROSSFADE},
+};
 use crate::dynamic_prefix;
 use crate::keys::ConnectionPool;
 use crate::utils::user::{get_members, get_pronouns};
 use sqlx;
 
+use std::time::Duration;
+
+/// How long someone has to give the bot access to their last.fm account.
+const FM_AUTH_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const CONFIRM_EMOJI: &str = "✅";
+
 #[derive(Deserialize, Serialize, Debug)]
 struct UpdatePronoun {
     id: i64,
@@ -40,7 +58,7 @@ struct UpdateLastFM {
 #[command]
 #[aliases(u)]
 #[description("Edit your user settings.")]
-#[sub_commands(user_pronoun, user_lastfm)]
+#[sub_commands(user_pronoun, user_lastfm, user_scrobble)]
 async fn user(ctx: &Context, msg: &Message) -> CommandResult {
     // Send error message if no subcommands were matched.
     msg.channel_id.say(&ctx.http, "Invalid setting!").await?;
@@ -252,10 +270,174 @@ pub async fn user_lastfm(ctx: &Context, msg: &Message, args: Args) -> CommandRes
     Ok(())
 }
 
+#[command("scrobble")]
+#[aliases(scrobbling)]
+#[usage("<on|off>")]
+#[description("Have tracks the bot plays scrobbled to your last.fm account while you're listening in voice.\nTurning it on DMs you a link to give the bot access to your account.")]
+async fn user_scrobble(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    // read from data lock, without holding it while waiting on last.fm
+    let pool = {
+        let data = ctx.data.read().await;
+        data.get::<ConnectionPool>().unwrap().clone()
+    };
+
+    let enabled = match args.single::<String>() {
+        Ok(s) => match s.to_lowercase().as_str() {
+            "on" | "true" | "yes" => true,
+            "off" | "false" | "no" => false,
+            _ => {
+                return Err(CommandError::from(
+                    "h-Scrobbling can only be `on` or `off`.",
+                ))
+            }
+        },
+        Err(_) => {
+            let user = sqlx::query!(
+                "
+            select lastfm_session, lastfm_session_name
+            from users
+            where id = $1
+            ",
+                msg.author.id.0 as i64
+            )
+            .fetch_optional(&pool)
+            .await?;
+
+            let message = match user {
+                Some(user) if user.lastfm_session.is_some() => format!(
+                    "Scrobbling to `{}` is on.",
+                    user.lastfm_session_name
+                        .as_deref()
+                        .unwrap_or("your last.fm account")
+                ),
+                _ => "Scrobbling is off.".to_string(),
+            };
+            let _ = msg.channel_id.say(&ctx.http, message).await;
+            return Ok(());
+        }
+    };
+
+    if !enabled {
+        forget_session(&pool, msg.author.id).await?;
+        let _ = msg
+            .channel_id
+            .say(&ctx.http, "Scrobbling is off. You can take the bot's access away on last.fm too, under Settings → Applications.")
+            .await;
+        return Ok(());
+    }
+
+    let (token, url) = auth_token()
+        .await
+        .map_err(|why| CommandError::from(format!("h-{}", why)))?;
+
+    let dm = msg
+        .author
+        .direct_message(&ctx, |m| {
+            m.embed(|e| {
+                e.title("Scrobble to last.fm")
+                    .color(0xb90000)
+                    .description(format!(
+                        "[Give me access to your last.fm account]({}), then react with {} here within {} minutes.",
+                        url,
+                        CONFIRM_EMOJI,
+                        FM_AUTH_TIMEOUT.as_secs() / 60
+                    ))
+            })
+        })
+        .await
+        .map_err(|_| CommandError::from("h-I couldn't DM you. Do you have DMs from server members turned off?"))?;
+
+    if msg.guild_id.is_some() {
+        let _ = msg
+            .channel_id
+            .say(
+                &ctx.http,
+                "I've sent you a DM to link your last.fm account.",
+            )
+            .await;
+    }
+
+    let _ = dm
+        .react(ctx, ReactionType::Unicode(CONFIRM_EMOJI.to_string()))
+        .await;
+    let confirmed = dm
+        .await_reaction(ctx)
+        .author_id(msg.author.id)
+        .timeout(FM_AUTH_TIMEOUT)
+        .filter(|r| match &r.emoji {
+            ReactionType::Unicode(e) => e == CONFIRM_EMOJI,
+            _ => false,
+        })
+        .await;
+
+    if confirmed.is_none() {
+        let _ = dm
+            .channel_id
+            .say(
+                &ctx.http,
+                "That took too long, so scrobbling is still off. Try again whenever you're ready.",
+            )
+            .await;
+        return Ok(());
+    }
+
+    let (name, key) = match auth_session(&token).await {
+        Ok(session) => session,
+        Err(why) => {
+            let _ = dm
+                .channel_id
+                .say(&ctx.http, format!("I couldn't link your account: {}", why))
+                .await;
+            return Ok(());
+        }
+    };
+
+    // kept apart from the username set with `lastfm_username`, which can be someone else's
+    sqlx::query!(
+        "
+    insert into users(id, lastfm_session, lastfm_session_name)
+    values($1, $2, $3)
+    on conflict (id) do update
+    set lastfm_session = $2, lastfm_session_name = $3
+    ",
+        msg.author.id.0 as i64,
+        key,
+        name
+    )
+    .execute(&pool)
+    .await?;
+
+    let _ = dm
+        .channel_id
+        .say(
+            &ctx.http,
+            format!(
+                "Scrobbling to `{}` is on. Tracks I play will be scrobbled while you're listening in voice.",
+                name
+            ),
+        )
+        .await;
+
+    Ok(())
+}
+
 #[command]
 #[aliases(sv)]
 #[description("Edit the server's settings.")]
-#[sub_commands(server_prefix)]
+#[sub_commands(
+    server_prefix,
+    server_voteskip,
+    server_always_on,
+    server_idle_timeout,
+    server_dj,
+    server_playlist_limit,
+    server_crossfade,
+    server_fair,
+    server_max_pending,
+    server_max_length,
+    server_announce,
+    server_autoplay
+)]
 async fn server(ctx: &Context, msg: &Message) -> CommandResult {
     // Send error message if no subcommands were matched.
     msg.channel_id.say(&ctx.http, "Invalid setting!").await?;
@@ -344,6 +526,288 @@ async fn server_prefix(ctx: &Context, msg: &Message, args: Args) -> CommandResul
 
     Ok(())
 }
+#[command("voteskip")]
+#[aliases(vs)]
+#[usage("<percent>")]
+#[description("Set how many listeners, in percent, need to vote before a track is skipped.")]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[owner_privilege]
+async fn server_voteskip(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    // read from data lock
+    let data = ctx.data.read().await;
+    // get our db pool from the data lock
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    let id = msg.guild_id.unwrap();
+
+    let percent = match args.single::<String>() {
+        Ok(p) => match p.trim_end_matches('%').parse::<i32>() {
+            Ok(p) if p > 0 && p <= 100 => p,
+            _ => {
+                return Err(CommandError::from(
+                    "h-The vote skip share needs to be a percentage between 1 and 100.",
+                ))
+            }
+        },
+        Err(_) => {
+            let current = sqlx::query!(
+                "
+            select vote_skip
+            from guilds
+            where id = $1
+            ",
+                id.0 as i64
+            )
+            .fetch_optional(pool)
+            .await?
+            .and_then(|g| g.vote_skip)
+            .unwrap_or(50);
+
+            let _ = msg
+                .channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "{}% of listeners currently need to vote to skip a track.",
+                        current
+                    ),
+                )
+                .await;
+            return Ok(());
+        }
+    };
+
+    sqlx::query!(
+        "
+    insert into guilds(id, vote_skip)
+    values($1, $2)
+    on conflict (id) do update
+    set vote_skip = $2
+    ",
+        id.0 as i64,
+        percent
+    )
+    .execute(pool)
+    .await?;
+
+    let _ = msg
+        .channel_id
+        .say(
+            &ctx.http,
+            format!(
+                "{}% of listeners now need to vote to skip a track.",
+                percent
+            ),
+        )
+        .await;
+
+    Ok(())
+}
+
+#[command("247")]
+#[aliases("24/7", alwayson)]
+#[usage("<on|off>")]
+#[description("Keep the bot in voice even when nobody's listening or nothing's playing.")]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[owner_privilege]
+async fn server_always_on(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    // read from data lock
+    let data = ctx.data.read().await;
+    // get our db pool from the data lock
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    let id = msg.guild_id.unwrap();
+
+    let enabled = match args.single::<String>() {
+        Ok(s) => match s.to_lowercase().as_str() {
+            "on" | "true" | "yes" => true,
+            "off" | "false" | "no" => false,
+            _ => return Err(CommandError::from("h-24/7 mode can only be `on` or `off`.")),
+        },
+        Err(_) => {
+            let current = always_on(pool, id).await?;
+            let _ = msg
+                .channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "24/7 mode is currently {}.",
+                        if current { "on" } else { "off" }
+                    ),
+                )
+                .await;
+            return Ok(());
+        }
+    };
+
+    sqlx::query!(
+        "
+    insert into guilds(id, always_on)
+    values($1, $2)
+    on conflict (id) do update
+    set always_on = $2
+    ",
+        id.0 as i64,
+        enabled
+    )
+    .execute(pool)
+    .await?;
+
+    let _ = msg
+        .channel_id
+        .say(
+            &ctx.http,
+            if enabled {
+                "24/7 mode is on. I'll stay in voice until someone tells me to leave."
+            } else {
+                "24/7 mode is off. I'll leave voice after a while with nobody listening or nothing playing."
+            },
+        )
+        .await;
+
+    Ok(())
+}
+
+#[command("timeout")]
+#[aliases(idle, afk)]
+#[usage("<minutes>")]
+#[description("Set how many minutes the bot stays in voice with nobody listening or nothing playing.")]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[owner_privilege]
+async fn server_idle_timeout(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    // read from data lock
+    let data = ctx.data.read().await;
+    // get our db pool from the data lock
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    let id = msg.guild_id.unwrap();
+
+    let minutes = match args.single::<String>() {
+        Ok(m) => match m.parse::<i32>() {
+            Ok(m) if (1..=MAX_INACTIVITY_TIMEOUT).contains(&m) => m,
+            _ => {
+                return Err(CommandError::from(format!(
+                    "h-The timeout needs to be a number of minutes between 1 and {}.",
+                    MAX_INACTIVITY_TIMEOUT
+                )))
+            }
+        },
+        Err(_) => {
+            let current = inactivity_timeout(pool, id).await?;
+            let _ = msg
+                .channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "I currently leave voice after {} minutes with nobody listening or nothing playing.",
+                        current.as_secs() / 60
+                    ),
+                )
+                .await;
+            return Ok(());
+        }
+    };
+
+    sqlx::query!(
+        "
+    insert into guilds(id, inactivity_timeout)
+    values($1, $2)
+    on conflict (id) do update
+    set inactivity_timeout = $2
+    ",
+        id.0 as i64,
+        minutes
+    )
+    .execute(pool)
+    .await?;
+
+    let mut message = format!(
+        "I'll now leave voice after {} minutes with nobody listening or nothing playing.",
+        minutes
+    );
+    if always_on(pool, id).await? {
+        message.push_str(" 24/7 mode is on though, so I'll stay until it's turned off.");
+    }
+    let _ = msg.channel_id.say(&ctx.http, message).await;
+
+    Ok(())
+}
+
+#[command("dj")]
+#[usage("<@role|off>")]
+#[description("Set the role allowed to skip, clear and otherwise manage music for everyone.")]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[owner_privilege]
+async fn server_dj(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    // read from data lock
+    let data = ctx.data.read().await;
+    // get our db pool from the data lock
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    let id = msg.guild_id.unwrap();
+
+    let to_set = args.rest().trim();
+    if to_set.is_empty() {
+        let message = match dj_role(pool, id).await? {
+            Some(role) => format!("The DJ role is currently {}.", role.mention()),
+            None => "There's no DJ role set. People with the Manage Channels permission can still manage music.".to_string(),
+        };
+        let _ = msg
+            .channel_id
+            .send_message(&ctx.http, |m| {
+                m.content(message).allowed_mentions(|am| am.empty_parse())
+            })
+            .await;
+        return Ok(());
+    }
+
+    let role = match to_set {
+        "off" | "none" => None,
+        _ => match to_set
+            .parse::<u64>()
+            .ok()
+            .or_else(|| serenity::utils::parse_role(to_set))
+        {
+            Some(role) => Some(RoleId(role)),
+            None => {
+                return Err(CommandError::from(
+                    "h-That doesn't look like a role. Mention it or use its id.",
+                ))
+            }
+        },
+    };
+
+    sqlx::query!(
+        "
+    insert into guilds(id, dj_role)
+    values($1, $2)
+    on conflict (id) do update
+    set dj_role = $2
+    ",
+        id.0 as i64,
+        role.map(|r| r.0 as i64)
+    )
+    .execute(pool)
+    .await?;
+
+    let message = match role {
+        Some(role) => format!("The DJ role is now {}.", role.mention()),
+        None => "The DJ role was removed.".to_string(),
+    };
+    let _ = msg
+        .channel_id
+        .send_message(&ctx.http, |m| {
+            m.content(message).allowed_mentions(|am| am.empty_parse())
+        })
+        .await;
+
+    Ok(())
+}
+
 fn does_pn_match(text: &str) -> bool {
     lazy_static! {
             //r"^[a-z0-9_-]{1,6}/[a-z0-9_-]{1,6}/[a-z0-9_-]{1,6}/[a-z0-9_-]{1,6}$",
@@ -351,3 +815,503 @@ fn does_pn_match(text: &str) -> bool {
     }
     RE.is_match(text)
 }
+
+#[command("playlistlimit")]
+#[aliases(pl)]
+#[usage("<tracks>")]
+#[description("Set how many tracks a single playlist import can add to the queue.")]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[owner_privilege]
+async fn server_playlist_limit(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    // read from data lock
+    let data = ctx.data.read().await;
+    // get our db pool from the data lock
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    let id = msg.guild_id.unwrap();
+
+    let limit = match args.single::<String>() {
+        Ok(l) => match l.parse::<i32>() {
+            Ok(l) if l > 0 && l <= 1000 => l,
+            _ => {
+                return Err(CommandError::from(
+                    "h-The playlist limit needs to be a number between 1 and 1000.",
+                ))
+            }
+        },
+        Err(_) => {
+            let current = playlist_limit(pool, id).await?;
+            let _ = msg
+                .channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "A playlist can currently add up to {} tracks at once.",
+                        current
+                    ),
+                )
+                .await;
+            return Ok(());
+        }
+    };
+
+    sqlx::query!(
+        "
+    insert into guilds(id, playlist_limit)
+    values($1, $2)
+    on conflict (id) do update
+    set playlist_limit = $2
+    ",
+        id.0 as i64,
+        limit
+    )
+    .execute(pool)
+    .await?;
+
+    let _ = msg
+        .channel_id
+        .say(
+            &ctx.http,
+            format!("A playlist can now add up to {} tracks at once.", limit),
+        )
+        .await;
+
+    Ok(())
+}
+
+#[command("crossfade")]
+#[aliases(cf)]
+#[usage("<seconds|off>")]
+#[description("Set how many seconds tracks overlap for as one fades into the next.")]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[owner_privilege]
+async fn server_crossfade(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    // read from data lock
+    let data = ctx.data.read().await;
+    // get our db pool from the data lock
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    let id = msg.guild_id.unwrap();
+
+    let seconds = match args.single::<String>() {
+        Ok(s) if s == "off" => 0,
+        Ok(s) => match s.parse::<i32>() {
+            Ok(s) if (0..=MAX_CROSSFADE).contains(&s) => s,
+            _ => {
+                return Err(CommandError::from(format!(
+                    "h-The crossfade needs to be a number of seconds between 0 and {}, or `off`.",
+                    MAX_CROSSFADE
+                )))
+            }
+        },
+        Err(_) => {
+            let message = match crossfade(pool, id).await? {
+                Some(length) => format!(
+                    "Tracks currently fade into each other over {} seconds.",
+                    length.as_secs()
+                ),
+                None => "Crossfade is currently off.".to_string(),
+            };
+            let _ = msg.channel_id.say(&ctx.http, message).await;
+            return Ok(());
+        }
+    };
+
+    sqlx::query!(
+        "
+    insert into guilds(id, crossfade)
+    values($1, $2)
+    on conflict (id) do update
+    set crossfade = $2
+    ",
+        id.0 as i64,
+        seconds
+    )
+    .execute(pool)
+    .await?;
+
+    let message = match seconds {
+        0 => "Crossfade is now off.".to_string(),
+        s => format!(
+            "Tracks will now fade into each other over {} seconds, starting with the next one.",
+            s
+        ),
+    };
+    let _ = msg.channel_id.say(&ctx.http, message).await;
+
+    Ok(())
+}
+
+#[command("fair")]
+#[aliases(roundrobin, rr)]
+#[usage("<on|off>")]
+#[description("Have upcoming tracks take turns between the people who added them, instead of playing in the order they were added.")]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[owner_privilege]
+async fn server_fair(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    // the data lock isn't held on to, since the session lookup below takes it again
+    let pool = {
+        let data = ctx.data.read().await;
+        data.get::<ConnectionPool>().unwrap().clone()
+    };
+
+    let id = msg.guild_id.unwrap();
+
+    let enabled = match args.single::<String>() {
+        Ok(s) => match s.to_lowercase().as_str() {
+            "on" | "true" | "yes" => true,
+            "off" | "false" | "no" => false,
+            _ => return Err(CommandError::from("h-Fair mode can only be `on` or `off`.")),
+        },
+        Err(_) => {
+            let current = fair_queue(&pool, id).await?;
+            let _ = msg
+                .channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "Fair mode is currently {}.",
+                        if current { "on" } else { "off" }
+                    ),
+                )
+                .await;
+            return Ok(());
+        }
+    };
+
+    sqlx::query!(
+        "
+    insert into guilds(id, fair_queue)
+    values($1, $2)
+    on conflict (id) do update
+    set fair_queue = $2
+    ",
+        id.0 as i64,
+        enabled
+    )
+    .execute(&pool)
+    .await?;
+
+    if let Some(queue) = guild_queue(ctx, id).await {
+        queue.set_fair_mode(enabled);
+    }
+
+    let _ = msg
+        .channel_id
+        .say(
+            &ctx.http,
+            if enabled {
+                "Fair mode is on. Everyone's tracks will take turns in the queue."
+            } else {
+                "Fair mode is off. Tracks will play in the order they're added."
+            },
+        )
+        .await;
+
+    Ok(())
+}
+
+#[command("maxpending")]
+#[aliases(perusercap, mpend)]
+#[usage("<tracks|off>")]
+#[description("Set how many tracks each person can have waiting in the queue. DJs can add as many as they like.")]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[owner_privilege]
+async fn server_max_pending(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    // read from data lock
+    let data = ctx.data.read().await;
+    // get our db pool from the data lock
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    let id = msg.guild_id.unwrap();
+
+    let max = match args.single::<String>() {
+        Ok(m) if m == "off" => 0,
+        Ok(m) => match m.parse::<i32>() {
+            Ok(m) if (0..=MAX_PENDING_LIMIT).contains(&m) => m,
+            _ => {
+                return Err(CommandError::from(format!(
+                    "h-The limit needs to be a number of tracks between 1 and {}, or `off`.",
+                    MAX_PENDING_LIMIT
+                )))
+            }
+        },
+        Err(_) => {
+            let message = match queue_limits(pool, id).await?.max_pending {
+                Some(max) => format!(
+                    "Each person can currently have up to {} tracks waiting.",
+                    max
+                ),
+                None => {
+                    "There's currently no limit on how many tracks each person can have waiting."
+                        .to_string()
+                }
+            };
+            let _ = msg.channel_id.say(&ctx.http, message).await;
+            return Ok(());
+        }
+    };
+
+    sqlx::query!(
+        "
+    insert into guilds(id, max_pending)
+    values($1, $2)
+    on conflict (id) do update
+    set max_pending = $2
+    ",
+        id.0 as i64,
+        max
+    )
+    .execute(pool)
+    .await?;
+
+    let message = match max {
+        0 => {
+            "There's no limit on how many tracks each person can have waiting anymore.".to_string()
+        }
+        m => format!("Each person can now have up to {} tracks waiting.", m),
+    };
+    let _ = msg.channel_id.say(&ctx.http, message).await;
+
+    Ok(())
+}
+
+#[command("maxlength")]
+#[aliases(ml)]
+#[usage("<minutes|off>")]
+#[description("Set how long, in minutes, a track can be. DJs can add tracks of any length.")]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[owner_privilege]
+async fn server_max_length(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    // read from data lock
+    let data = ctx.data.read().await;
+    // get our db pool from the data lock
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    let id = msg.guild_id.unwrap();
+
+    let minutes = match args.single::<String>() {
+        Ok(m) if m == "off" => 0,
+        Ok(m) => match m.parse::<i32>() {
+            Ok(m) if (0..=MAX_LENGTH_LIMIT).contains(&m) => m,
+            _ => return Err(CommandError::from(format!(
+                "h-The length limit needs to be a number of minutes between 1 and {}, or `off`.",
+                MAX_LENGTH_LIMIT
+            ))),
+        },
+        Err(_) => {
+            let message = match queue_limits(pool, id).await?.max_length {
+                Some(max) => format!(
+                    "Tracks can currently be up to {} minutes long.",
+                    max.as_secs() / 60
+                ),
+                None => "There's currently no limit on how long tracks can be.".to_string(),
+            };
+            let _ = msg.channel_id.say(&ctx.http, message).await;
+            return Ok(());
+        }
+    };
+
+    sqlx::query!(
+        "
+    insert into guilds(id, max_track_length)
+    values($1, $2)
+    on conflict (id) do update
+    set max_track_length = $2
+    ",
+        id.0 as i64,
+        minutes * 60
+    )
+    .execute(pool)
+    .await?;
+
+    let message = match minutes {
+        0 => "There's no limit on how long tracks can be anymore.".to_string(),
+        m => format!("Tracks can now be up to {} minutes long.", m),
+    };
+    let _ = msg.channel_id.say(&ctx.http, message).await;
+
+    Ok(())
+}
+
+#[command("announce")]
+#[aliases(nowplaying, np)]
+#[usage("<off|text|embed> [#channel|default]")]
+#[description("Set how the next track gets announced when one ends, and where. Announcements go where the music was started from unless a channel is given.")]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[owner_privilege]
+async fn server_announce(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    // read from data lock
+    let data = ctx.data.read().await;
+    // get our db pool from the data lock
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    let id = msg.guild_id.unwrap();
+
+    let mode = match args.single::<String>() {
+        Ok(m) => match AnnounceMode::parse(&m) {
+            Some(mode) => mode,
+            None => {
+                return Err(CommandError::from(
+                    "h-Announcements can be `off`, `text` or `embed`.",
+                ))
+            }
+        },
+        Err(_) => {
+            let current = announce_settings(pool, id).await?;
+            let message = match (current.mode, current.channel) {
+                (AnnounceMode::Off, _) => "Tracks currently aren't announced.".to_string(),
+                (mode, Some(channel)) => format!(
+                    "Tracks are currently announced as {} in {}.",
+                    mode,
+                    channel.mention()
+                ),
+                (mode, None) => format!(
+                    "Tracks are currently announced as {} where the music was started from.",
+                    mode
+                ),
+            };
+            let _ = msg.channel_id.say(&ctx.http, message).await;
+            return Ok(());
+        }
+    };
+
+    let channel = match args.single::<String>() {
+        Err(_) => None,
+        Ok(c) if c == "default" => None,
+        Ok(c) => match c
+            .parse::<u64>()
+            .ok()
+            .or_else(|| serenity::utils::parse_channel(&c))
+        {
+            Some(channel) => Some(ChannelId(channel)),
+            None => {
+                return Err(CommandError::from(
+                    "h-That doesn't look like a channel. Mention it or use its id.",
+                ))
+            }
+        },
+    };
+
+    if let Some(channel) = channel {
+        if !can_announce_in(ctx, id, channel).await {
+            return Err(CommandError::from(
+                "h-I can only announce in a channel in this server that I can send messages in.",
+            ));
+        }
+    }
+
+    sqlx::query!(
+        "
+    insert into guilds(id, announce_mode, announce_channel)
+    values($1, $2, $3)
+    on conflict (id) do update
+    set announce_mode = $2, announce_channel = $3
+    ",
+        id.0 as i64,
+        mode.to_string(),
+        channel.map(|c| c.0 as i64)
+    )
+    .execute(pool)
+    .await?;
+
+    let message = match (mode, channel) {
+        (AnnounceMode::Off, _) => "Tracks won't be announced anymore.".to_string(),
+        (mode, Some(channel)) => format!(
+            "Tracks will now be announced as {} in {}.",
+            mode,
+            channel.mention()
+        ),
+        (mode, None) => format!(
+            "Tracks will now be announced as {} where the music was started from.",
+            mode
+        ),
+    };
+    let _ = msg.channel_id.say(&ctx.http, message).await;
+
+    Ok(())
+}
+
+/// Whether `channel` is in the guild, and the bot can send messages there.
+async fn can_announce_in(ctx: &Context, guild_id: GuildId, channel: ChannelId) -> bool {
+    let channel = match channel.to_channel(ctx).await {
+        Ok(Channel::Guild(channel)) if channel.guild_id == guild_id => channel,
+        _ => return false,
+    };
+
+    channel
+        .permissions_for_user(ctx, ctx.cache.current_user_id())
+        .map(|p| p.contains(Permissions::VIEW_CHANNEL | Permissions::SEND_MESSAGES))
+        .unwrap_or(false)
+}
+
+#[command("autoplay")]
+#[aliases(radio)]
+#[usage("<on|off>")]
+#[description("Keep the music going once the queue runs out, with tracks last.fm thinks are like the last one played.")]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[owner_privilege]
+async fn server_autoplay(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    // read from data lock
+    let data = ctx.data.read().await;
+    // get our db pool from the data lock
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    let id = msg.guild_id.unwrap();
+
+    let enabled = match args.single::<String>() {
+        Ok(s) => match s.to_lowercase().as_str() {
+            "on" | "true" | "yes" => true,
+            "off" | "false" | "no" => false,
+            _ => return Err(CommandError::from("h-Autoplay can only be `on` or `off`.")),
+        },
+        Err(_) => {
+            let current = autoplay(pool, id).await?;
+            let _ = msg
+                .channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "Autoplay is currently {}.",
+                        if current { "on" } else { "off" }
+                    ),
+                )
+                .await;
+            return Ok(());
+        }
+    };
+
+    sqlx::query!(
+        "
+    insert into guilds(id, autoplay)
+    values($1, $2)
+    on conflict (id) do update
+    set autoplay = $2
+    ",
+        id.0 as i64,
+        enabled
+    )
+    .execute(pool)
+    .await?;
+
+    let _ = msg
+        .channel_id
+        .say(
+            &ctx.http,
+            if enabled {
+                "Autoplay is on. When the queue runs out, I'll keep playing tracks like the last one."
+            } else {
+                "Autoplay is off. The music will stop when the queue runs out."
+            },
+        )
+        .await;
+
+    Ok(())
+}
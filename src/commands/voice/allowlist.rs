@@ -0,0 +1,208 @@
+use serenity::framework::standard::{macros::command, Args, CommandError, CommandResult};
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use sqlx::PgPool;
+
+use crate::keys::ConnectionPool;
+
+const ALLOW_ALL_SETTING: &str = "music_allow_all";
+
+/// Whether music commands can be used in a guild.
+pub async fn is_music_allowed(pool: &PgPool, guild_id: GuildId) -> Result<bool, sqlx::Error> {
+    if allow_all(pool).await? {
+        return Ok(true);
+    }
+
+    let guild = sqlx::query!(
+        "
+    select guild_id
+    from music_allowlist
+    where guild_id = $1
+    ",
+        guild_id.0 as i64
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(guild.is_some())
+}
+
+async fn allow_all(pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let setting = sqlx::query!(
+        "
+    select value
+    from bot_settings
+    where name = $1
+    ",
+        ALLOW_ALL_SETTING
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(setting.map_or(false, |s| s.value == "true"))
+}
+
+/// Takes a guild id from the arguments, falling back to the guild the message was sent in.
+fn target_guild(msg: &Message, args: &Args) -> Result<GuildId, CommandError> {
+    match args.rest().trim() {
+        "" => msg.guild_id.ok_or_else(|| {
+            CommandError::from("h-You need to give me a server id outside of a server.")
+        }),
+        id => id
+            .parse::<u64>()
+            .map(GuildId)
+            .map_err(|_| CommandError::from("h-That doesn't look like a server id.")),
+    }
+}
+
+#[command]
+#[aliases(musicallow, mg)]
+#[description(
+    "Manage which servers can use music commands.\nSubcommands: `add`, `remove`, `list`, `all`"
+)]
+#[owners_only]
+#[sub_commands(musicguilds_add, musicguilds_remove, musicguilds_list, musicguilds_all)]
+async fn musicguilds(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    musicguilds_list(ctx, msg, args).await
+}
+
+#[command("add")]
+#[usage("[server id]")]
+#[owners_only]
+async fn musicguilds_add(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild_id = target_guild(msg, &args)?;
+
+    let data = ctx.data.read().await;
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    sqlx::query!(
+        "
+    insert into music_allowlist(guild_id, added_by)
+    values($1, $2)
+    on conflict (guild_id) do nothing
+    ",
+        guild_id.0 as i64,
+        msg.author.id.0 as i64
+    )
+    .execute(pool)
+    .await?;
+
+    msg.channel_id
+        .say(
+            &ctx.http,
+            format!("Music is now turned on for `{}`.", guild_id),
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[command("remove")]
+#[aliases(rm)]
+#[usage("[server id]")]
+#[owners_only]
+async fn musicguilds_remove(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild_id = target_guild(msg, &args)?;
+
+    let data = ctx.data.read().await;
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    let removed = sqlx::query!(
+        "
+    delete from music_allowlist
+    where guild_id = $1
+    ",
+        guild_id.0 as i64
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    let message = if removed == 0 {
+        format!("`{}` wasn't on the music allowlist.", guild_id)
+    } else {
+        format!("Music is now turned off for `{}`.", guild_id)
+    };
+    msg.channel_id.say(&ctx.http, message).await?;
+
+    Ok(())
+}
+
+#[command("list")]
+#[aliases(ls)]
+#[owners_only]
+async fn musicguilds_list(ctx: &Context, msg: &Message) -> CommandResult {
+    let data = ctx.data.read().await;
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    let guilds = sqlx::query!(
+        "
+    select guild_id
+    from music_allowlist
+    order by added_at
+    "
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut to_send = if allow_all(pool).await? {
+        "Music is turned on for **every** server. These are on the allowlist for when that's turned off:\n".to_string()
+    } else {
+        "Music is turned on for these servers:\n".to_string()
+    };
+
+    for guild in guilds {
+        let id = GuildId(guild.guild_id as u64);
+        let name = ctx
+            .cache
+            .guild(id)
+            .map(|g| g.name)
+            .unwrap_or_else(|| "unknown server".to_string());
+        to_send.push_str(&format!("`{}` {}\n", id, name));
+    }
+
+    msg.channel_id.say(&ctx.http, to_send).await?;
+
+    Ok(())
+}
+
+#[command("all")]
+#[usage("<on|off>")]
+#[owners_only]
+async fn musicguilds_all(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let enabled = match args.single::<String>().map(|s| s.to_lowercase()) {
+        Ok(s) if s == "on" => true,
+        Ok(s) if s == "off" => false,
+        _ => {
+            return Err(CommandError::from(
+                "h-Allowing every server can only be `on` or `off`.",
+            ))
+        }
+    };
+
+    let data = ctx.data.read().await;
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    sqlx::query!(
+        "
+    insert into bot_settings(name, value)
+    values($1, $2)
+    on conflict (name) do update
+    set value = $2
+    ",
+        ALLOW_ALL_SETTING,
+        enabled.to_string()
+    )
+    .execute(pool)
+    .await?;
+
+    let message = if enabled {
+        "Music is now turned on for every server."
+    } else {
+        "Music is now only turned on for servers on the allowlist."
+    };
+    msg.channel_id.say(&ctx.http, message).await?;
+
+    Ok(())
+}
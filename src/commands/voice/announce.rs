@@ -0,0 +1,204 @@
+use serenity::builder::CreateEmbed;
+use serenity::http::Http;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use sqlx::PgPool;
+use std::{fmt, sync::Arc};
+use tokio::sync::mpsc;
+
+use crate::{
+    commands::voice::history::track_line,
+    utils::{queue_store::StoredTrack, time::format_duration},
+};
+
+/// How a guild wants to hear about the next track starting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnounceMode {
+    Off,
+    Text,
+    Embed,
+}
+
+impl AnnounceMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "off" | "none" => Some(AnnounceMode::Off),
+            "text" | "on" => Some(AnnounceMode::Text),
+            "embed" => Some(AnnounceMode::Embed),
+            _ => None,
+        }
+    }
+}
+
+impl Default for AnnounceMode {
+    fn default() -> Self {
+        AnnounceMode::Text
+    }
+}
+
+impl fmt::Display for AnnounceMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnnounceMode::Off => write!(f, "off"),
+            AnnounceMode::Text => write!(f, "text"),
+            AnnounceMode::Embed => write!(f, "embed"),
+        }
+    }
+}
+
+/// Where and how a guild's now playing announcements go.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AnnounceSettings {
+    pub mode: AnnounceMode,
+    /// The channel to post in, instead of the one the session was started from.
+    pub channel: Option<ChannelId>,
+}
+
+/// Reads how the guild wants tracks announced.
+pub async fn announce_settings(
+    pool: &PgPool,
+    guild_id: GuildId,
+) -> Result<AnnounceSettings, sqlx::Error> {
+    let guild = sqlx::query!(
+        "
+    select announce_mode, announce_channel
+    from guilds
+    where id = $1
+    ",
+        guild_id.0 as i64
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(match guild {
+        Some(guild) => AnnounceSettings {
+            mode: guild
+                .announce_mode
+                .as_deref()
+                .and_then(AnnounceMode::parse)
+                .unwrap_or_default(),
+            channel: guild.announce_channel.map(|c| ChannelId(c as u64)),
+        },
+        None => AnnounceSettings::default(),
+    })
+}
+
+fn announce_embed<'a>(e: &'a mut CreateEmbed, track: &StoredTrack) -> &'a mut CreateEmbed {
+    e.author(|a| a.name("Now playing"))
+        .color(0xb90000)
+        .description(track_line(&track.title, &track.artist, &track.source_url))
+        .field(
+            "Length",
+            track.duration.map_or("?".to_string(), format_duration),
+            true,
+        )
+        .field(
+            "Requested by",
+            match track.request() {
+                Some(request) => request.to_string(),
+                None => track.requester.mention().to_string(),
+            },
+            true,
+        );
+
+    if let Some(thumbnail) = &track.thumbnail {
+        e.thumbnail(thumbnail);
+    }
+
+    e
+}
+
+fn announce_text(track: Option<&StoredTrack>) -> String {
+    match track {
+        Some(track) => format!(
+            "Now playing: `{}` by `{}`, requested by {}",
+            track.title.as_deref().unwrap_or("Unknown"),
+            track.artist.as_deref().unwrap_or("unknown"),
+            track.requester.mention()
+        ),
+        None => "No songs left in queue.".to_string(),
+    }
+}
+
+/// Posts each track a guild's queue moves on to, and takes down the post before it.
+pub struct Announcer {
+    guild_id: GuildId,
+    /// The channel the session was started from, used unless the guild picked another.
+    default_channel: ChannelId,
+    http: Arc<Http>,
+    pool: PgPool,
+    last: Option<Message>,
+}
+
+impl Announcer {
+    pub fn new(
+        guild_id: GuildId,
+        default_channel: ChannelId,
+        http: Arc<Http>,
+        pool: PgPool,
+    ) -> Self {
+        Self {
+            guild_id,
+            default_channel,
+            http,
+            pool,
+            last: None,
+        }
+    }
+
+    /// Announces tracks as they come through `tracks`, until the queue sending them is gone.
+    pub fn spawn(mut self, mut tracks: mpsc::UnboundedReceiver<Option<StoredTrack>>) {
+        tokio::spawn(async move {
+            while let Some(track) = tracks.recv().await {
+                self.announce(track.as_ref()).await;
+            }
+        });
+    }
+
+    async fn announce(&mut self, track: Option<&StoredTrack>) {
+        // settings are read every time, so changes apply from the next track
+        let settings = match announce_settings(&self.pool, self.guild_id).await {
+            Ok(settings) => settings,
+            Err(why) => {
+                warn!(
+                    "Could not read announcement settings for guild {}: {:?}",
+                    self.guild_id, why
+                );
+                AnnounceSettings::default()
+            }
+        };
+
+        if let Some(last) = self.last.take() {
+            // it might have been deleted already, which is fine
+            let _ = last.delete(&self.http).await;
+        }
+
+        let channel = settings.channel.unwrap_or(self.default_channel);
+        let sent = match (settings.mode, track) {
+            (AnnounceMode::Off, _) => return,
+            (AnnounceMode::Embed, Some(track)) => {
+                channel
+                    .send_message(&self.http, |m| m.embed(|e| announce_embed(e, track)))
+                    .await
+            }
+            _ => {
+                channel
+                    .send_message(&self.http, |m| {
+                        // don't ping whoever asked for it every time one of their tracks comes up
+                        m.content(announce_text(track))
+                            .allowed_mentions(|am| am.empty_parse())
+                    })
+                    .await
+            }
+        };
+
+        match sent {
+            Ok(message) => self.last = Some(message),
+            Err(why) => warn!(
+                "Could not announce the next track in guild {}: {:?}",
+                self.guild_id, why
+            ),
+        }
+    }
+}
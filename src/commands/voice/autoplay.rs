@@ -0,0 +1,300 @@
+use serenity::http::Http;
+use serenity::model::prelude::*;
+
+use sqlx::PgPool;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::{
+    commands::{
+        music::lastfm::{similar_tracks, SimilarTrack},
+        voice::{
+            play::get_source,
+            session::SessionManager,
+            sources::{search, SearchSite},
+        },
+    },
+    utils::{
+        queue::Request,
+        queue_store::{PlayedTrack, StoredTrack},
+    },
+};
+
+/// How many similar tracks are asked for, so there are some left once recent ones are ruled out.
+const CANDIDATES: usize = 25;
+/// How many candidates are looked for on YouTube before giving up.
+const MAX_ATTEMPTS: usize = 3;
+
+/// Returns whether the guild wants similar tracks played once the queue runs out.
+pub async fn autoplay(pool: &PgPool, guild_id: GuildId) -> Result<bool, sqlx::Error> {
+    let guild = sqlx::query!(
+        "
+    select autoplay
+    from guilds
+    where id = $1
+    ",
+        guild_id.0 as i64
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(guild.and_then(|g| g.autoplay).unwrap_or(false))
+}
+
+/// Strips the bits YouTube titles tend to have on the end, like "(Official Video)".
+fn clean_title(title: &str) -> String {
+    let cut = title
+        .find(|c| c == '(' || c == '[')
+        .unwrap_or_else(|| title.len());
+
+    match title[..cut].trim() {
+        "" => title.trim().to_string(),
+        cleaned => cleaned.to_string(),
+    }
+}
+
+/// Works out the artist and title last.fm would know a track by.
+///
+/// Videos are often titled "Artist - Title" whoever uploaded them, and the ones YouTube
+/// makes itself are uploaded by "Artist - Topic".
+pub fn lastfm_names(track: &StoredTrack) -> Option<(String, String)> {
+    let title = track.title.as_deref()?;
+
+    if let Some((artist, title)) = title.split_once(" - ") {
+        return Some((artist.trim().to_string(), clean_title(title)));
+    }
+
+    let artist = track.artist.as_deref()?.trim_end_matches(" - Topic").trim();
+    Some((artist.to_string(), clean_title(title)))
+}
+
+/// Whether a similar track looks like one that played recently.
+fn played_recently(candidate: &SimilarTrack, recent: &[PlayedTrack]) -> bool {
+    let title = candidate.title.to_lowercase();
+    let artist = candidate.artist.to_lowercase();
+
+    recent.iter().any(|played| {
+        let played_title = played.track.title.as_deref().unwrap_or("").to_lowercase();
+        let played_artist = played.track.artist.as_deref().unwrap_or("").to_lowercase();
+
+        played_title.contains(&title)
+            && (played_title.contains(&artist) || played_artist.contains(&artist))
+    })
+}
+
+/// Keeps a guild's music going once its queue runs out, with tracks last.fm thinks are like
+/// the last one played.
+pub struct Autoplayer {
+    guild_id: GuildId,
+    text_channel: ChannelId,
+    /// Autoplayed tracks are put down as asked for by the bot itself.
+    bot_id: UserId,
+    http: Arc<Http>,
+    pool: PgPool,
+    sessions: Arc<SessionManager>,
+}
+
+impl Autoplayer {
+    pub fn new(
+        guild_id: GuildId,
+        text_channel: ChannelId,
+        bot_id: UserId,
+        http: Arc<Http>,
+        pool: PgPool,
+        sessions: Arc<SessionManager>,
+    ) -> Self {
+        Self {
+            guild_id,
+            text_channel,
+            bot_id,
+            http,
+            pool,
+            sessions,
+        }
+    }
+
+    /// Queues something after each track that `run_outs` says the queue ran out on, until
+    /// the queue sending them is gone.
+    pub fn spawn(self, mut run_outs: mpsc::UnboundedReceiver<StoredTrack>) {
+        tokio::spawn(async move {
+            while let Some(last) = run_outs.recv().await {
+                self.continue_from(&last).await;
+            }
+        });
+    }
+
+    async fn continue_from(&self, last: &StoredTrack) {
+        // read every time, so turning it off applies straight away
+        match autoplay(&self.pool, self.guild_id).await {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(why) => {
+                warn!(
+                    "Could not read autoplay for guild {}: {:?}",
+                    self.guild_id, why
+                );
+                return;
+            }
+        }
+
+        let (artist, title) = match lastfm_names(last) {
+            Some(names) => names,
+            None => return,
+        };
+        let similar = match similar_tracks(&artist, &title, CANDIDATES).await {
+            Ok(similar) => similar,
+            Err(why) => {
+                debug!(
+                    "No similar tracks for {} - {} in guild {}: {}",
+                    artist, title, self.guild_id, why
+                );
+                return;
+            }
+        };
+
+        let session = match self.sessions.get(self.guild_id) {
+            Some(session) => session,
+            None => return,
+        };
+        let recent = session.queue.history();
+
+        for candidate in similar
+            .iter()
+            .filter(|c| !played_recently(c, &recent))
+            .take(MAX_ATTEMPTS)
+        {
+            let query = format!("{} - {}", candidate.artist, candidate.title);
+            let found = match search(&query, SearchSite::YouTube, 1).await {
+                Ok(found) => found.into_iter().next(),
+                Err(_) => None,
+            };
+            let found = match found {
+                Some(found) if !recent.iter().any(|p| p.track.source_url == found.url) => found,
+                _ => continue,
+            };
+
+            let source = match get_source(self.guild_id, &found.url).await {
+                Ok(source) => source,
+                Err(_) => continue,
+            };
+
+            let mut handler = session.call.lock().await;
+            // someone might have queued something while this was being looked for
+            if !session.queue.is_empty() {
+                return;
+            }
+            session.queue.add_source(
+                source,
+                Request::new(self.bot_id, self.text_channel),
+                &mut handler,
+            );
+            drop(handler);
+
+            let _ = self
+                .text_channel
+                .say(
+                    &self.http,
+                    format!(
+                        "📻 Autoplay: `{}` by `{}`, since the queue ran out.",
+                        candidate.title, candidate.artist
+                    ),
+                )
+                .await;
+
+            return;
+        }
+
+        debug!(
+            "Found nothing to autoplay after {} - {} in guild {}",
+            artist, title, self.guild_id
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn track(title: Option<&str>, artist: Option<&str>) -> StoredTrack {
+        StoredTrack {
+            source_url: "https://www.youtube.com/watch?v=abc".to_string(),
+            title: title.map(|t| t.to_string()),
+            artist: artist.map(|a| a.to_string()),
+            thumbnail: None,
+            duration: None,
+            requester: UserId(1),
+            requested_in: None,
+            requested_at: None,
+        }
+    }
+
+    fn names(title: Option<&str>, artist: Option<&str>) -> Option<(String, String)> {
+        lastfm_names(&track(title, artist))
+    }
+
+    #[test]
+    fn names_come_from_the_title() {
+        assert_eq!(
+            names(
+                Some("Daft Punk - One More Time (Official Video)"),
+                Some("Some Uploader")
+            ),
+            Some(("Daft Punk".to_string(), "One More Time".to_string()))
+        );
+    }
+
+    #[test]
+    fn names_fall_back_to_the_uploader() {
+        assert_eq!(
+            names(
+                Some("Harder, Better, Faster, Stronger [HD]"),
+                Some("Daft Punk - Topic")
+            ),
+            Some((
+                "Daft Punk".to_string(),
+                "Harder, Better, Faster, Stronger".to_string()
+            ))
+        );
+        assert_eq!(names(Some("Something"), None), None);
+        assert_eq!(names(None, Some("Daft Punk")), None);
+    }
+
+    #[test]
+    fn cleaning_keeps_titles_that_are_all_brackets() {
+        assert_eq!(clean_title("(Intro)"), "(Intro)");
+        assert_eq!(clean_title(" Aerodynamic "), "Aerodynamic");
+    }
+
+    #[test]
+    fn recently_played_tracks_are_recognised() {
+        let recent = vec![PlayedTrack {
+            track: track(
+                Some("Daft Punk - Digital Love (Official Audio)"),
+                Some("Daft Punk"),
+            ),
+            played_at: Utc::now(),
+        }];
+        let similar = |title: &str, artist: &str| SimilarTrack {
+            title: title.to_string(),
+            artist: artist.to_string(),
+        };
+
+        assert!(played_recently(
+            &similar("Digital Love", "Daft Punk"),
+            &recent
+        ));
+        assert!(played_recently(
+            &similar("digital love", "DAFT PUNK"),
+            &recent
+        ));
+        assert!(!played_recently(
+            &similar("Digital Love", "Someone Else"),
+            &recent
+        ));
+        assert!(!played_recently(
+            &similar("Veridis Quo", "Daft Punk"),
+            &recent
+        ));
+    }
+}
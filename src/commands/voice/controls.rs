@@ -0,0 +1,271 @@
+use serenity::builder::CreateEmbed;
+use serenity::framework::standard::{macros::command, Args, CommandError, CommandResult};
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use songbird::{
+    input::Metadata,
+    tracks::{PlayMode, TrackState},
+};
+use std::time::Duration;
+
+use crate::{
+    commands::voice::{filters::Filters, session::guild_queue, DJ_CHECK, WHITELISTED_GUILDS_CHECK},
+    utils::{
+        queue::Request,
+        time::{format_duration, parse_timestamp, TimestampError},
+    },
+};
+
+/// How often a `nowplaying` message gets refreshed.
+const NOW_PLAYING_REFRESH: Duration = Duration::from_secs(10);
+const PROGRESS_BAR_WIDTH: usize = 20;
+
+fn progress_bar(position: Duration, length: Duration) -> String {
+    let ratio = if length.as_secs_f64() > 0.0 {
+        position.as_secs_f64() / length.as_secs_f64()
+    } else {
+        0.0
+    };
+    let filled = ((ratio * PROGRESS_BAR_WIDTH as f64) as usize).min(PROGRESS_BAR_WIDTH - 1);
+
+    "▬".repeat(filled) + "🔘" + &"▬".repeat(PROGRESS_BAR_WIDTH - 1 - filled)
+}
+
+/// Fills in the now playing embed for a track, at the state it is currently in.
+pub fn now_playing_embed<'a>(
+    e: &'a mut CreateEmbed,
+    meta: &Metadata,
+    state: &TrackState,
+    filters: &Filters,
+    request: Option<&Request>,
+) -> &'a mut CreateEmbed {
+    let title = meta
+        .title
+        .clone()
+        .or_else(|| meta.track.clone())
+        .unwrap_or_else(|| "Unknown".to_string());
+    let artist = meta.artist.clone().unwrap_or_else(|| "unknown".to_string());
+
+    let icon = match state.playing {
+        PlayMode::Pause => "⏸️",
+        _ => "▶️",
+    };
+    // the player counts played time, which speed filters stretch; show where in the track
+    // that is instead, the same way `seek` takes it
+    let position = state.position.mul_f64(filters.tempo());
+    let progress = match meta.duration {
+        Some(length) => format!(
+            "{} {}\n`{} / {}`",
+            icon,
+            progress_bar(position, length),
+            format_duration(position),
+            format_duration(length)
+        ),
+        None => format!("{} `{}`", icon, format_duration(position)),
+    };
+
+    e.author(|a| a.name("Now playing"))
+        .title(title)
+        .color(0xb90000)
+        .description(format!("by {}\n\n{}", artist, progress));
+
+    if let Some(request) = request {
+        e.field("Requested by", request, false);
+    }
+    if !filters.is_empty() {
+        e.field("Filters", filters, false);
+    }
+
+    if let Some(url) = &meta.source_url {
+        e.url(url);
+    }
+    if let Some(thumbnail) = &meta.thumbnail {
+        e.thumbnail(thumbnail);
+    }
+
+    e
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds)]
+#[description("Pause the current track.")]
+async fn pause(ctx: &Context, msg: &Message) -> CommandResult {
+    match guild_queue(ctx, msg.guild_id.unwrap()).await {
+        Some(queue) if !queue.is_empty() => {
+            queue.pause()?;
+            msg.channel_id.say(&ctx.http, "⏸️ Paused.").await?;
+        }
+        _ => {
+            msg.channel_id
+                .say(&ctx.http, "There isn't anything playing right now.")
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds)]
+#[aliases(unpause)]
+#[description("Resume the current track.")]
+async fn resume(ctx: &Context, msg: &Message) -> CommandResult {
+    match guild_queue(ctx, msg.guild_id.unwrap()).await {
+        Some(queue) if !queue.is_empty() => {
+            queue.resume()?;
+            msg.channel_id.say(&ctx.http, "▶️ Resumed.").await?;
+        }
+        _ => {
+            msg.channel_id
+                .say(&ctx.http, "There isn't anything playing right now.")
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds, dj)]
+#[usage("<timestamp in the track, whatever speed it's playing at>")]
+#[example("1:30")]
+#[description(
+    "Jump to a point in the current track. Timestamps match the ones `nowplaying` shows."
+)]
+async fn seek(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let position = match parse_timestamp(args.rest()) {
+        Ok(position) => position,
+        Err(TimestampError::OutOfRange) => {
+            return Err(CommandError::from(
+                "h-That timestamp is further in than any track goes.",
+            ));
+        }
+        Err(TimestampError::Invalid) => {
+            msg.channel_id
+                .say(&ctx.http, "You need to give me a timestamp, like `1:30`.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let queue = guild_queue(ctx, msg.guild_id.unwrap()).await;
+    let (current, meta, filters) = match queue
+        .and_then(|queue| Some((queue.current()?, queue.current_metadata()?, queue.filters())))
+    {
+        Some(current) => current,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "There isn't anything playing right now.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    if !current.is_seekable() {
+        msg.channel_id
+            .say(&ctx.http, "This track can't be seeked.")
+            .await?;
+
+        return Ok(());
+    }
+
+    if let Some(length) = meta.duration {
+        if position >= length {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!("This track is only {} long.", format_duration(length)),
+                )
+                .await?;
+
+            return Ok(());
+        }
+    }
+
+    // the player counts played time, which speed filters stretch
+    current.seek_time(position.div_f64(filters.tempo()))?;
+
+    msg.channel_id
+        .say(
+            &ctx.http,
+            format!("⏩ Jumped to {}.", format_duration(position)),
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds)]
+#[aliases(np)]
+#[description(
+    "Show what's playing right now. The message keeps itself up to date until the track ends."
+)]
+async fn nowplaying(ctx: &Context, msg: &Message) -> CommandResult {
+    let queue = guild_queue(ctx, msg.guild_id.unwrap()).await;
+    let (current, meta, filters, request) = match queue.and_then(|queue| {
+        Some((
+            queue.current()?,
+            queue.current_metadata()?,
+            queue.filters(),
+            queue.current_request(),
+        ))
+    }) {
+        Some(current) => current,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "There isn't anything playing right now.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let state = current.get_info().await?;
+
+    let mut message = msg
+        .channel_id
+        .send_message(&ctx.http, |m| {
+            m.embed(|e| now_playing_embed(e, &meta, &state, &filters, request.as_ref()))
+        })
+        .await?;
+
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        let mut last_position = state.position;
+        loop {
+            tokio::time::sleep(NOW_PLAYING_REFRESH).await;
+
+            // this errors once the track has finished, which is when we stop.
+            let state = match current.get_info().await {
+                Ok(state) => state,
+                Err(_) => break,
+            };
+
+            if state.position == last_position {
+                continue;
+            }
+            last_position = state.position;
+
+            // stop if the message was deleted
+            if message
+                .edit(&ctx, |m| {
+                    m.embed(|e| now_playing_embed(e, &meta, &state, &filters, request.as_ref()))
+                })
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,315 @@
+use serenity::framework::standard::{macros::command, Args, CommandResult};
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use rand::{seq::SliceRandom, thread_rng};
+use std::collections::{HashSet, VecDeque};
+
+use crate::{
+    commands::voice::{session::guild_queue, DJ_CHECK, WHITELISTED_GUILDS_CHECK},
+    utils::{queue::Queued, user::get_id},
+};
+
+/// Stops tracks which were taken out of the queue, so they don't keep their sources open.
+fn stop_removed(removed: &[Queued]) {
+    for track in removed {
+        // an error here just means the track is already gone.
+        let _ = track.stop();
+    }
+}
+
+fn track_title(track: &Queued) -> String {
+    let meta = track.metadata();
+    meta.title
+        .clone()
+        .or_else(|| meta.track.clone())
+        .unwrap_or_else(|| "song".to_string())
+}
+
+/// Parses `3` or `3-7` into an inclusive range of queue positions.
+fn parse_range(arg: &str) -> Option<(usize, usize)> {
+    let mut split = arg.splitn(2, '-');
+    let start = split.next()?.trim().parse::<usize>().ok()?;
+    let end = match split.next() {
+        Some(end) => end.trim().parse::<usize>().ok()?,
+        None => start,
+    };
+
+    if start > end {
+        None
+    } else {
+        Some((start, end))
+    }
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds, dj)]
+#[aliases(rm)]
+#[usage("<position|start-end|@user>")]
+#[description("Remove a track, a range of tracks, or everything someone added from the queue.")]
+async fn remove(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let arg = match args.single::<String>() {
+        Ok(arg) => arg,
+        Err(_) => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "You need to give me a position, a range like `2-5`, or a user.",
+                )
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let queue = match guild_queue(ctx, msg.guild_id.unwrap()).await {
+        Some(queue) if queue.len() > 1 => queue,
+        _ => {
+            msg.channel_id
+                .say(&ctx.http, "There isn't anything coming up in the queue.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let message = if let Some((start, end)) = parse_range(&arg) {
+        // checked against the queue as it is when it's locked, since it can change before then
+        let removed = queue.modify_queue(|vq| {
+            if start == 0 || end >= vq.len() {
+                return Err(format!(
+                    "Positions go from 1 to {}. Use `skip` to get rid of the current track.",
+                    vq.len().saturating_sub(1)
+                ));
+            }
+
+            Ok(vq.drain(start..=end).collect::<Vec<_>>())
+        });
+
+        match removed {
+            Ok(removed) => {
+                stop_removed(&removed);
+
+                match removed.len() {
+                    1 => format!("Removed `{}` from the queue.", track_title(&removed[0])),
+                    n => format!("Removed {} tracks from the queue.", n),
+                }
+            }
+            Err(why) => why,
+        }
+    } else if let Some(id) = get_id(&arg) {
+        let user = UserId(id);
+        let removed = queue.remove_requested_by(user);
+
+        format!(
+            "Removed {} tracks added by {}.",
+            removed.len(),
+            user.mention()
+        )
+    } else {
+        "You need to give me a position, a range like `2-5`, or a user.".to_string()
+    };
+
+    msg.channel_id.say(&ctx.http, message).await?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds)]
+#[aliases(rmmine, unqueue)]
+#[description("Remove everything you added from the queue, apart from the track that's playing.")]
+async fn removemine(ctx: &Context, msg: &Message) -> CommandResult {
+    let queue = match guild_queue(ctx, msg.guild_id.unwrap()).await {
+        Some(queue) if queue.pending_for(msg.author.id) > 0 => queue,
+        _ => {
+            msg.channel_id
+                .say(&ctx.http, "You don't have anything coming up in the queue.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let removed = queue.remove_requested_by(msg.author.id);
+    let message = match removed.len() {
+        1 => format!("Removed `{}` from the queue.", track_title(&removed[0])),
+        n => format!("Removed your {} tracks from the queue.", n),
+    };
+    msg.channel_id.say(&ctx.http, message).await?;
+
+    Ok(())
+}
+
+#[command("move")]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds, dj)]
+#[aliases(mv)]
+#[usage("<from> <to>")]
+#[description("Move a track to another position in the queue.")]
+async fn move_track(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let (from, to) = match (args.single::<usize>(), args.single::<usize>()) {
+        (Ok(from), Ok(to)) => (from, to),
+        _ => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "You need to give me the position to move from and the position to move to.",
+                )
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let queue = match guild_queue(ctx, msg.guild_id.unwrap()).await {
+        Some(queue) if queue.len() > 1 => queue,
+        _ => {
+            msg.channel_id
+                .say(&ctx.http, "There isn't anything coming up in the queue.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let moved = queue.modify_queue(|vq| {
+        if from == 0 || to == 0 || from >= vq.len() || to >= vq.len() {
+            return Err(format!(
+                "Positions go from 1 to {}.",
+                vq.len().saturating_sub(1)
+            ));
+        }
+
+        let track = vq.remove(from).unwrap();
+        let title = track_title(&track);
+        vq.insert(to, track);
+        Ok(title)
+    });
+
+    let message = match moved {
+        Ok(title) => format!("Moved `{}` to position {}.", title, to),
+        Err(why) => why,
+    };
+    msg.channel_id.say(&ctx.http, message).await?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds, dj)]
+#[description("Shuffle everything after the current track.")]
+async fn shuffle(ctx: &Context, msg: &Message) -> CommandResult {
+    let queue = match guild_queue(ctx, msg.guild_id.unwrap()).await {
+        Some(queue) if queue.len() > 2 => queue,
+        _ => {
+            msg.channel_id
+                .say(&ctx.http, "There isn't enough in the queue to shuffle.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let shuffled = queue.modify_queue(|vq| {
+        if vq.len() < 3 {
+            return Err("There isn't enough in the queue to shuffle.");
+        }
+
+        vq.make_contiguous()[1..].shuffle(&mut thread_rng());
+        Ok(vq.len() - 1)
+    });
+
+    let message = match shuffled {
+        Ok(n) => format!("🔀 Shuffled {} tracks.", n),
+        Err(why) => why.to_string(),
+    };
+    msg.channel_id.say(&ctx.http, message).await?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds, dj)]
+#[description("Remove everything after the current track from the queue.")]
+async fn clear(ctx: &Context, msg: &Message) -> CommandResult {
+    let queue = match guild_queue(ctx, msg.guild_id.unwrap()).await {
+        Some(queue) if queue.len() > 1 => queue,
+        _ => {
+            msg.channel_id
+                .say(&ctx.http, "There isn't anything coming up in the queue.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let removed = queue.modify_queue(|vq| match vq.len() {
+        0 => Vec::new(),
+        _ => vq.drain(1..).collect::<Vec<_>>(),
+    });
+    stop_removed(&removed);
+
+    msg.channel_id
+        .say(
+            &ctx.http,
+            format!("Cleared {} tracks from the queue.", removed.len()),
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds, dj)]
+#[aliases(dedup)]
+#[description("Remove tracks which are already somewhere else in the queue.")]
+async fn dedupe(ctx: &Context, msg: &Message) -> CommandResult {
+    let queue = match guild_queue(ctx, msg.guild_id.unwrap()).await {
+        Some(queue) if queue.len() > 1 => queue,
+        _ => {
+            msg.channel_id
+                .say(&ctx.http, "There isn't anything coming up in the queue.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let removed = queue.modify_queue(|vq| {
+        let mut seen = HashSet::new();
+        let mut removed = Vec::new();
+        let mut kept = VecDeque::with_capacity(vq.len());
+        for track in vq.drain(..) {
+            // tracks without a source can't be compared, so they always stay.
+            let is_new = match &track.metadata().source_url {
+                Some(url) => seen.insert(url.clone()),
+                None => true,
+            };
+
+            // the current track is always the first occurrence, so it's never removed.
+            if is_new {
+                kept.push_back(track);
+            } else {
+                removed.push(track);
+            }
+        }
+        *vq = kept;
+        removed
+    });
+    stop_removed(&removed);
+
+    msg.channel_id
+        .say(
+            &ctx.http,
+            format!("Removed {} duplicate tracks.", removed.len()),
+        )
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,446 @@
+use serenity::async_trait;
+use serenity::framework::standard::{macros::command, Args, CommandError, CommandResult};
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use songbird::{
+    input::{
+        error::Result as InputResult, ffmpeg_optioned, restartable::Restart, Codec, Container,
+        Input, Metadata, Restartable,
+    },
+    tracks::PlayMode,
+};
+use std::{fmt, time::Duration};
+use tokio::process::Command;
+
+use crate::commands::voice::{
+    library::{local_path, LOCAL_PREFIX},
+    play::get_source,
+    policy::{link_ffmpeg, vet_link, VettedLink},
+    session::guild_queue,
+    sources::{audius_track, youtube_dl_path},
+    DJ_CHECK, WHITELISTED_GUILDS_CHECK,
+};
+
+const SAMPLE_RATE: f64 = 48000.0;
+const MAX_EQ_BANDS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Preset {
+    BassBoost,
+    Nightcore,
+    Vaporwave,
+    EightD,
+    Karaoke,
+}
+
+impl Preset {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "bassboost" | "bass" => Some(Preset::BassBoost),
+            "nightcore" | "nc" => Some(Preset::Nightcore),
+            "vaporwave" | "vw" => Some(Preset::Vaporwave),
+            "8d" => Some(Preset::EightD),
+            "karaoke" => Some(Preset::Karaoke),
+            _ => None,
+        }
+    }
+
+    fn chain(&self) -> Vec<String> {
+        match self {
+            Preset::BassBoost => vec!["bass=g=12:f=110:w=0.6".to_string()],
+            // these change the sample rate without resampling, which speeds up and pitches up in one go
+            Preset::Nightcore => resample(1.25),
+            Preset::Vaporwave => resample(0.8),
+            Preset::EightD => vec!["apulsator=hz=0.125".to_string()],
+            // turning the middle of the stereo image down takes most of the vocals with it
+            Preset::Karaoke => vec!["stereotools=mlev=0.03".to_string()],
+        }
+    }
+
+    /// How much faster than the source this plays.
+    fn tempo(&self) -> f64 {
+        match self {
+            Preset::Nightcore => 1.25,
+            Preset::Vaporwave => 0.8,
+            _ => 1.0,
+        }
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Preset::BassBoost => write!(f, "bassboost"),
+            Preset::Nightcore => write!(f, "nightcore"),
+            Preset::Vaporwave => write!(f, "vaporwave"),
+            Preset::EightD => write!(f, "8d"),
+            Preset::Karaoke => write!(f, "karaoke"),
+        }
+    }
+}
+
+fn resample(factor: f64) -> Vec<String> {
+    vec![
+        format!("aresample={}", SAMPLE_RATE),
+        format!("asetrate={}", (SAMPLE_RATE * factor).round()),
+        format!("aresample={}", SAMPLE_RATE),
+    ]
+}
+
+/// The audio filters a guild has turned on, which apply to everything it plays.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filters {
+    pub preset: Option<Preset>,
+    pub speed: Option<f64>,
+    pub pitch: Option<f64>,
+    /// Equalizer bands, as a frequency in Hz and a gain in dB.
+    pub eq: Vec<(u32, f64)>,
+}
+
+impl Filters {
+    pub fn is_empty(&self) -> bool {
+        *self == Filters::default()
+    }
+
+    /// The ffmpeg filter chain for these filters, to be passed to `-af`.
+    pub fn chain(&self) -> Option<String> {
+        let mut chain = Vec::new();
+
+        if let Some(preset) = self.preset {
+            chain.extend(preset.chain());
+        }
+        if let Some(pitch) = self.pitch {
+            // pitch the sample rate, then stretch it back to the same speed
+            chain.extend(resample(pitch));
+            chain.push(format!("atempo={}", 1.0 / pitch));
+        }
+        if let Some(speed) = self.speed {
+            chain.push(format!("atempo={}", speed));
+        }
+        for (frequency, gain) in &self.eq {
+            chain.push(format!("equalizer=f={}:t=o:w=1:g={}", frequency, gain));
+        }
+
+        if chain.is_empty() {
+            None
+        } else {
+            Some(chain.join(","))
+        }
+    }
+
+    /// How much faster than the source a track plays with these filters.
+    ///
+    /// Track positions are in played time, so they have to be scaled by this to line up with the source.
+    pub fn tempo(&self) -> f64 {
+        self.preset.map_or(1.0, |p| p.tempo()) * self.speed.unwrap_or(1.0)
+    }
+}
+
+impl fmt::Display for Filters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+
+        if let Some(preset) = self.preset {
+            parts.push(preset.to_string());
+        }
+        if let Some(speed) = self.speed {
+            parts.push(format!("speed {}×", speed));
+        }
+        if let Some(pitch) = self.pitch {
+            parts.push(format!("pitch {}×", pitch));
+        }
+        if !self.eq.is_empty() {
+            let bands = self
+                .eq
+                .iter()
+                .map(|(frequency, gain)| format!("{}Hz {:+}dB", frequency, gain))
+                .collect::<Vec<_>>();
+            parts.push(format!("EQ {}", bands.join(" ")));
+        }
+
+        if parts.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", parts.join(" · "))
+        }
+    }
+}
+
+/// Where ffmpeg reads a track's audio from.
+enum StreamLocation {
+    /// A file, or a stream a site gave out for the track.
+    Trusted(String),
+    /// A link someone queued directly, which is piped into ffmpeg instead of being opened by it.
+    Link(VettedLink),
+}
+
+/// Works out where ffmpeg can read a track's audio from, going by the link it was queued with.
+async fn stream_location(url: &str) -> Result<StreamLocation, String> {
+    if let Some(relative) = url.strip_prefix(LOCAL_PREFIX) {
+        return Ok(StreamLocation::Trusted(
+            local_path(relative)?.to_string_lossy().into_owned(),
+        ));
+    }
+
+    match url.split('/').nth(2).unwrap_or("") {
+        "audius.co" | "www.audius.co" => {
+            Ok(StreamLocation::Trusted(audius_track(url).await?.stream_url))
+        }
+        "www.youtube.com" | "youtube.com" | "youtu.be" | "soundcloud.com" => {
+            let output = Command::new(youtube_dl_path())
+                .args(&["-f", "bestaudio", "-g", "--no-playlist"])
+                .arg(url)
+                .output()
+                .await
+                .map_err(|_| "I couldn't load that track again.".to_string())?;
+
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .next()
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty())
+                .map(StreamLocation::Trusted)
+                .ok_or_else(|| "I couldn't load that track again.".to_string())
+        }
+        _ => Ok(StreamLocation::Link(vet_link(url).await?)),
+    }
+}
+
+/// Restarts ffmpeg with a filter chain whenever the track is created or seeked.
+struct FilteredSource {
+    location: StreamLocation,
+    chain: String,
+    tempo: f64,
+}
+
+#[async_trait]
+impl Restart for FilteredSource {
+    async fn call_restart(&mut self, time: Option<Duration>) -> InputResult<Input> {
+        let mut pre_input_args = Vec::new();
+        if let StreamLocation::Trusted(location) = &self.location {
+            if location.starts_with("http") {
+                pre_input_args.extend(
+                    [
+                        "-reconnect",
+                        "1",
+                        "-reconnect_streamed",
+                        "1",
+                        "-reconnect_delay_max",
+                        "5",
+                    ]
+                    .iter()
+                    .map(|a| a.to_string()),
+                );
+            }
+        }
+        if let Some(time) = time {
+            // songbird seeks in played time, ffmpeg in source time
+            pre_input_args.push("-ss".to_string());
+            pre_input_args.push(format!("{:.3}", time.as_secs_f64() * self.tempo));
+        }
+        let pre_input_args = pre_input_args
+            .iter()
+            .map(|a| a.as_str())
+            .collect::<Vec<_>>();
+        let args = [
+            "-af",
+            self.chain.as_str(),
+            "-f",
+            "s16le",
+            "-ac",
+            "2",
+            "-ar",
+            "48000",
+            "-acodec",
+            "pcm_f32le",
+            "-",
+        ];
+
+        match &self.location {
+            StreamLocation::Trusted(location) => {
+                ffmpeg_optioned(location, &pre_input_args, &args).await
+            }
+            StreamLocation::Link(link) => link_ffmpeg(link, &pre_input_args, &args).await,
+        }
+    }
+
+    async fn lazy_init(&mut self) -> InputResult<(Option<Metadata>, Codec, Container)> {
+        Ok((None, Codec::FloatPcm, Container::Raw))
+    }
+}
+
+/// Creates a source for a queued track with `filters` applied.
+///
+/// Without any filters, this is the same as [`get_source`].
+pub async fn filtered_source(
+    guild_id: GuildId,
+    url: &str,
+    filters: &Filters,
+) -> Result<Input, String> {
+    let chain = match filters.chain() {
+        Some(chain) => chain,
+        None => return get_source(guild_id, url).await,
+    };
+
+    let source = FilteredSource {
+        location: stream_location(url).await?,
+        chain,
+        tempo: filters.tempo(),
+    };
+
+    match Restartable::new(source, true).await {
+        Ok(source) => Ok(source.into()),
+        Err(why) => {
+            warn!(
+                "Could not start {} with filters in guild {}: {:?}",
+                url, guild_id, why
+            );
+
+            Err("I couldn't apply filters to that track.".to_string())
+        }
+    }
+}
+
+/// Parses a speed or pitch multiplier, which ffmpeg's `atempo` limits to between 0.5 and 2.
+fn parse_factor(args: &mut Args, what: &str) -> Result<Option<f64>, CommandError> {
+    let value = args.single::<String>().unwrap_or_default().to_lowercase();
+    if value == "off" || value == "reset" {
+        return Ok(None);
+    }
+
+    match value.trim_end_matches('x').parse::<f64>() {
+        Ok(factor) if (factor - 1.0).abs() < f64::EPSILON => Ok(None),
+        Ok(factor) if (0.5..=2.0).contains(&factor) => Ok(Some(factor)),
+        _ => Err(CommandError::from(format!(
+            "h-The {} has to be between `0.5` and `2`, like `1.25`.",
+            what
+        ))),
+    }
+}
+
+/// Parses equalizer bands like `60:+6 1000:-3`.
+fn parse_eq(args: &mut Args) -> Result<Vec<(u32, f64)>, CommandError> {
+    let usage = "h-EQ bands are a frequency and a gain, like `60:+6 1000:-3`. Frequencies go from 20 to 20000 Hz, and gains from -20 to +20 dB.";
+    let mut bands = Vec::new();
+
+    for band in args.iter::<String>().filter_map(|a| a.ok()) {
+        // Units are written every which way, `Hz`, `HZ` and `dB` included.
+        let band = band.to_lowercase();
+        if band == "off" {
+            return Ok(Vec::new());
+        }
+
+        let (frequency, gain) = band
+            .split_once(':')
+            .ok_or_else(|| CommandError::from(usage))?;
+        let frequency = frequency
+            .trim_end_matches("hz")
+            .parse::<u32>()
+            .ok()
+            .filter(|f| (20..=20000).contains(f))
+            .ok_or_else(|| CommandError::from(usage))?;
+        let gain = gain
+            .trim_end_matches("db")
+            .parse::<f64>()
+            .ok()
+            .filter(|g| (-20.0..=20.0).contains(g))
+            .ok_or_else(|| CommandError::from(usage))?;
+
+        bands.retain(|(f, _)| *f != frequency);
+        bands.push((frequency, gain));
+    }
+
+    if bands.is_empty() {
+        return Err(CommandError::from(usage));
+    }
+    if bands.len() > MAX_EQ_BANDS {
+        return Err(CommandError::from(format!(
+            "h-You can only set up to {} EQ bands.",
+            MAX_EQ_BANDS
+        )));
+    }
+
+    bands.sort_by_key(|(f, _)| *f);
+    Ok(bands)
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds, dj)]
+#[aliases(filters, fx)]
+#[usage("<bassboost|nightcore|vaporwave|8d|karaoke|speed <x>|pitch <x>|eq <Hz:dB>...|off>")]
+#[example("nightcore")]
+#[example("speed 1.25")]
+#[example("eq 60:+6 8000:-3")]
+#[description(
+    "Change how the music sounds. Filters stay on for every track until they're turned off."
+)]
+async fn filter(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let queue = match guild_queue(ctx, msg.guild_id.unwrap()).await {
+        Some(queue) => queue,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "I'm not playing anything right now.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let old = queue.filters();
+    let mut filters = old.clone();
+
+    let option = args.single::<String>().unwrap_or_default().to_lowercase();
+    match option.as_str() {
+        "" => {
+            msg.channel_id
+                .say(&ctx.http, format!("🎛️ Filters: {}", old))
+                .await?;
+
+            return Ok(());
+        }
+        "off" | "clear" | "reset" | "none" => filters = Filters::default(),
+        "speed" | "tempo" => filters.speed = parse_factor(&mut args, "speed")?,
+        "pitch" => filters.pitch = parse_factor(&mut args, "pitch")?,
+        "eq" | "equalizer" => filters.eq = parse_eq(&mut args)?,
+        name => match Preset::parse(name) {
+            // picking the preset that's already on turns it off
+            Some(preset) if filters.preset == Some(preset) => filters.preset = None,
+            Some(preset) => filters.preset = Some(preset),
+            None => {
+                return Err(CommandError::from(
+                    "h-I know `bassboost`, `nightcore`, `vaporwave`, `8d` and `karaoke`, or you can set the `speed`, `pitch` or `eq`.",
+                ))
+            }
+        },
+    }
+
+    if filters == old {
+        msg.channel_id
+            .say(&ctx.http, format!("🎛️ Filters are already set to {}.", old))
+            .await?;
+
+        return Ok(());
+    }
+
+    queue.set_filters(filters.clone());
+
+    // pick the current track back up from the same point in the song
+    if let Some(current) = queue.current() {
+        if let Ok(state) = current.get_info().await {
+            let position = state.position.mul_f64(old.tempo()).div_f64(filters.tempo());
+            let paused = state.playing == PlayMode::Pause;
+
+            if let Err(why) = queue.recreate_current(position, paused).await {
+                msg.channel_id.say(&ctx.http, why).await?;
+            }
+        }
+    }
+
+    msg.channel_id
+        .say(&ctx.http, format!("🎛️ Filters: {}", filters))
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,216 @@
+use serenity::framework::standard::{macros::command, CommandError, CommandResult};
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use chrono::Utc;
+use chrono_humanize::HumanTime;
+use sqlx::PgPool;
+
+use crate::{
+    commands::voice::{
+        play::get_source,
+        session::{guild_queue, sessions},
+        DJ_CHECK, WHITELISTED_GUILDS_CHECK,
+    },
+    keys::ConnectionPool,
+    utils::{
+        queue::Request,
+        queue_store::{play_history, PlayedTrack},
+    },
+};
+
+const HISTORY_SHOWN: usize = 10;
+const CHART_SIZE: i64 = 10;
+
+/// Recently played tracks, from the running queue if there is one, otherwise from the database.
+async fn recent(
+    ctx: &Context,
+    pool: &PgPool,
+    guild_id: GuildId,
+) -> Result<Vec<PlayedTrack>, sqlx::Error> {
+    let in_memory = guild_queue(ctx, guild_id)
+        .await
+        .map(|queue| queue.history())
+        .unwrap_or_default();
+
+    if in_memory.is_empty() {
+        play_history(pool, guild_id, HISTORY_SHOWN as i64).await
+    } else {
+        Ok(in_memory)
+    }
+}
+
+/// A track as a line of text, linked when it came from the web.
+pub fn track_line(title: &Option<String>, artist: &Option<String>, url: &str) -> String {
+    let title = title.as_deref().unwrap_or("Unknown");
+    let artist = artist.as_deref().unwrap_or("unknown");
+
+    if url.starts_with("http") {
+        format!("[{}]({}) by {}", title, url, artist)
+    } else {
+        format!("{} by {}", title, artist)
+    }
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds)]
+#[aliases(recent)]
+#[description("Show what played recently, and who asked for it.")]
+async fn history(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let pool = {
+        let data = ctx.data.read().await;
+        data.get::<ConnectionPool>().unwrap().clone()
+    };
+
+    let played = recent(ctx, &pool, guild_id).await?;
+    if played.is_empty() {
+        msg.channel_id
+            .say(&ctx.http, "Nothing has been played here yet.")
+            .await?;
+
+        return Ok(());
+    }
+
+    let now = Utc::now();
+    let mut description = String::new();
+    for (i, played) in played.iter().take(HISTORY_SHOWN).enumerate() {
+        let track = &played.track;
+        description.push_str(&format!(
+            "`{}.` {}\nrequested by {}, {}\n",
+            i + 1,
+            track_line(&track.title, &track.artist, &track.source_url),
+            track.requester.mention(),
+            HumanTime::from(played.played_at - now)
+        ));
+    }
+
+    msg.channel_id
+        .send_message(&ctx.http, |m| {
+            m.embed(|e| {
+                e.title("🕘 Recently played")
+                    .color(0xb90000)
+                    .description(description)
+            })
+        })
+        .await?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds, dj)]
+#[aliases(prev, back)]
+#[description("Play the last track again, before whatever's playing now.")]
+async fn previous(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let pool = {
+        let data = ctx.data.read().await;
+        data.get::<ConnectionPool>().unwrap().clone()
+    };
+
+    let last = match recent(ctx, &pool, guild_id).await?.into_iter().next() {
+        Some(last) => last.track,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "Nothing has been played here yet.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let session = match sessions(ctx).await.get(guild_id) {
+        Some(session) => session,
+        None => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "I'm not in a voice channel right now. Use `play` to get me to join.",
+                )
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let source = get_source(guild_id, &last.source_url)
+        .await
+        .map_err(|why| CommandError::from(format!("h-{}", why)))?;
+    let mut handler = session.call.lock().await;
+    session.queue.add_source_front(
+        source,
+        Request::new(msg.author.id, msg.channel_id),
+        &mut handler,
+    );
+
+    msg.channel_id
+        .say(
+            &ctx.http,
+            format!(
+                "⏮️ Playing `{}` again.",
+                last.title.as_deref().unwrap_or("the last track")
+            ),
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds)]
+#[aliases(chart, mp)]
+#[description("Show the tracks played the most in this server.")]
+async fn mostplayed(ctx: &Context, msg: &Message) -> CommandResult {
+    let data = ctx.data.read().await;
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    let tracks = sqlx::query!(
+        "
+    select source_url, max(title) as title, max(artist) as artist, count(*) as \"plays!\"
+    from play_history
+    where guild_id = $1
+    group by source_url
+    order by 4 desc, max(played_at) desc
+    limit $2
+    ",
+        msg.guild_id.unwrap().0 as i64,
+        CHART_SIZE
+    )
+    .fetch_all(pool)
+    .await?;
+
+    if tracks.is_empty() {
+        msg.channel_id
+            .say(&ctx.http, "Nothing has been played here yet.")
+            .await?;
+
+        return Ok(());
+    }
+
+    let mut description = String::new();
+    for (i, track) in tracks.iter().enumerate() {
+        description.push_str(&format!(
+            "`{}.` {} · {} {}\n",
+            i + 1,
+            track_line(&track.title, &track.artist, &track.source_url),
+            track.plays,
+            if track.plays == 1 { "play" } else { "plays" }
+        ));
+    }
+
+    msg.channel_id
+        .send_message(&ctx.http, |m| {
+            m.embed(|e| {
+                e.title("📈 Most played")
+                    .color(0xb90000)
+                    .description(description)
+            })
+        })
+        .await?;
+
+    Ok(())
+}
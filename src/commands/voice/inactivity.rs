@@ -0,0 +1,188 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serenity::{async_trait, cache::Cache, http::Http, model::prelude::*};
+use songbird::{Event, EventContext, EventHandler as VoiceEventHandler, Songbird};
+use sqlx::PgPool;
+
+use crate::{
+    commands::voice::{listeners_with, session::SessionManager},
+    utils::queue::TrackQueue,
+};
+
+/// How often the watcher looks at the channel.
+pub const INACTIVITY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How many minutes the bot sticks around with nobody listening or nothing to play, unless
+/// the guild has set its own.
+const DEFAULT_INACTIVITY_TIMEOUT: i32 = 5;
+/// The longest a guild can have the bot stick around for, in minutes.
+pub const MAX_INACTIVITY_TIMEOUT: i32 = 180;
+
+/// Leaves the voice channel once the bot has been alone, or had nothing to play, for too long.
+///
+/// This is registered as a periodic event on the call. How long is too long is set per guild,
+/// and 24/7 mode turns it off.
+/// Each check runs in its own task, so reading the settings doesn't hold up the call's other events.
+pub struct InactivityWatcher(Arc<Watcher>);
+
+struct Watcher {
+    guild_id: GuildId,
+    chan_id: ChannelId,
+    http: Arc<Http>,
+    cache: Arc<Cache>,
+    manager: Arc<Songbird>,
+    sessions: Arc<SessionManager>,
+    queue: TrackQueue,
+    pool: PgPool,
+    inactive_since: Mutex<Option<Instant>>,
+    /// Set while a check is running, so a slow one doesn't get another started alongside it.
+    checking: AtomicBool,
+}
+
+impl InactivityWatcher {
+    pub fn new(
+        guild_id: GuildId,
+        chan_id: ChannelId,
+        http: Arc<Http>,
+        cache: Arc<Cache>,
+        manager: Arc<Songbird>,
+        sessions: Arc<SessionManager>,
+        queue: TrackQueue,
+        pool: PgPool,
+    ) -> Self {
+        Self(Arc::new(Watcher {
+            guild_id,
+            chan_id,
+            http,
+            cache,
+            manager,
+            sessions,
+            queue,
+            pool,
+            inactive_since: Mutex::new(None),
+            checking: AtomicBool::new(false),
+        }))
+    }
+}
+
+/// Whether a guild has 24/7 mode on, which keeps the bot in voice no matter what.
+pub async fn always_on(pool: &PgPool, guild_id: GuildId) -> Result<bool, sqlx::Error> {
+    let guild = sqlx::query!(
+        "
+    select always_on
+    from guilds
+    where id = $1
+    ",
+        guild_id.0 as i64
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(guild.and_then(|g| g.always_on).unwrap_or(false))
+}
+
+/// Returns how long the bot stays in voice in a guild with nobody listening or nothing to play.
+pub async fn inactivity_timeout(pool: &PgPool, guild_id: GuildId) -> Result<Duration, sqlx::Error> {
+    let guild = sqlx::query!(
+        "
+    select inactivity_timeout
+    from guilds
+    where id = $1
+    ",
+        guild_id.0 as i64
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let minutes = guild
+        .and_then(|g| g.inactivity_timeout)
+        .filter(|m| *m > 0)
+        .unwrap_or(DEFAULT_INACTIVITY_TIMEOUT);
+
+    Ok(Duration::from_secs(minutes as u64 * 60))
+}
+
+impl Watcher {
+    /// Leaves if the bot has been inactive for long enough.
+    async fn check(&self) {
+        if always_on(&self.pool, self.guild_id).await.unwrap_or(false) {
+            *self.inactive_since.lock() = None;
+            return;
+        }
+        let timeout = match inactivity_timeout(&self.pool, self.guild_id).await {
+            Ok(timeout) => timeout,
+            Err(why) => {
+                warn!(
+                    "Could not read the inactivity timeout for guild {}: {:?}",
+                    self.guild_id, why
+                );
+                return;
+            }
+        };
+
+        let alone = match self.cache.guild(self.guild_id) {
+            Some(guild) => listeners_with(&self.manager, &guild)
+                .await
+                .map_or(true, |(_, users)| users.is_empty()),
+            None => false,
+        };
+        let idle = self.queue.is_empty();
+
+        let reason = if alone {
+            "nobody's been listening"
+        } else if idle {
+            "nothing's been playing"
+        } else {
+            *self.inactive_since.lock() = None;
+            return;
+        };
+
+        let since = *self.inactive_since.lock().get_or_insert_with(Instant::now);
+        if since.elapsed() < timeout {
+            return;
+        }
+
+        info!("Leaving voice in guild {}: {}.", self.guild_id, reason);
+
+        // this drops the handler along with the call.
+        self.sessions.end(&self.manager, self.guild_id).await;
+
+        let length = match timeout.as_secs() / 60 {
+            1 => "a minute".to_string(),
+            m => format!("{} minutes", m),
+        };
+        let _ = self
+            .chan_id
+            .say(
+                &self.http,
+                format!(
+                    "👋 Leaving, since {} for {}. See you again soon!",
+                    reason, length
+                ),
+            )
+            .await;
+    }
+}
+
+#[async_trait]
+impl VoiceEventHandler for InactivityWatcher {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        if self.0.checking.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+
+        let watcher = self.0.clone();
+        tokio::spawn(async move {
+            watcher.check().await;
+            watcher.checking.store(false, Ordering::Release);
+        });
+
+        None
+    }
+}
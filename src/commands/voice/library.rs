@@ -0,0 +1,482 @@
+use serenity::framework::standard::{macros::command, CommandError, CommandResult};
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use songbird::input::{Input, Restartable};
+use sqlx::PgPool;
+use std::{
+    collections::HashSet,
+    env, fs,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+use tokio::process::Command;
+
+use crate::keys::ConnectionPool;
+
+/// Queue entries for library files carry this prefix, followed by the path inside the library.
+pub const LOCAL_PREFIX: &str = "local:";
+const AUDIO_EXTENSIONS: [&str; 8] = ["mp3", "flac", "ogg", "oga", "opus", "m4a", "wav", "aac"];
+
+/// A file in the local music library.
+pub struct LocalTrack {
+    /// Where the file is, relative to the library root.
+    pub path: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+#[derive(Default)]
+pub struct ScanSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub removed: u64,
+    pub failed: usize,
+}
+
+/// The library root from `MUSIC_LIBRARY`, if one is set up.
+fn library_root() -> Result<PathBuf, String> {
+    let root = env::var("MUSIC_LIBRARY")
+        .map_err(|_| "There isn't a local music library set up here.".to_string())?;
+
+    Path::new(&root).canonicalize().map_err(|why| {
+        error!("Could not open the music library at {}: {:?}", root, why);
+        "I couldn't open the local music library.".to_string()
+    })
+}
+
+/// Resolves a path inside the library, refusing anything that ends up outside of it.
+fn library_path(root: &Path, relative: &str) -> Result<PathBuf, String> {
+    let path = root
+        .join(relative)
+        .canonicalize()
+        .map_err(|_| "That file isn't in the music library anymore.".to_string())?;
+
+    if path.starts_with(root) {
+        Ok(path)
+    } else {
+        Err("That file isn't in the music library.".to_string())
+    }
+}
+
+/// Where a library file is on disk, for sources which need to open it themselves.
+pub fn local_path(relative: &str) -> Result<PathBuf, String> {
+    library_path(&library_root()?, relative)
+}
+
+/// Collects every audio file under the root, following symlinks only while they stay inside it.
+///
+/// Each directory is only read once, so symlinks which loop back on themselves can't keep it going.
+fn walk_library(root: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    let mut walked = HashSet::new();
+
+    while let Some(dir) = dirs.pop() {
+        if !walked.insert(dir.clone()) {
+            continue;
+        }
+
+        let read = match fs::read_dir(&dir) {
+            Ok(read) => read,
+            Err(why) => {
+                warn!("Could not read {:?}: {:?}", dir, why);
+                continue;
+            }
+        };
+
+        for entry in read.filter_map(|e| e.ok()) {
+            let path = match entry.path().canonicalize() {
+                Ok(path) if path.starts_with(root) => path,
+                _ => continue,
+            };
+
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path
+                .extension()
+                .and_then(|e| e.to_str())
+                .map_or(false, |e| {
+                    AUDIO_EXTENSIONS.contains(&e.to_lowercase().as_str())
+                })
+            {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    files.dedup();
+    files
+}
+
+fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Reads the tags and duration of a file with ffprobe, which handles ID3, Vorbis comments and FLAC alike.
+async fn probe(
+    path: &Path,
+) -> Option<(
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<Duration>,
+)> {
+    let output = Command::new("ffprobe")
+        .args(&[
+            "-v",
+            "quiet",
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+        ])
+        .arg(path)
+        .output()
+        .await
+        .ok()?;
+    let probed = serde_json::from_slice::<Value>(&output.stdout).ok()?;
+
+    // Ogg files keep their tags on the stream rather than the container
+    let tag = |name: &str| {
+        let format_tags = probed.get("format").and_then(|f| f.get("tags"));
+        let stream_tags = probed
+            .get("streams")
+            .and_then(|s| s.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|s| s.get("tags"));
+
+        format_tags
+            .into_iter()
+            .chain(stream_tags)
+            .filter_map(|tags| tags.as_object())
+            .flat_map(|tags| tags.iter())
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .and_then(|(_, value)| value.as_str())
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    let duration = probed
+        .get("format")
+        .and_then(|f| f.get("duration"))
+        .and_then(|d| d.as_str())
+        .and_then(|d| d.parse::<f64>().ok())
+        .map(Duration::from_secs_f64);
+
+    Some((tag("title"), tag("artist"), tag("album"), duration))
+}
+
+/// Notes that a file was still on disk during the scan started at `started`, so it's kept.
+async fn mark_seen(
+    pool: &PgPool,
+    relative: &str,
+    started: chrono::DateTime<chrono::Utc>,
+) -> Result<(), String> {
+    sqlx::query!(
+        "
+    update local_tracks
+    set scanned_at = $1
+    where path = $2
+    ",
+        started,
+        relative
+    )
+    .execute(pool)
+    .await
+    .map_err(|why| format!("{:?}", why))?;
+
+    Ok(())
+}
+
+/// Brings the library index up to date with what's on disk.
+///
+/// Files which haven't changed size or modification time since the last scan aren't hashed again.
+pub async fn scan_library(pool: &PgPool) -> Result<ScanSummary, String> {
+    let root = library_root()?;
+    let started = chrono::Utc::now();
+    let mut summary = ScanSummary::default();
+
+    let walk_root = root.clone();
+    let files = tokio::task::spawn_blocking(move || walk_library(&walk_root))
+        .await
+        .map_err(|_| "Scanning the music library failed.".to_string())?;
+
+    for path in files {
+        let relative = match path.strip_prefix(&root).ok().and_then(|p| p.to_str()) {
+            Some(relative) => relative.to_string(),
+            None => continue,
+        };
+        let (size, modified) = match fs::metadata(&path) {
+            Ok(meta) => (
+                meta.len() as i64,
+                meta.modified()
+                    .ok()
+                    .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |m| m.as_secs() as i64),
+            ),
+            Err(_) => {
+                // it's still there, so an older entry for it can stay
+                mark_seen(pool, &relative, started).await?;
+                summary.failed += 1;
+                continue;
+            }
+        };
+
+        let known = sqlx::query!(
+            "
+        select size, modified
+        from local_tracks
+        where path = $1
+        ",
+            relative
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(|why| format!("{:?}", why))?;
+
+        if let Some(known) = &known {
+            if known.size == size && known.modified == modified {
+                mark_seen(pool, &relative, started).await?;
+
+                summary.unchanged += 1;
+                continue;
+            }
+        }
+
+        let hash_path = path.clone();
+        let hash = match tokio::task::spawn_blocking(move || hash_file(&hash_path)).await {
+            Ok(Ok(hash)) => hash,
+            _ => {
+                mark_seen(pool, &relative, started).await?;
+                summary.failed += 1;
+                continue;
+            }
+        };
+        let (title, artist, album, duration) = probe(&path).await.unwrap_or_default();
+
+        sqlx::query!(
+            "
+        insert into local_tracks(path, title, artist, album, duration, hash, size, modified, scanned_at)
+        values($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        on conflict (path) do update
+        set title = $2, artist = $3, album = $4, duration = $5, hash = $6, size = $7, modified = $8, scanned_at = $9
+        ",
+            relative,
+            title,
+            artist,
+            album,
+            duration.map(|d| d.as_millis() as i64),
+            hash,
+            size,
+            modified,
+            started
+        )
+        .execute(pool)
+        .await
+        .map_err(|why| format!("{:?}", why))?;
+
+        if known.is_some() {
+            summary.updated += 1;
+        } else {
+            summary.added += 1;
+        }
+    }
+
+    // anything not seen in this scan is gone from disk, since files that couldn't be read were still marked
+    summary.removed = sqlx::query!(
+        "
+    delete from local_tracks
+    where scanned_at < $1
+    ",
+        started
+    )
+    .execute(pool)
+    .await
+    .map_err(|why| format!("{:?}", why))?
+    .rows_affected();
+
+    Ok(summary)
+}
+
+/// Finds the best match in the library for a title, artist, album or file name.
+pub async fn search_library(pool: &PgPool, query: &str) -> Result<Option<LocalTrack>, sqlx::Error> {
+    let pattern = format!("%{}%", query.replace('%', "\\%").replace('_', "\\_"));
+
+    let track = sqlx::query!(
+        "
+    select path, title, artist, duration
+    from local_tracks
+    where title ilike $1 or artist ilike $1 or album ilike $1 or path ilike $1
+    order by (title ilike $1) desc, artist, album, title
+    limit 1
+    ",
+        pattern
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(track.map(|t| LocalTrack {
+        path: t.path,
+        title: t.title,
+        artist: t.artist,
+        duration: t.duration.map(|d| Duration::from_millis(d as u64)),
+    }))
+}
+
+/// Opens a library file through ffmpeg, with the metadata from its tags.
+pub async fn local_source(guild_id: GuildId, relative: &str) -> Result<Input, String> {
+    let root = library_root()?;
+    let path = library_path(&root, relative)?;
+    let (title, artist, _, duration) = probe(&path).await.unwrap_or_default();
+
+    let mut source: Input = match Restartable::ffmpeg(path.clone().into_os_string(), true).await {
+        Ok(source) => source.into(),
+        Err(why) => {
+            warn!(
+                "Could not start library file {} in guild {}: {:?}",
+                relative, guild_id, why
+            );
+
+            return Err("I couldn't open that file.".to_string());
+        }
+    };
+
+    let file_name = path
+        .file_stem()
+        .map(|name| name.to_string_lossy().into_owned());
+    source.metadata.title = title.or(file_name);
+    source.metadata.artist = artist;
+    source.metadata.duration = duration.or(source.metadata.duration);
+    source.metadata.source_url = Some(format!("{}{}", LOCAL_PREFIX, relative));
+
+    Ok(source)
+}
+
+#[command]
+#[aliases(libscan)]
+#[description("Rescan the local music library for new, changed and removed files.")]
+#[owners_only]
+async fn rescan(ctx: &Context, msg: &Message) -> CommandResult {
+    // the scan takes a while, so don't hold on to the data lock for it
+    let pool = {
+        let data = ctx.data.read().await;
+        data.get::<ConnectionPool>().unwrap().clone()
+    };
+
+    msg.channel_id
+        .say(&ctx.http, "Scanning the music library...")
+        .await?;
+
+    let summary = scan_library(&pool)
+        .await
+        .map_err(|why| CommandError::from(format!("h-{}", why)))?;
+
+    msg.channel_id
+        .say(
+            &ctx.http,
+            format!(
+                "Done! {} added, {} updated, {} unchanged, {} removed.{}",
+                summary.added,
+                summary.updated,
+                summary.unchanged,
+                summary.removed,
+                match summary.failed {
+                    0 => String::new(),
+                    n => format!(" {} files couldn't be read.", n),
+                }
+            ),
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// A fresh directory to build a library in, and the canonical path of its root.
+    fn test_library(name: &str) -> (PathBuf, PathBuf) {
+        let dir =
+            env::temp_dir().join(format!("maki-test-library-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root")).unwrap();
+
+        let root = dir.join("root").canonicalize().unwrap();
+        (dir, root)
+    }
+
+    #[test]
+    fn library_paths_stay_inside_the_root() {
+        let (dir, root) = test_library("paths");
+        fs::create_dir_all(root.join("album")).unwrap();
+        fs::write(root.join("album/song.mp3"), "").unwrap();
+        fs::write(dir.join("secret.mp3"), "").unwrap();
+        symlink(dir.join("secret.mp3"), root.join("escape.mp3")).unwrap();
+        symlink(root.join("album/song.mp3"), root.join("inside.mp3")).unwrap();
+
+        assert_eq!(
+            library_path(&root, "album/song.mp3"),
+            Ok(root.join("album/song.mp3"))
+        );
+        assert_eq!(
+            library_path(&root, "inside.mp3"),
+            Ok(root.join("album/song.mp3"))
+        );
+        assert!(library_path(&root, "../secret.mp3").is_err());
+        assert!(library_path(&root, "album/../../secret.mp3").is_err());
+        assert!(library_path(&root, "escape.mp3").is_err());
+        assert!(library_path(&root, &dir.join("secret.mp3").to_string_lossy()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn walking_keeps_audio_files_inside_the_root() {
+        let (dir, root) = test_library("walk");
+        fs::create_dir_all(root.join("album/disc 2")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        fs::write(root.join("album/one.mp3"), "").unwrap();
+        fs::write(root.join("album/disc 2/two.FLAC"), "").unwrap();
+        fs::write(root.join("album/cover.jpg"), "").unwrap();
+        fs::write(root.join("album/notes"), "").unwrap();
+        fs::write(dir.join("outside/three.mp3"), "").unwrap();
+        symlink(dir.join("outside"), root.join("linked")).unwrap();
+        symlink(dir.join("outside/three.mp3"), root.join("three.mp3")).unwrap();
+
+        assert_eq!(
+            walk_library(&root),
+            vec![
+                root.join("album/disc 2/two.FLAC"),
+                root.join("album/one.mp3")
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn walking_stops_at_symlink_cycles() {
+        let (dir, root) = test_library("cycle");
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/b/song.ogg"), "").unwrap();
+        symlink(root.join("a"), root.join("a/b/loop")).unwrap();
+        symlink(&root, root.join("a/top")).unwrap();
+
+        assert_eq!(walk_library(&root), vec![root.join("a/b/song.ogg")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
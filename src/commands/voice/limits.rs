@@ -0,0 +1,80 @@
+use serenity::model::prelude::*;
+
+use sqlx::PgPool;
+use std::time::Duration;
+
+use crate::utils::time::format_duration;
+
+/// The most tracks a guild can let each person have waiting.
+pub const MAX_PENDING_LIMIT: i32 = 100;
+/// The longest a guild's track length limit can be, in minutes.
+pub const MAX_LENGTH_LIMIT: i32 = 600;
+
+/// Limits on what people can add to a guild's queue. DJs aren't held to them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueLimits {
+    /// How many tracks each person can have waiting after the current one.
+    pub max_pending: Option<usize>,
+    /// How long a single track can be. Tracks without a known length, like streams, are let through.
+    pub max_length: Option<Duration>,
+}
+
+impl QueueLimits {
+    /// How many more tracks someone with `pending` tracks waiting can add.
+    pub fn room(&self, pending: usize) -> Option<usize> {
+        self.max_pending.map(|max| max.saturating_sub(pending))
+    }
+
+    /// Checks a track's length against the limit, explaining what's wrong if it's too long.
+    pub fn check_length(&self, length: Option<Duration>) -> Result<(), String> {
+        match (length, self.max_length) {
+            (Some(length), Some(max)) if length > max => Err(format!(
+                "That track is {} long, and tracks here can only be up to {}.",
+                format_duration(length),
+                format_duration(max)
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether the guild has upcoming tracks take turns between the people who added them.
+pub async fn fair_queue(pool: &PgPool, guild_id: GuildId) -> Result<bool, sqlx::Error> {
+    let guild = sqlx::query!(
+        "
+    select fair_queue
+    from guilds
+    where id = $1
+    ",
+        guild_id.0 as i64
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(guild.and_then(|g| g.fair_queue).unwrap_or(false))
+}
+
+/// The limits the guild has set on what people can add to the queue.
+pub async fn queue_limits(pool: &PgPool, guild_id: GuildId) -> Result<QueueLimits, sqlx::Error> {
+    let guild = sqlx::query!(
+        "
+    select max_pending, max_track_length
+    from guilds
+    where id = $1
+    ",
+        guild_id.0 as i64
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(match guild {
+        Some(guild) => QueueLimits {
+            max_pending: guild.max_pending.filter(|m| *m > 0).map(|m| m as usize),
+            max_length: guild
+                .max_track_length
+                .filter(|m| *m > 0)
+                .map(|m| Duration::from_secs(m as u64)),
+        },
+        None => QueueLimits::default(),
+    })
+}
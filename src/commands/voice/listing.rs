@@ -0,0 +1,216 @@
+use serenity::builder::CreateEmbed;
+use serenity::framework::standard::CommandResult;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use futures::StreamExt;
+use std::time::Duration;
+
+use crate::{
+    commands::voice::history::track_line,
+    utils::{
+        queue::{LoopMode, TrackQueue},
+        queue_store::StoredTrack,
+        time::format_duration,
+    },
+};
+
+const PAGE_SIZE: usize = 10;
+/// How long the page reactions keep working for.
+const PAGE_TIMEOUT: Duration = Duration::from_secs(120);
+const PREVIOUS_EMOJI: &str = "◀️";
+const NEXT_EMOJI: &str = "▶️";
+
+fn requested_by(track: &StoredTrack) -> String {
+    match track.request() {
+        Some(request) => request.to_string(),
+        None => track.requester.mention().to_string(),
+    }
+}
+
+/// The queue as it was when it was listed, laid out so pages can be drawn without touching the queue again.
+struct Listing {
+    now_playing: String,
+    upcoming: Vec<String>,
+    /// How long until the queue runs out, and whether that includes every track.
+    remaining: Duration,
+    remaining_known: bool,
+    loop_mode: LoopMode,
+    fair: bool,
+}
+
+impl Listing {
+    async fn new(queue: &TrackQueue) -> Option<Self> {
+        let snapshot = queue.snapshot();
+        let loop_mode = queue.loop_mode();
+        let fair = queue.fair_mode();
+        let tempo = queue.filters().tempo();
+
+        let (current, upcoming) = snapshot.tracks.split_first()?;
+        let position = match &snapshot.current {
+            Some(handle) => handle
+                .get_info()
+                .await
+                .map_or(Duration::default(), |s| s.position),
+            None => Duration::default(),
+        };
+
+        // speed filters make tracks take more or less time to play than they are long
+        let played_length = |length: Option<Duration>| length.map(|l| l.div_f64(tempo));
+
+        let current_length = played_length(current.duration);
+        let now_playing = format!(
+            "{}\n`{} / {}` · requested by {}",
+            track_line(&current.title, &current.artist, &current.source_url),
+            format_duration(position),
+            current_length.map_or("?".to_string(), format_duration),
+            requested_by(current)
+        );
+
+        // a repeating track never gets to the rest of the queue
+        let mut eta = match loop_mode {
+            LoopMode::Track => None,
+            _ => current_length.map(|l| l.checked_sub(position).unwrap_or_default()),
+        };
+        let mut remaining = current_length
+            .map(|l| l.checked_sub(position).unwrap_or_default())
+            .unwrap_or_default();
+        let mut remaining_known = current_length.is_some();
+
+        let mut lines = Vec::with_capacity(upcoming.len());
+        for (i, track) in upcoming.iter().enumerate() {
+            let length = played_length(track.duration);
+
+            lines.push(format!(
+                "`{}.` {} `{}`\n{} · plays in {}",
+                i + 1,
+                track_line(&track.title, &track.artist, &track.source_url),
+                length.map_or("?".to_string(), format_duration),
+                requested_by(track),
+                eta.map_or("?".to_string(), format_duration)
+            ));
+
+            eta = eta.zip(length).map(|(eta, length)| eta + length);
+            match length {
+                Some(length) => remaining += length,
+                None => remaining_known = false,
+            }
+        }
+
+        Some(Self {
+            now_playing,
+            upcoming: lines,
+            remaining,
+            remaining_known,
+            loop_mode,
+            fair,
+        })
+    }
+
+    fn pages(&self) -> usize {
+        ((self.upcoming.len() + PAGE_SIZE - 1) / PAGE_SIZE).max(1)
+    }
+
+    fn page<'a>(&self, e: &'a mut CreateEmbed, page: usize) -> &'a mut CreateEmbed {
+        let shown = self
+            .upcoming
+            .iter()
+            .skip(page * PAGE_SIZE)
+            .take(PAGE_SIZE)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let description = if shown.is_empty() {
+            "Nothing else is queued.".to_string()
+        } else {
+            shown.join("\n")
+        };
+
+        let mut footer = format!(
+            "Page {}/{} · {} up next · {}{} left",
+            page + 1,
+            self.pages(),
+            self.upcoming.len(),
+            format_duration(self.remaining),
+            if self.remaining_known { "" } else { "+" }
+        );
+        if self.loop_mode != LoopMode::Off {
+            footer.push_str(&format!(" · 🔁 Looping: {}", self.loop_mode));
+        }
+        if self.fair {
+            footer.push_str(" · ⚖️ Fair mode");
+        }
+
+        e.title("🎶 Queue")
+            .color(0xb90000)
+            .field("Now playing", &self.now_playing, false)
+            .description(description)
+            .footer(|f| f.text(footer))
+    }
+}
+
+/// Sends a paged listing of the queue, with reactions to flip through the pages.
+pub async fn show_queue(ctx: &Context, channel_id: ChannelId, queue: &TrackQueue) -> CommandResult {
+    let listing = match Listing::new(queue).await {
+        Some(listing) => listing,
+        None => {
+            channel_id
+                .say(&ctx.http, "There isn't anything in the queue.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let message = channel_id
+        .send_message(&ctx.http, |m| m.embed(|e| listing.page(e, 0)))
+        .await?;
+
+    if listing.pages() > 1 {
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(why) = flip_pages(&ctx, message, listing).await {
+                debug!("Stopped paging the queue: {:?}", why);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Moves between pages whenever someone reacts, until the reactions time out.
+async fn flip_pages(ctx: &Context, mut message: Message, listing: Listing) -> serenity::Result<()> {
+    for emoji in [PREVIOUS_EMOJI, NEXT_EMOJI] {
+        message
+            .react(ctx, ReactionType::Unicode(emoji.to_string()))
+            .await?;
+    }
+
+    let bot_id = ctx.cache.current_user_id();
+    let mut reactions = message
+        .await_reactions(ctx)
+        .timeout(PAGE_TIMEOUT)
+        .filter(move |r| r.user_id != Some(bot_id))
+        .await;
+
+    let pages = listing.pages();
+    let mut page = 0;
+    while let Some(action) = reactions.next().await {
+        let reaction = action.as_inner_ref();
+        page = match &reaction.emoji {
+            ReactionType::Unicode(e) if e == PREVIOUS_EMOJI => (page + pages - 1) % pages,
+            ReactionType::Unicode(e) if e == NEXT_EMOJI => (page + 1) % pages,
+            _ => continue,
+        };
+
+        // this needs manage messages, and paging works without it
+        let _ = reaction.delete(ctx).await;
+        message
+            .edit(ctx, |m| m.embed(|e| listing.page(e, page)))
+            .await?;
+    }
+
+    let _ = message.delete_reactions(ctx).await;
+
+    Ok(())
+}
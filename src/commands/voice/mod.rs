@@ -1,97 +1,216 @@
+pub mod allowlist;
+pub mod announce;
+pub mod autoplay;
+pub mod controls;
+pub mod edit;
+pub mod filters;
+pub mod history;
+pub mod inactivity;
+pub mod library;
+pub mod limits;
+pub mod listing;
 pub mod play;
+pub mod policy;
+pub mod record;
+pub mod restore;
+pub mod scrobble;
+pub mod session;
 pub mod sources;
+pub mod stats;
+pub mod transition;
 
-use std::{collections::{HashMap, hash_map::RandomState}, sync::Arc};
+use std::sync::Arc;
 
-use serenity::{model::id::GuildId, async_trait, http::Http, model::prelude::ChannelId, framework::standard::{Args, CommandOptions, Reason, macros::check}};
 use serenity::model::prelude::*;
 use serenity::prelude::*;
+use serenity::{
+    async_trait,
+    framework::standard::{macros::check, Args, CommandOptions, Reason},
+    model::id::GuildId,
+    model::prelude::ChannelId,
+};
 
+use crate::{
+    commands::voice::{allowlist::is_music_allowed, record::Recorder, stats::VoiceStats},
+    keys::ConnectionPool,
+};
 use songbird::{
     model::payload::{ClientConnect, ClientDisconnect, Speaking},
-    Event, EventContext, EventHandler as VoiceEventHandler,
+    Event, EventContext, EventHandler as VoiceEventHandler, Songbird,
 };
-use crate::utils::queue::TrackQueue;
+use sqlx::PgPool;
+
+/// Returns the voice channel the bot is in for a guild, along with every non-bot user in it.
+pub async fn listeners(ctx: &Context, guild: &Guild) -> Option<(ChannelId, Vec<UserId>)> {
+    let manager = songbird::get(ctx)
+        .await
+        .expect("Songbird Voice client placed in at initialisation.")
+        .clone();
+
+    listeners_with(&manager, guild).await
+}
+
+/// Same as [`listeners`], for places which have a handle to songbird but no [`Context`].
+pub async fn listeners_with(manager: &Songbird, guild: &Guild) -> Option<(ChannelId, Vec<UserId>)> {
+    let channel = {
+        let handler_lock = manager.get(guild.id)?;
+        let handler = handler_lock.lock().await;
+        ChannelId(handler.current_channel()?.0)
+    };
+
+    let users = guild
+        .voice_states
+        .values()
+        .filter(|state| state.channel_id == Some(channel))
+        .filter(|state| {
+            !guild
+                .members
+                .get(&state.user_id)
+                .map_or(false, |member| member.user.bot)
+        })
+        .map(|state| state.user_id)
+        .collect();
+
+    Some((channel, users))
+}
 
-use tokio::sync::RwLock;
+/// Returns the role set as the guild's DJ role, if there is one.
+pub async fn dj_role(pool: &PgPool, guild_id: GuildId) -> Result<Option<RoleId>, sqlx::Error> {
+    let guild = sqlx::query!(
+        "
+    select dj_role
+    from guilds
+    where id = $1
+    ",
+        guild_id.0 as i64
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(guild
+        .and_then(|g| g.dj_role)
+        .map(|role| RoleId(role as u64)))
+}
+
+/// Whether a member can manage the voice session (skipping, clearing the queue, etc).
+///
+/// That's anyone with the guild's DJ role or the Manage Channels permission, or
+/// whoever is listening on their own.
+pub async fn is_dj(ctx: &Context, guild: &Guild, user_id: UserId) -> bool {
+    if guild.owner_id == user_id {
+        return true;
+    }
+
+    let member = match guild.members.get(&user_id) {
+        Some(member) => member,
+        None => return false,
+    };
+
+    // @everyone shares its id with the guild
+    let has_permission = member
+        .roles
+        .iter()
+        .chain(std::iter::once(&RoleId(guild.id.0)))
+        .filter_map(|role| guild.roles.get(role))
+        .any(|role| {
+            role.permissions.contains(Permissions::ADMINISTRATOR)
+                || role.permissions.contains(Permissions::MANAGE_CHANNELS)
+        });
+    if has_permission {
+        return true;
+    }
+
+    let role = {
+        let data = ctx.data.read().await;
+        let pool = data.get::<ConnectionPool>().unwrap();
+        dj_role(pool, guild.id).await.unwrap_or(None)
+    };
+    if let Some(role) = role {
+        if member.roles.contains(&role) {
+            return true;
+        }
+    }
 
-pub struct TrackEndNotifier {
-    guild_id: GuildId,
-    chan_id: ChannelId,
-    http: Arc<Http>,
-    //manager: Arc<Songbird>,
-    queue: Arc<RwLock<HashMap<GuildId, TrackQueue, RandomState>>>,
+    match listeners(ctx, guild).await {
+        Some((_, users)) => users == [user_id],
+        None => false,
+    }
 }
 
 #[check]
-#[name = "whitelisted_guilds"]
-async fn music_check(_: &Context, msg: &Message, _: &mut Args, _: &CommandOptions) -> Result<(), Reason> {
-    let allowed_guilds = [&228625269101953035, &290284538733658112, &781421814601089055, &418093857394262020, &381880193251409931, &828548609322254357, &720025586034147338, &749034687392907344, &553948136629075968];
-    if !allowed_guilds.contains(&msg.guild_id.unwrap_or(GuildId(1)).as_u64()) {
-        return Err(Reason::Log("Guild is not in allowlist".to_string()));
+#[name = "dj"]
+async fn dj_check(
+    ctx: &Context,
+    msg: &Message,
+    _: &mut Args,
+    _: &CommandOptions,
+) -> Result<(), Reason> {
+    let guild = match msg.guild(&ctx.cache) {
+        Some(guild) => guild,
+        None => return Err(Reason::Log("Guild is not in the cache".to_string())),
+    };
+
+    if !is_dj(ctx, &guild, msg.author.id).await {
+        return Err(Reason::User(
+            "You need the DJ role or the Manage Channels permission for this, unless you're listening on your own."
+                .to_string(),
+        ));
     }
 
     Ok(())
 }
 
-#[async_trait]
-impl VoiceEventHandler for TrackEndNotifier {
-    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
-        if let EventContext::Track(_track_list) = ctx {
-            let q = self.queue.write().await;
-
-            let q_guild = q.get(&self.guild_id).unwrap();
-
-            dbg!(q_guild.current()?.metadata());
-            let cguild = q_guild.current()?;
-            let m = cguild.metadata();
-            //dbg!(q_guild.len());
-            let message = match q_guild.len() {
-                0 => "No songs left in queue.".to_string(),
-                _ => match (&m.title, &m.track, &m.artist) {
-                    (None, Some(t), Some(a)) => format!("Now playing: `{}` by `{}`", t, a),
-                    (Some(t), _, Some(a)) => format!("Now playing: `{}` by `{}`", t, a),
-                    _ => "Now playing another song. (no metadata)".to_string()
-                },
-            };
-
-            match self
-                .chan_id
-                .say(&self.http, &message)
-                .await
-            {
-                Ok(e) => e,
-                Err(e) => {
-                    println!("Error sending message: {:?}", e);
-                    return None;
-                }
-            };
+#[check]
+#[name = "whitelisted_guilds"]
+async fn music_check(
+    ctx: &Context,
+    msg: &Message,
+    _: &mut Args,
+    _: &CommandOptions,
+) -> Result<(), Reason> {
+    let guild_id = match msg.guild_id {
+        Some(id) => id,
+        None => {
+            return Err(Reason::Log(
+                "Music commands only work in guilds".to_string(),
+            ))
         }
-        None
+    };
+
+    let data = ctx.data.read().await;
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    match is_music_allowed(pool, guild_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Reason::UserAndLog {
+            user: "Music isn't turned on for this server yet. You can ask for it in the support server: https://r.izu.moe/discord".to_string(),
+            log: "Guild is not in allowlist".to_string(),
+        }),
+        Err(why) => Err(Reason::Log(format!("Could not check the music allowlist: {:?}", why))),
     }
 }
 
-pub struct Receiver;
+/// Feeds what people say in the channel to the call's [`Recorder`] and [`VoiceStats`].
+pub struct Receiver {
+    recorder: Arc<Recorder>,
+    stats: Arc<VoiceStats>,
+}
 
 impl Receiver {
-    pub fn new() -> Self {
-        // You can manage state here, such as a buffer of audio packet bytes so
-        // you can later store them in intervals.
-        Self {}
+    pub fn new(recorder: Arc<Recorder>, stats: Arc<VoiceStats>) -> Self {
+        Self { recorder, stats }
     }
 }
 
 #[async_trait]
 impl VoiceEventHandler for Receiver {
-    #[allow(unused_variables)]
     async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
         use EventContext as Ctx;
 
         match ctx {
             Ctx::SpeakingStateUpdate(Speaking {
-                speaking,
                 ssrc,
-                user_id,
+                user_id: Some(user_id),
                 ..
             }) => {
                 // Discord voice calls use RTP, where every sender uses a randomly allocated
@@ -100,76 +219,33 @@ impl VoiceEventHandler for Receiver {
                 // the sender's user_id, only Discord Voice Gateway messages like this one
                 // inform us about which random SSRC a user has been allocated. Future voice
                 // packets will contain *only* the SSRC.
-                //
-                // You can implement logic here so that you can differentiate users'
-                // SSRCs and map the SSRC to the User ID and maintain this state.
-                // Using this map, you can map the `ssrc` in `voice_packet`
-                // to the user ID and handle their audio packets separately.
-                println!(
-                    "Speaking state update: user {:?} has SSRC {:?}, using {:?}",
-                    user_id, ssrc, speaking,
-                );
+                self.recorder.map_ssrc(*ssrc, UserId(user_id.0));
             }
-            Ctx::SpeakingUpdate ( data) => {
-                // You can implement logic here which reacts to a user starting
-                // or stopping speaking.
-                println!(
-                    "{} has {} speaking.",
-                    data.ssrc,
-                    if data.speaking { "started" } else { "stopped" },
-                );
+            Ctx::SpeakingUpdate(data) => {
+                if let Some(user_id) = self.recorder.user(data.ssrc) {
+                    self.stats.speaking(user_id, data.speaking);
+                }
             }
-            Ctx::VoicePacket (data) => {
-                // An event which fires for every received audio packet,
-                // containing the decoded data.
+            Ctx::VoicePacket(data) => {
+                // this is only decoded while the recorder has decoding turned on
                 if let Some(audio) = data.audio {
-                    println!(
-                        "Audio packet's first 5 samples: {:?}",
-                        audio.get(..5.min(audio.len()))
-                    );
-                    println!(
-                        "Audio packet sequence {:05} has {:04} bytes (decompressed from {}), SSRC {}",
-                        data.packet.sequence.0,
-                        audio.len() * std::mem::size_of::<i16>(),
-                        data.packet.payload.len(),
-                        data.packet.ssrc,
-                    );
-                } else {
-                    println!("RTP packet, but no audio. Driver may not be configured to decode.");
+                    self.recorder.write(data.packet.ssrc, audio);
                 }
-            },
-            Ctx::RtcpPacket(data) => {
-                // An event which fires for every received rtcp packet,
-                // containing the call statistics and reporting information.
-                //println!("RTCP packet received: {:?}", data.packet);
-                ();
-            },
+            }
             Ctx::ClientConnect(ClientConnect {
                 audio_ssrc,
-                video_ssrc,
                 user_id,
                 ..
             }) => {
-                // You can implement your own logic here to handle a user who has joined the
-                // voice channel e.g., allocate structures, map their SSRC to User ID.
-
-                println!(
-                    "Client connected: user {:?} has audio SSRC {:?}, video SSRC {:?}",
-                    user_id, audio_ssrc, video_ssrc,
-                );
+                let user_id = UserId(user_id.0);
+                self.recorder.map_ssrc(*audio_ssrc, user_id);
+                self.stats.joined(user_id);
+                self.recorder.announce_join(user_id);
             }
             Ctx::ClientDisconnect(ClientDisconnect { user_id, .. }) => {
-                // You can implement your own logic here to handle a user who has left the
-                // voice channel e.g., finalise processing of statistics etc.
-                // You will typically need to map the User ID to their SSRC; observed when
-                // speaking or connecting.
-
-                println!("Client disconnected: user {:?}", user_id);
-            }
-            _ => {
-                // We won't be registering this struct for any more event classes.
-                unimplemented!()
+                self.stats.left(UserId(user_id.0));
             }
+            _ => {}
         }
 
         None
@@ -2,120 +2,241 @@ use serenity::prelude::*;
 use serenity::{
     framework::standard::{macros::command, Args, CommandError, CommandResult},
     model::{
-        channel::Message,
-        id::{ChannelId, GuildId},
+        channel::{Message, ReactionType},
+        guild::Guild,
+        id::{ChannelId, GuildId, UserId},
     },
 };
 
-use songbird::{CoreEvent, Event, Songbird, TrackEvent};
-use std::{
-    collections::{hash_map::RandomState, HashMap},
-    ffi::OsStr,
-    sync::Arc,
-};
+use songbird::input::{Input, Restartable};
+use std::time::Duration;
 
 use crate::{
-    commands::voice::{Receiver, TrackEndNotifier, WHITELISTED_GUILDS_CHECK},
-    keys::VoiceQueue,
-    utils::queue::TrackQueue,
+    commands::voice::{
+        is_dj,
+        library::{local_source, search_library, LOCAL_PREFIX},
+        limits::{queue_limits, QueueLimits},
+        listeners,
+        listing::show_queue,
+        policy::link_source,
+        session::{guild_queue, sessions},
+        sources::{
+            audius_track, is_playlist_url, is_spotify_url, parse_m3u, parse_pls, playlist_entries,
+            search, spotify_entries, PlaylistEntry, SearchResult, SearchSite,
+        },
+        DJ_CHECK, WHITELISTED_GUILDS_CHECK,
+    },
+    keys::ConnectionPool,
+    utils::{
+        queue::{LoopMode, Request},
+        time::format_duration,
+    },
 };
 
-async fn get_source<P: AsRef<OsStr>>(
-    path: P,
-    path_string: &str,
-) -> Result<songbird::input::Input, String> {
-    dbg!(path_string.split("/").collect::<Vec<&str>>()[2]);
-    let source = match path_string.split("/").collect::<Vec<&str>>()[2] {
-        "audius.co" => {
-            let testpath_dash = path_string.clone().split("-").collect::<Vec<_>>();
-            let id = testpath_dash[testpath_dash.len() - 1];
-
-            let testpath_slash = path_string.clone().split("/").collect::<Vec<_>>();
-            let slug = testpath_slash[testpath_slash.len() - 1]
-                .replace(&("-".to_owned() + &id.to_string()), "");
-            let artist = testpath_slash[testpath_slash.len() - 2];
-
-            let url = format!(
-                "https://creatornode--linustek.repl.co/api/generate.m3u8?id={}&title={}&handle={}",
-                id, slug, artist
-            );
-            dbg!(&url);
-
-            match songbird::ffmpeg(url).await {
+use sqlx::PgPool;
+
+const DEFAULT_VOTE_SKIP_PERCENT: i32 = 50;
+pub const DEFAULT_PLAYLIST_LIMIT: i32 = 100;
+/// How many search results the picker shows.
+const SEARCH_RESULTS: usize = 5;
+/// How long the requester has to pick a search result.
+const PICK_TIMEOUT: Duration = Duration::from_secs(30);
+const NUMBER_EMOJI: [&str; SEARCH_RESULTS] = [
+    "1\u{fe0f}\u{20e3}",
+    "2\u{fe0f}\u{20e3}",
+    "3\u{fe0f}\u{20e3}",
+    "4\u{fe0f}\u{20e3}",
+    "5\u{fe0f}\u{20e3}",
+];
+const CANCEL_EMOJI: &str = "❌";
+
+/// Shows search results and waits for the requester to pick one, by number or reaction.
+///
+/// Returns `None` if they cancelled or didn't pick anything in time.
+async fn pick_search_result(
+    ctx: &Context,
+    msg: &Message,
+    query: &str,
+    results: &[SearchResult],
+) -> Result<Option<String>, CommandError> {
+    let mut description = String::new();
+    for (i, result) in results.iter().enumerate() {
+        description.push_str(&format!(
+            "`{}.` [{}]({}) by {} `{}`\n",
+            i + 1,
+            result.title,
+            result.url,
+            result.uploader.as_deref().unwrap_or("unknown"),
+            result.duration.map_or("?".to_string(), format_duration)
+        ));
+    }
+
+    let mut picker = msg
+        .channel_id
+        .send_message(&ctx.http, |m| {
+            m.embed(|e| {
+                e.title(format!("Results for `{}`", query))
+                    .color(0xb90000)
+                    .description(description)
+                    .footer(|f| {
+                        f.text(format!(
+                            "Reply with a number or react within {} seconds.",
+                            PICK_TIMEOUT.as_secs()
+                        ))
+                    })
+            })
+        })
+        .await?;
+
+    let reactions = NUMBER_EMOJI[..results.len()]
+        .iter()
+        .chain(std::iter::once(&CANCEL_EMOJI))
+        .map(|e| ReactionType::Unicode(e.to_string()))
+        .collect::<Vec<_>>();
+
+    // react in the background, so people can pick before all of them are added.
+    let react_ctx = ctx.clone();
+    let react_msg = picker.clone();
+    let reacting = tokio::spawn(async move {
+        for reaction in reactions {
+            if react_msg.react(&react_ctx, reaction).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let count = results.len();
+    let picked = tokio::select! {
+        reply = msg
+            .author
+            .await_reply(ctx)
+            .channel_id(msg.channel_id)
+            .timeout(PICK_TIMEOUT)
+            .filter(move |m| {
+                let content = m.content.trim();
+                content.eq_ignore_ascii_case("cancel")
+                    || content.parse::<usize>().map_or(false, |n| n >= 1 && n <= count)
+            }) => reply.and_then(|m| m.content.trim().parse::<usize>().ok()),
+        reaction = picker
+            .await_reaction(ctx)
+            .author_id(msg.author.id)
+            .timeout(PICK_TIMEOUT)
+            .filter(|r| match &r.emoji {
+                ReactionType::Unicode(e) => NUMBER_EMOJI.contains(&e.as_str()) || e.as_str() == CANCEL_EMOJI,
+                _ => false,
+            }) => {
+            reaction.and_then(|r| match &r.as_inner_ref().emoji {
+                ReactionType::Unicode(e) => NUMBER_EMOJI.iter().position(|n| *n == e.as_str()).map(|i| i + 1),
+                _ => None,
+            })
+        }
+    };
+
+    reacting.abort();
+    let _ = picker.delete_reactions(&ctx.http).await;
+
+    let result = match picked {
+        Some(n) => &results[n - 1],
+        None => {
+            let _ = picker
+                .edit(&ctx, |m| {
+                    m.embed(|e| e.color(0xb90000).description("Nothing was picked."))
+                })
+                .await;
+
+            return Ok(None);
+        }
+    };
+
+    let _ = picker
+        .edit(&ctx, |m| {
+            m.embed(|e| {
+                e.color(0xb90000)
+                    .description(format!("Picked [{}]({}).", result.title, result.url))
+            })
+        })
+        .await;
+
+    Ok(Some(result.url.clone()))
+}
+
+/// Resolves a URL into a playable source.
+///
+/// Sources are restartable, so they can be seeked, and always carry the URL they
+/// were created from so they can be recreated later.
+pub async fn get_source(guild_id: GuildId, path_string: &str) -> Result<Input, String> {
+    if let Some(relative) = path_string.strip_prefix(LOCAL_PREFIX) {
+        return local_source(guild_id, relative).await;
+    }
+
+    let mut source: Input = match path_string.split('/').nth(2).unwrap_or("") {
+        "audius.co" | "www.audius.co" => {
+            let track = audius_track(path_string).await?;
+
+            match Restartable::ffmpeg(track.stream_url.clone(), true).await {
                 Ok(source) => {
-                    //source.metadata.title = Some("hi".to_string());
+                    let mut source: Input = source.into();
+                    track.fill_metadata(&mut source.metadata);
                     source
                 }
                 Err(why) => {
-                    println!("Err starting source: {:?}", why);
+                    warn!(
+                        "Could not start Audius track {} in guild {}: {:?}",
+                        path_string, guild_id, why
+                    );
 
-                    return Err("fuck. a ffmpeg error.".to_string());
+                    return Err("I couldn't load that track from Audius.".to_string());
                 }
             }
         }
         "www.youtube.com" | "youtube.com" | "youtu.be" | "soundcloud.com" => {
-            match songbird::ytdl(&path_string).await {
+            match Restartable::ytdl(path_string.to_string(), true).await {
                 Ok(source) => {
                     info!("youtube-dl track added");
-                    source
+                    source.into()
                 }
                 Err(why) => {
-                    println!("Err starting source: {:?}", why);
+                    warn!(
+                        "Could not start {} with youtube-dl in guild {}: {:?}",
+                        path_string, guild_id, why
+                    );
 
-                    return Err("fuck. a youtube-dl error.".to_string());
+                    return Err("I couldn't load that. It might be private, removed or blocked in my region.".to_string());
                 }
             }
         }
-        _ => match songbird::ffmpeg(&path).await {
-            Ok(source) => {
-                //source.metadata.title = Some("hi".to_string());
-                source
-            }
-            Err(why) => {
-                println!("Err starting source: {:?}", why);
-
-                return Err("fuck. a ffmpeg error.".to_string());
-            }
-        },
+        // anything else goes straight to ffmpeg, once it's been checked that's somewhere we want it to go
+        _ => link_source(guild_id, path_string).await?,
     };
+    if source.metadata.source_url.is_none() {
+        source.metadata.source_url = Some(path_string.to_string());
+    }
     Ok(source)
 }
 
 #[command]
 #[only_in(guilds)]
-#[checks(whitelisted_guilds)]
+#[checks(whitelisted_guilds, dj)]
 async fn leave(ctx: &Context, msg: &Message) -> CommandResult {
-    // get our queue
-    let data = ctx.data.read().await;
-    let qu = data.get::<VoiceQueue>().unwrap();
-    let q = qu.write().await;
-
-    let guild = msg.guild(&ctx.cache).unwrap();
-    let guild_id = guild.id;
+    let guild_id = msg.guild_id.unwrap();
 
     let manager = songbird::get(ctx)
         .await
         .expect("Songbird Voice client placed in at initialisation.")
         .clone();
 
-    // stop playback if going on
-    if let Some(queue) = q.get(&guild_id) {
-        let _ = queue.stop();
-    }
+    if manager.get(guild_id).is_none() {
+        msg.reply(ctx, "Where do you want me to leave? You should be in a voice channel to execute this command.").await?;
 
-    let has_handler = manager.get(guild_id).is_some();
+        return Ok(());
+    }
 
-    if has_handler {
-        if let Err(e) = manager.remove(guild_id).await {
-            msg.channel_id
-                .say(&ctx.http, format!("Failed: {:?}", e))
-                .await?;
-        }
+    // stops playback and wraps up any recording along the way
+    sessions(ctx).await.end(&manager, guild_id).await;
 
-        msg.channel_id.say(&ctx.http, "👋 Bye! See you again soon!").await?;
-    } else {
-        msg.reply(ctx, "Where do you want me to leave? You should be in a voice channel to execute this command.").await?;
-    }
+    msg.channel_id
+        .say(&ctx.http, "👋 Bye! See you again soon!")
+        .await?;
 
     Ok(())
 }
@@ -123,331 +244,390 @@ async fn leave(ctx: &Context, msg: &Message) -> CommandResult {
 #[command]
 #[only_in(guilds)]
 #[checks(whitelisted_guilds)]
-async fn play(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-    let url = match args.single::<String>() {
-        Ok(url) => url,
-        Err(_) => {
-            msg.channel_id
-                .say(&ctx.http, "You need to provide a URL to a video or audio")
-                .await?;
-
-            return Ok(());
+#[usage("<url|search|local: search> [--first] [--sc]")]
+#[example("never gonna give you up --first")]
+#[description("Play a URL, or search YouTube (SoundCloud with `--sc`) and pick from the top results. `--first` skips the picker.\nPlaylist links and `.m3u`/`.pls` attachments add every track in them. `local:` plays from the bot's own music library.")]
+async fn play(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let mut first = false;
+    let mut site = SearchSite::YouTube;
+    let mut words = Vec::new();
+    for word in args.rest().split_whitespace() {
+        match word {
+            "--first" => first = true,
+            "--sc" | "--soundcloud" => site = SearchSite::SoundCloud,
+            "--yt" | "--youtube" => site = SearchSite::YouTube,
+            _ => words.push(word),
         }
-    };
-
-    if !url.starts_with("http") {
-        msg.channel_id
-            .say(&ctx.http, "You need to provide a valid URL")
-            .await?;
-
-        return Ok(());
     }
 
-    let guild = msg.guild(&ctx.cache).unwrap();
-    let guild_id = guild.id;
-
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
-
-    debug!("Got manager");
-
-    // get our queue
-    let data = ctx.data.read().await;
-    let qu = &mut data.get::<VoiceQueue>().unwrap();
-    let mut q = qu.write().await;
-
-    debug!("Got queue");
-
-    if let Some(handler_lock) = manager.get(guild_id) {
-        if let Some(queue) = q.get(&guild_id) {
-            let mut handler = handler_lock.lock().await;
-
-            debug!("Got audio handler");
+    let playlist_file = msg.attachments.iter().find(|a| {
+        let name = a.filename.to_lowercase();
+        name.ends_with(".m3u") || name.ends_with(".m3u8") || name.ends_with(".pls")
+    });
 
-            let source = get_source(&url, &url).await?;
-            let metadata = source.metadata.clone();
-
-            //handler.enqueue_source(source);
-            queue.add_source(source, &mut handler);
+    let url = match words.as_slice() {
+        [] if playlist_file.is_some() => String::new(),
+        [] => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "You need to give me a URL or something to search for.",
+                )
+                .await?;
 
-            match queue.len() {
-                1 => {
-                    let none_value = "Unknown".to_string();
-                    let title = match metadata.title {
-                        Some(t) => t,
-                        None => match metadata.track {
-                            Some(t) => t,
-                            None => none_value,
-                        },
-                    };
-                    msg.channel_id
-                        .say(
-                            &ctx.http,
-                            format!(
-                                "Now playing: `{}` by `{}`",
-                                title,
-                                metadata.artist.unwrap_or("unknown".to_string())
-                            ),
-                        )
-                        .await?;
-                }
-                _ => {
-                    let none_value = "song".to_string();
-                    let title = match metadata.title {
-                        Some(t) => t,
-                        None => match metadata.track {
-                            Some(t) => t,
-                            None => none_value,
-                        },
-                    };
-                    let vari = match queue.len() - 1 {
-                        1 => "It's up next.".to_string(),
-                        2 => "It'll play after the next track.".to_string(),
-                        _ => format!("It'll play after the next {} tracks.",  queue.len() - 2),
-                    };
+            return Ok(());
+        }
+        [url, ..] if url.starts_with("http") => url.to_string(),
+        [first, ..] if first.starts_with(LOCAL_PREFIX) => {
+            let query = words.join(" ");
+            let query = query[LOCAL_PREFIX.len()..].trim();
+
+            let found = {
+                let data = ctx.data.read().await;
+                let pool = data.get::<ConnectionPool>().unwrap();
+                search_library(pool, query).await?
+            };
+
+            match found {
+                Some(track) => format!("{}{}", LOCAL_PREFIX, track.path),
+                None => {
                     msg.channel_id
                         .say(
                             &ctx.http,
-                            format!(
-                                "Added `{}` to queue. {}",
-                                title,
-                                vari
-                            ),
+                            format!("I couldn't find `{}` in the music library.", query),
                         )
                         .await?;
+
+                    return Ok(());
                 }
             }
         }
-    } else {
-        let channel_id = guild
-            .voice_states
-            .get(&msg.author.id)
-            .and_then(|voice_state| voice_state.channel_id);
-
-        let connect_to = match channel_id {
-            Some(channel) => channel,
-            None => {
-                msg.reply(ctx, "Where do you want me to join? You need to be in a voice channel for this.").await?;
+        _ => {
+            let query = words.join(" ");
+            let results = search(&query, site, if first { 1 } else { SEARCH_RESULTS }).await?;
+
+            if results.is_empty() {
+                msg.channel_id
+                    .say(
+                        &ctx.http,
+                        format!("I couldn't find anything for `{}`.", query),
+                    )
+                    .await?;
 
                 return Ok(());
             }
-        };
 
-        connect_and_register(
-            manager.clone(),
-            ctx,
-            msg,
-            guild_id,
-            connect_to,
-            msg.channel_id,
-            qu,
-        )
-        .await?;
-
-        q.insert(guild_id, TrackQueue::new());
-
-        if let Some(handler_lock) = manager.get(guild_id) {
-            if let Some(queue) = q.get(&guild_id) {
-                let mut handler = handler_lock.lock().await;
-
-                let source = get_source(&url, &url).await?;
-                let metadata = source.metadata.clone();
-
-                //handler.enqueue_source(source);
-                queue.add_source(source, &mut handler);
-
-                dbg!(queue);
-
-                let none_value = "Unknown".to_string();
-                let title = match metadata.title {
-                    Some(t) => t,
-                    None => match metadata.track {
-                        Some(t) => t,
-                        None => none_value,
-                    },
-                };
-
-                match queue.len() {
-                    1 => {
-                        msg.channel_id
-                            .say(
-                                &ctx.http,
-                                format!(
-                                    "Now playing: `{}` by `{}`",
-                                    title,
-                                    metadata.artist.unwrap_or("unknown".to_string())
-                                ),
-                            )
-                            .await?;
-                    }
-                    _ => {
-                        msg.channel_id
-                            .say(
-                                &ctx.http,
-                                format!(
-                                    "Added `{}` to queue at position {}",
-                                    title,
-                                    queue.len() - 1
-                                ),
-                            )
-                            .await?;
-                    }
-                }
+            if first {
+                results[0].url.clone()
             } else {
-                println!("no guild id found in queue")
+                match pick_search_result(ctx, msg, &query, &results).await? {
+                    Some(url) => url,
+                    None => return Ok(()),
+                }
             }
-        } else {
-            println!("no guild id found in handler")
         }
+    };
+
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let guild_id = guild.id;
+
+    let limit = {
+        let data = ctx.data.read().await;
+        let pool = data.get::<ConnectionPool>().unwrap();
+        playlist_limit(pool, guild_id).await? as usize
+    };
+
+    let (mut entries, total) = match playlist_file {
+        Some(file) if url.is_empty() => {
+            let content = String::from_utf8_lossy(&file.download().await?).into_owned();
+            let entries = if file.filename.to_lowercase().ends_with(".pls") {
+                parse_pls(&content)
+            } else {
+                parse_m3u(&content)
+            };
+            let total = entries.len();
+            (entries, total)
+        }
+        _ if is_spotify_url(&url) => spotify_entries(&url, limit).await?,
+        _ if is_playlist_url(&url) => {
+            let entries = playlist_entries(&url).await?;
+            let total = entries.len();
+            (entries, total)
+        }
+        _ => (
+            vec![PlaylistEntry {
+                url,
+                ..Default::default()
+            }],
+            1,
+        ),
+    };
+
+    if entries.is_empty() {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                "I couldn't find any tracks I can play in that playlist.",
+            )
+            .await?;
+
+        return Ok(());
     }
 
-    Ok(())
-}
+    let left_out = total.saturating_sub(limit);
+    entries.truncate(limit);
 
-async fn connect_and_register(
-    manager: std::sync::Arc<Songbird>,
-    ctx: &Context,
-    msg: &Message,
-    guild_id: GuildId,
-    voice: ChannelId,
-    text: ChannelId,
-    queue: &mut &Arc<RwLock<HashMap<GuildId, TrackQueue, RandomState>>>,
-) -> Result<(), CommandError> {
-    let (handler_lock, conn_result) = manager.join(guild_id, voice).await;
+    let session = match sessions(ctx).await.get(guild_id) {
+        Some(session) => session,
+        None => {
+            let connect_to = match author_voice_channel(&guild, msg.author.id) {
+                Some(channel) => channel,
+                None => {
+                    msg.reply(
+                        ctx,
+                        "Where do you want me to join? You need to be in a voice channel for this.",
+                    )
+                    .await?;
 
-    if let Ok(_) = conn_result {
-        // NOTE: this skips listening for the actual connection result.
-        let mut handler = handler_lock.lock().await;
+                    return Ok(());
+                }
+            };
 
-        let send_http = ctx.http.clone();
+            match sessions(ctx)
+                .await
+                .start(ctx, guild_id, connect_to, msg.channel_id)
+                .await?
+            {
+                Some(session) => session,
+                None => return Ok(()),
+            }
+        }
+    };
 
-        handler.add_global_event(
-            Event::Track(TrackEvent::End),
-            TrackEndNotifier {
-                guild_id,
-                chan_id: text,
-                http: send_http,
-                //manager,
-                queue: queue.to_owned(),
-            },
-        );
+    let queue = &session.queue;
 
-        handler.add_global_event(CoreEvent::SpeakingStateUpdate.into(), Receiver::new());
+    let limits = if is_dj(ctx, &guild, msg.author.id).await {
+        QueueLimits::default()
+    } else {
+        let data = ctx.data.read().await;
+        let pool = data.get::<ConnectionPool>().unwrap();
+        queue_limits(pool, guild_id).await?
+    };
 
-        handler.add_global_event(CoreEvent::SpeakingUpdate.into(), Receiver::new());
+    let mut capped = 0;
+    if let Some(room) = limits.room(queue.pending_for(msg.author.id)) {
+        if room == 0 {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "You already have {} tracks waiting, which is as many as anyone can have here. Give the others a turn!",
+                        limits.max_pending.unwrap_or_default()
+                    ),
+                )
+                .await?;
 
-        handler.add_global_event(CoreEvent::VoicePacket.into(), Receiver::new());
+            return Ok(());
+        }
 
-        handler.add_global_event(CoreEvent::RtcpPacket.into(), Receiver::new());
+        capped = entries.len().saturating_sub(room);
+        entries.truncate(room);
+    }
 
-        handler.add_global_event(CoreEvent::ClientConnect.into(), Receiver::new());
+    // the first track is resolved now, the rest get queued in the background.
+    let first = entries.remove(0);
 
-        handler.add_global_event(CoreEvent::ClientDisconnect.into(), Receiver::new());
+    // resolve before taking the call, so a slow lookup doesn't hold up playback
+    let source = resolve_entry(guild_id, &first)
+        .await
+        .map_err(|why| CommandError::from(format!("h-{}", why)))?;
+    let metadata = source.metadata.clone();
+    limits
+        .check_length(metadata.duration)
+        .map_err(|why| CommandError::from(format!("h-{}", why)))?;
+
+    let request = Request::new(msg.author.id, msg.channel_id);
+    let position = {
+        let mut handler = session.call.lock().await;
+        queue.add_source(source, request, &mut handler)
+    };
+
+    let title = metadata.title.or(metadata.track);
+    match position {
+        0 => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "Now playing: `{}` by `{}`",
+                        title.unwrap_or_else(|| "Unknown".to_string()),
+                        metadata.artist.unwrap_or_else(|| "unknown".to_string())
+                    ),
+                )
+                .await?;
+        }
+        position => {
+            let vari = match position {
+                1 => "It's up next.".to_string(),
+                2 => "It'll play after the next track.".to_string(),
+                _ => format!("It'll play after the next {} tracks.", position - 1),
+            };
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "Added `{}` to queue. {}",
+                        title.unwrap_or_else(|| "song".to_string()),
+                        vari
+                    ),
+                )
+                .await?;
+        }
+    }
 
+    if !entries.is_empty() {
+        let mut message = format!("Adding {} more tracks from the playlist.", entries.len());
+        if left_out > 0 {
+            message.push_str(&format!(
+                " {} were left out, since one import can only add {} tracks here.",
+                left_out, limit
+            ));
+        }
+        if capped > 0 {
+            message.push_str(&format!(
+                " {} more were left out, since you can only have {} tracks waiting here.",
+                capped,
+                limits.max_pending.unwrap_or_default()
+            ));
+        }
+        msg.channel_id.say(&ctx.http, message).await?;
+
+        tokio::spawn(enqueue_lazily(
+            ctx.clone(),
+            guild_id,
+            request,
+            limits,
+            entries,
+        ));
+    } else if capped > 0 {
         msg.channel_id
             .say(
                 &ctx.http,
-                &format!(
-                    "Joined 🔊 {}, bound to #️⃣ {}",
-                    voice.mention(),
-                    text.mention()
+                format!(
+                    "The rest of the playlist was left out, since you can only have {} tracks waiting here.",
+                    limits.max_pending.unwrap_or_default()
                 ),
             )
             .await?;
-    } else {
-        msg.channel_id
-            .say(&ctx.http, "I couldn't join the channel. Make sure (or get someone to make sure) that I have permissions to join and speak.")
-            .await?;
-    };
+    }
+
     Ok(())
 }
 
-#[command]
-#[only_in(guilds)]
-#[checks(whitelisted_guilds)]
-async fn queue(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-    match args.is_empty() {
-        false => play(ctx, msg, args).await?,
-        true => {
-            let manager = songbird::get(ctx)
-                .await
-                .expect("Songbird Voice client placed in at initialisation.")
-                .clone();
+/// Resolves a playlist entry into a playable source.
+///
+/// Spotify entries are matched to the top YouTube result for their artist and title,
+/// and keep their Spotify metadata for display.
+async fn resolve_entry(guild_id: GuildId, entry: &PlaylistEntry) -> Result<Input, String> {
+    if !is_spotify_url(&entry.url) {
+        return get_source(guild_id, &entry.url).await;
+    }
 
-            // get our queue
-            let data = ctx.data.read().await;
-            let qu = &mut data.get::<VoiceQueue>().unwrap();
-            let q = qu.write().await;
+    let query = match (&entry.artist, &entry.title) {
+        (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+        (None, Some(title)) => title.clone(),
+        _ => return Err("That Spotify track doesn't have a title to look for.".to_string()),
+    };
 
-            let guild = msg.guild(&ctx.cache).unwrap();
-            let guild_id = guild.id;
+    let found = search(&query, SearchSite::YouTube, 1)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("I couldn't find `{}` on YouTube.", query))?;
 
-            if let Some(queue) = q.get(&guild_id) {
-                let q = queue.current_queue();
-                dbg!(&q);
+    let mut source = get_source(guild_id, &found.url).await?;
+    let meta = &mut source.metadata;
+    meta.title = entry.title.clone().or_else(|| meta.title.take());
+    meta.artist = entry.artist.clone().or_else(|| meta.artist.take());
+    meta.thumbnail = entry.thumbnail.clone().or_else(|| meta.thumbnail.take());
 
-                let mut to_send: String = "".to_string();
-                if q.len() < 1 {
-                    msg.channel_id.say(&ctx.http, "There isn't anything in the queue.").await?;
-                    return Ok(());
-                }
-                for (i, track) in q.iter().enumerate() {
-                    let info = track.metadata();
-                    let none_value = "?";
-                    let title = match &info.title {
-                        Some(t) => t,
-                        None => match &info.track {
-                            Some(t) => t,
-                            None => none_value,
-                        },
-                    };
-                    let artist = match &info.artist {
-                        Some(a) => a,
-                        None => none_value,
-                    };
-                    let time = match &info.duration {
-                        Some(d) => {
-                            let mut time_str: String = "".to_string();
-                            let mut time = d.as_secs();
-                            if d.as_secs() > 3600 {
-                                time_str.push_str(&format!("{}:", time / 3600));
-                                time = time % 3600;
-                            }
-                            if d.as_secs() > 60 {
-                                time_str.push_str(&format!("{}:", time / 60));
-                                time = time % 60;
-                                dbg!(time);
-                            }
-                            time_str.push_str(&format!("{:02}", time));
-                            time_str
-                        }
-                        None => "?".to_string(),
-                    };
-
-                    let url = match &info.source_url {
-                        Some(u) => {
-                            let to_split = u.split("#?").collect::<Vec<_>>();
-                            to_split[to_split.len() - 1].to_owned() + "\n"
-                        }
-                        None => "".to_string(),
-                    };
-                    let index = match i {
-                        0 => "Now Playing".to_string(),
-                        _ => i.to_string()
-                    };
-
-
-                    let to_push = format!("{}: {} - {} [{}]\n{}", index, title, artist, time, url);
-
-                    to_send.push_str(&to_push);
-                }
-                msg.channel_id.say(&ctx.http, to_send).await?;
+    Ok(source)
+}
+
+/// Resolves and queues playlist entries one by one, so a big playlist doesn't hold
+/// up the command or the queue.
+async fn enqueue_lazily(
+    ctx: Context,
+    guild_id: GuildId,
+    request: Request,
+    limits: QueueLimits,
+    entries: Vec<PlaylistEntry>,
+) {
+    let sessions = sessions(&ctx).await;
+
+    for entry in entries {
+        let source = match resolve_entry(guild_id, &entry).await {
+            Ok(source) => source,
+            Err(why) => {
+                warn!(
+                    "Could not load playlist entry {:?} ({}): {}",
+                    entry.title, entry.url, why
+                );
+                continue;
             }
+        };
+        if let Err(why) = limits.check_length(source.metadata.duration) {
+            debug!("Left out playlist entry {}: {}", entry.url, why);
+            continue;
+        }
 
-            return Ok(());
+        // stop once the bot has left
+        let session = match sessions.get(guild_id) {
+            Some(session) => session,
+            None => return,
+        };
+        // or once they've filled up their share of the queue some other way
+        if limits.room(session.queue.pending_for(request.user)) == Some(0) {
+            return;
         }
+
+        let mut handler = session.call.lock().await;
+        session.queue.add_source(source, request, &mut handler);
+    }
+}
+
+/// How many tracks a single playlist import can add.
+pub async fn playlist_limit(pool: &PgPool, guild_id: GuildId) -> Result<i32, sqlx::Error> {
+    let guild = sqlx::query!(
+        "
+    select playlist_limit
+    from guilds
+    where id = $1
+    ",
+        guild_id.0 as i64
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(guild
+        .and_then(|g| g.playlist_limit)
+        .unwrap_or(DEFAULT_PLAYLIST_LIMIT))
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds)]
+async fn queue(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    match args.is_empty() {
+        false => play(ctx, msg, args).await?,
+        true => match guild_queue(ctx, msg.guild_id.unwrap()).await {
+            Some(queue) => show_queue(ctx, msg.channel_id, &queue).await?,
+            None => {
+                msg.channel_id
+                    .say(&ctx.http, "There isn't anything in the queue.")
+                    .await?;
+            }
+        },
     };
 
     Ok(())
@@ -455,16 +635,24 @@ async fn queue(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
 
 #[command]
 #[only_in(guilds)]
+#[checks(whitelisted_guilds)]
 async fn join(ctx: &Context, msg: &Message) -> CommandResult {
     let guild = msg.guild(&ctx.cache).unwrap();
     let guild_id = guild.id;
 
-    let channel_id = guild
-        .voice_states
-        .get(&msg.author.id)
-        .and_then(|voice_state| voice_state.channel_id);
+    let sessions = sessions(ctx).await;
+    if let Some(session) = sessions.get(guild_id) {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!("I'm already in 🔊 {}.", session.voice_channel.mention()),
+            )
+            .await?;
 
-    let connect_to = match channel_id {
+        return Ok(());
+    }
+
+    let connect_to = match author_voice_channel(&guild, msg.author.id) {
         Some(channel) => channel,
         None => {
             msg.reply(ctx, "You need to be in a voice channel.").await?;
@@ -473,42 +661,184 @@ async fn join(ctx: &Context, msg: &Message) -> CommandResult {
         }
     };
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
-
-    let _handler = manager.join(guild_id, connect_to).await;
+    sessions
+        .start(ctx, guild_id, connect_to, msg.channel_id)
+        .await?;
 
     Ok(())
 }
 
+/// The voice channel someone is in, if they're in one.
+fn author_voice_channel(guild: &Guild, user_id: UserId) -> Option<ChannelId> {
+    guild
+        .voice_states
+        .get(&user_id)
+        .and_then(|voice_state| voice_state.channel_id)
+}
+
 #[command]
 #[only_in(guilds)]
 #[checks(whitelisted_guilds)]
+#[description(
+    "Skip the current track. Unless you added it, this adds your vote to skip it instead."
+)]
 async fn skip(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
-    // get our queue
-    let data = ctx.data.read().await;
-    let qu = &mut data.get::<VoiceQueue>().unwrap();
-    let q = qu.write().await;
-
     let guild = msg.guild(&ctx.cache).unwrap();
     let guild_id = guild.id;
 
-    if let Some(queue) = q.get(&guild_id) {
+    let queue = match guild_queue(ctx, guild_id).await {
+        Some(queue) if !queue.is_empty() => queue,
+        _ => {
+            msg.channel_id
+                .say(&ctx.http, "There isn't anything playing right now.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    if queue.current_requester() == Some(msg.author.id) || is_dj(ctx, &guild, msg.author.id).await {
         let _ = queue.skip();
 
         msg.channel_id
             .say(
                 &ctx.http,
-                format!("1 song skipped in queue, {} left.", queue.len() - 1),
+                format!(
+                    "1 song skipped in queue, {} left.",
+                    queue.len().saturating_sub(1)
+                ),
+            )
+            .await?;
+
+        return Ok(());
+    }
+
+    let listening = match listeners(ctx, &guild).await {
+        Some((_, users)) => users,
+        None => Vec::new(),
+    };
+
+    if !listening.contains(&msg.author.id) {
+        msg.channel_id
+            .say(&ctx.http, "You need to be listening to vote to skip.")
+            .await?;
+
+        return Ok(());
+    }
+
+    // people who voted and then left the channel don't count anymore
+    let votes = queue
+        .vote_skip(msg.author.id)
+        .iter()
+        .filter(|user| listening.contains(user))
+        .count();
+    let percent = {
+        let data = ctx.data.read().await;
+        let pool = data.get::<ConnectionPool>().unwrap();
+        vote_skip_percent(pool, guild_id).await? as usize
+    };
+    let needed = ((listening.len() * percent + 99) / 100).max(1);
+
+    if votes >= needed {
+        let _ = queue.skip();
+
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!(
+                    "Vote passed, skipping. {} left in queue.",
+                    queue.len().saturating_sub(1)
+                ),
             )
             .await?;
     } else {
         msg.channel_id
-            .say(&ctx.http, "You need to be in a voice channel for this.")
+            .say(
+                &ctx.http,
+                format!("Voted to skip: {}/{} votes.", votes, needed),
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// The share of listeners, in percent, who need to vote before a track is skipped.
+async fn vote_skip_percent(pool: &PgPool, guild_id: GuildId) -> Result<i32, sqlx::Error> {
+    let guild = sqlx::query!(
+        "
+    select vote_skip
+    from guilds
+    where id = $1
+    ",
+        guild_id.0 as i64
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(guild
+        .and_then(|g| g.vote_skip)
+        .unwrap_or(DEFAULT_VOTE_SKIP_PERCENT))
+}
+
+#[command("loop")]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds, dj)]
+#[usage("<track|queue|off>")]
+#[description("Repeat the current track, loop the whole queue, or turn looping off.")]
+async fn loop_mode(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let queue = match guild_queue(ctx, msg.guild_id.unwrap()).await {
+        Some(queue) => queue,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "There isn't anything playing right now.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    if args.is_empty() {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!("Looping is currently set to `{}`.", queue.loop_mode()),
+            )
             .await?;
+
+        return Ok(());
     }
 
+    let mode = match args.single::<String>()?.to_lowercase().as_str() {
+        "track" | "song" | "one" => LoopMode::Track,
+        "queue" | "all" => LoopMode::Queue,
+        "off" | "none" => LoopMode::Off,
+        _ => {
+            msg.channel_id
+                .say(&ctx.http, "You need to pick `track`, `queue` or `off`.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    if queue.set_loop_mode(mode).is_err() {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                "The current track can't be looped, but the setting will apply from the next one.",
+            )
+            .await?;
+
+        return Ok(());
+    }
+
+    let message = match mode {
+        LoopMode::Track => "🔂 Repeating the current track.",
+        LoopMode::Queue => "🔁 Looping the queue.",
+        LoopMode::Off => "Looping is off.",
+    };
+    msg.channel_id.say(&ctx.http, message).await?;
+
     Ok(())
 }
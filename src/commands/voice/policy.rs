@@ -0,0 +1,620 @@
+use reqwest::{
+    header::{CONTENT_TYPE, LOCATION},
+    redirect::Policy,
+    Client, Response, Url,
+};
+use serde_json::Value;
+use serenity::{async_trait, model::id::GuildId};
+use songbird::input::{
+    children_to_reader, error::Result as InputResult, restartable::Restart, Codec, Container,
+    Input, Metadata, Restartable,
+};
+use std::{
+    env,
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    process::{Command as StdCommand, Stdio},
+    thread,
+    time::Duration,
+};
+use tokio::{io::AsyncWriteExt, net::lookup_host, process::Command, runtime::Handle};
+
+/// How many redirects a link can go through before it's given up on.
+const MAX_REDIRECTS: usize = 5;
+/// Put in front of every link ffmpeg plays. Links are fetched by the bot and piped in, so ffmpeg
+/// has no business opening anything else, and playlist formats like HLS and concat, which would
+/// have it open whatever they point at, aren't on the list.
+pub const LINK_INPUT_ARGS: [&str; 4] = [
+    "-protocol_whitelist",
+    "pipe",
+    "-format_whitelist",
+    "mp3,ogg,flac,wav,aac,matroska,webm,mov,mp4,m4a",
+];
+
+/// Reads a comma separated list of domains from the environment.
+fn domain_list(var: &str) -> Vec<String> {
+    env::var(var)
+        .unwrap_or_default()
+        .split(',')
+        .map(|d| d.trim().trim_start_matches('.').to_lowercase())
+        .filter(|d| !d.is_empty())
+        .collect()
+}
+
+/// Whether a host is a domain on the list, or a subdomain of one.
+fn matches_domain(host: &str, domains: &[String]) -> bool {
+    domains
+        .iter()
+        .any(|d| host == d || host.ends_with(&format!(".{}", d)))
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // 0.0.0.0/8
+        || a == 0
+        // carrier-grade NAT, 100.64.0.0/10
+        || (a == 100 && (64..128).contains(&b))
+        // benchmarking, 198.18.0.0/15
+        || (a == 198 && (b == 18 || b == 19))
+        // reserved, 240.0.0.0/4
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+
+    // the ranges with an IPv4 address inside them are refused outright, since whatever reaches
+    // the IPv4 address can be somewhere private
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // IPv4-compatible, ::/96, and IPv4-mapped, ::ffff:0:0/96
+        || (segments[..5] == [0; 5] && (segments[5] == 0 || segments[5] == 0xffff))
+        // IPv4-translated, ::ffff:0:0:0/96
+        || (segments[..4] == [0; 4] && segments[4] == 0xffff && segments[5] == 0)
+        // NAT64, 64:ff9b::/96 and 64:ff9b:1::/48
+        || (segments[0] == 0x64 && segments[1] == 0xff9b && segments[2..6] == [0; 4])
+        || (segments[0] == 0x64 && segments[1] == 0xff9b && segments[2] == 1)
+        // 6to4, 2002::/16
+        || segments[0] == 0x2002
+        // unique local, fc00::/7
+        || (segments[0] & 0xfe00) == 0xfc00
+        // link-local, fe80::/10
+        || (segments[0] & 0xffc0) == 0xfe80
+        // documentation, 2001:db8::/32
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8))
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+/// Checks a URL before it's handed to ffmpeg.
+///
+/// Only http and https are allowed, and the host has to resolve to public addresses only.
+/// `SOURCE_ALLOWED_DOMAINS` limits links to those domains when set, and
+/// `SOURCE_BLOCKED_DOMAINS` refuses domains. Both are comma separated, and cover subdomains.
+pub async fn check_url(url: &str) -> Result<(), String> {
+    check_url_against(url, is_public).await.map(|_| ())
+}
+
+/// Checks a URL like [`check_url`], with `allowed` deciding which addresses are fine, and
+/// returns it along with the address it was checked against.
+async fn check_url_against(
+    url: &str,
+    allowed: fn(IpAddr) -> bool,
+) -> Result<(Url, SocketAddr), String> {
+    let parsed = Url::parse(url).map_err(|_| "That doesn't look like a link.".to_string())?;
+
+    match parsed.scheme() {
+        "http" | "https" => {}
+        scheme => {
+            return Err(format!(
+                "I can only play http and https links, not `{}`.",
+                scheme
+            ))
+        }
+    }
+
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| "That link doesn't have a website in it.".to_string())?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .trim_end_matches('.')
+        .to_lowercase();
+
+    let allowed_domains = domain_list("SOURCE_ALLOWED_DOMAINS");
+    if !allowed_domains.is_empty() && !matches_domain(&host, &allowed_domains) {
+        return Err(format!("Links from `{}` aren't allowed here.", host));
+    }
+    if matches_domain(&host, &domain_list("SOURCE_BLOCKED_DOMAINS")) {
+        return Err(format!("Links from `{}` are blocked here.", host));
+    }
+
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    let addresses = lookup_host((host.as_str(), port))
+        .await
+        .map_err(|_| format!("I couldn't find `{}`. Is the link right?", host))?
+        .collect::<Vec<_>>();
+
+    if addresses.iter().any(|addr| !allowed(addr.ip())) {
+        warn!("Refused link to {} ({:?})", host, addresses);
+        return Err("I can't play links to private or local addresses.".to_string());
+    }
+
+    match addresses.first() {
+        Some(address) => Ok((parsed, *address)),
+        None => Err(format!("I couldn't find `{}`. Is the link right?", host)),
+    }
+}
+
+/// A link that's been through [`vet_link`].
+///
+/// Everything after the check goes to the address it was checked against, so the host can't
+/// be looked up again and come back as something else.
+#[derive(Clone, Debug)]
+pub struct VettedLink {
+    pub url: Url,
+    address: SocketAddr,
+}
+
+impl VettedLink {
+    /// Requests the link from the address it was checked against, without following redirects.
+    async fn fetch(&self) -> Result<Response, String> {
+        let mut client = Client::builder().redirect(Policy::none());
+        if let Some(domain) = self.url.domain() {
+            client = client.resolve(domain, self.address);
+        }
+
+        client
+            .build()
+            .map_err(|why| format!("{:?}", why))?
+            .get(self.url.clone())
+            .send()
+            .await
+            .map_err(|_| "I couldn't reach that link.".to_string())
+    }
+}
+
+/// Whether a response is a playlist which ffmpeg would go and fetch the parts of.
+async fn is_manifest(response: &mut Response) -> bool {
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .unwrap_or_default()
+        .to_lowercase();
+    if content_type.contains("mpegurl") {
+        return true;
+    }
+
+    match response.chunk().await {
+        Ok(Some(start)) => start.starts_with(b"#EXTM3U") || start.starts_with(b"ffconcat"),
+        _ => false,
+    }
+}
+
+/// Follows a link's redirects, checking each one with [`check_url`], and returns where it ends up.
+///
+/// ffmpeg is never given the link itself. [`link_ffmpeg`] fetches the returned link and pipes it
+/// in, so nothing ffmpeg is sent to or redirected to goes unchecked.
+pub async fn vet_link(url: &str) -> Result<VettedLink, String> {
+    vet_link_against(url, is_public).await
+}
+
+async fn vet_link_against(url: &str, allowed: fn(IpAddr) -> bool) -> Result<VettedLink, String> {
+    let mut url = url.to_string();
+    for _ in 0..=MAX_REDIRECTS {
+        let (parsed, address) = check_url_against(&url, allowed).await?;
+        let link = VettedLink {
+            url: parsed,
+            address,
+        };
+
+        let mut response = link.fetch().await?;
+        if !response.status().is_redirection() {
+            if is_manifest(&mut response).await {
+                return Err("I can't play playlists or stream manifests from links.".to_string());
+            }
+
+            return Ok(link);
+        }
+
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .ok_or_else(|| "That link redirects to nowhere.".to_string())?;
+        url = link
+            .url
+            .join(location)
+            .map_err(|_| "That link redirects to nowhere.".to_string())?
+            .to_string();
+    }
+
+    Err("That link redirects too many times.".to_string())
+}
+
+/// Copies a link's body into a program's stdin until either runs out.
+async fn pipe_body(mut response: Response, stdin: &mut (impl AsyncWriteExt + Unpin)) {
+    while let Ok(Some(chunk)) = response.chunk().await {
+        if stdin.write_all(&chunk).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Starts ffmpeg on a vetted link, with `pre_input_args` going after [`LINK_INPUT_ARGS`] and
+/// `args` after the input.
+///
+/// The link is fetched here and piped into ffmpeg, which only ever reads from stdin.
+pub async fn link_ffmpeg(
+    link: &VettedLink,
+    pre_input_args: &[&str],
+    args: &[&str],
+) -> InputResult<Input> {
+    let mut response = link
+        .fetch()
+        .await
+        .map_err(|why| io::Error::new(io::ErrorKind::Other, why))?;
+
+    let mut ffmpeg = StdCommand::new("ffmpeg")
+        .args(&LINK_INPUT_ARGS)
+        .args(pre_input_args)
+        .args(&["-i", "pipe:0"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // songbird reads from a blocking child, so its stdin is fed from a thread of its own
+    if let Some(mut stdin) = ffmpeg.stdin.take() {
+        let handle = Handle::current();
+        thread::spawn(move || {
+            while let Ok(Some(chunk)) = handle.block_on(response.chunk()) {
+                if stdin.write_all(&chunk).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    Ok(Input::new(
+        true,
+        children_to_reader::<f32>(vec![ffmpeg]),
+        Codec::FloatPcm,
+        Container::Raw,
+        None,
+    ))
+}
+
+/// Plays a link through ffmpeg, fed by [`link_ffmpeg`].
+struct LinkSource {
+    link: VettedLink,
+}
+
+#[async_trait]
+impl Restart for LinkSource {
+    async fn call_restart(&mut self, time: Option<Duration>) -> InputResult<Input> {
+        let position = time.map(|t| format!("{:.3}", t.as_secs_f64()));
+        let mut pre_input_args = Vec::new();
+        if let Some(position) = &position {
+            pre_input_args.extend(&["-ss", position.as_str()]);
+        }
+
+        link_ffmpeg(
+            &self.link,
+            &pre_input_args,
+            &[
+                "-f",
+                "s16le",
+                "-ac",
+                "2",
+                "-ar",
+                "48000",
+                "-acodec",
+                "pcm_f32le",
+                "-",
+            ],
+        )
+        .await
+    }
+
+    async fn lazy_init(&mut self) -> InputResult<(Option<Metadata>, Codec, Container)> {
+        let response = self
+            .link
+            .fetch()
+            .await
+            .map_err(|why| io::Error::new(io::ErrorKind::Other, why))?;
+
+        let mut ffprobe = Command::new("ffprobe")
+            .args(&LINK_INPUT_ARGS)
+            .args(&[
+                "-v",
+                "quiet",
+                "-of",
+                "json",
+                "-show_format",
+                "-show_streams",
+                "-i",
+                "pipe:0",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        if let Some(mut stdin) = ffprobe.stdin.take() {
+            tokio::spawn(async move { pipe_body(response, &mut stdin).await });
+        }
+        let output = ffprobe.wait_with_output().await?;
+
+        let metadata = serde_json::from_slice::<Value>(&output.stdout)
+            .ok()
+            .map(|probed| Metadata::from_ffprobe_json(&probed));
+
+        Ok((metadata, Codec::FloatPcm, Container::Raw))
+    }
+}
+
+/// Vets a link with [`vet_link`] and makes a source which plays it through ffmpeg.
+pub async fn link_source(guild_id: GuildId, url: &str) -> Result<Input, String> {
+    let link = vet_link(url).await?;
+
+    match Restartable::new(LinkSource { link: link.clone() }, true).await {
+        Ok(source) => Ok(source.into()),
+        Err(why) => {
+            warn!(
+                "Could not start link {} in guild {}: {:?}",
+                link.url, guild_id, why
+            );
+
+            Err("I couldn't play that link. Is it a direct link to audio or video?".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::serve_in_turn;
+    use tokio::{net::TcpListener, time::timeout};
+
+    /// Lets tests serve links from 127.0.0.2 while the rest of loopback stays off limits.
+    fn only_test_server(ip: IpAddr) -> bool {
+        ip == IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))
+    }
+
+    /// A local server links can try to reach, and whether anything did within a second.
+    async fn inward_target() -> (String, tokio::task::JoinHandle<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        (
+            url,
+            tokio::spawn(async move {
+                timeout(Duration::from_secs(1), listener.accept())
+                    .await
+                    .is_ok()
+            }),
+        )
+    }
+
+    #[test]
+    fn private_addresses_are_not_public() {
+        for ip in &[
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "240.0.0.1",
+            "fc00::1",
+            "fd12:3456::1",
+            "::",
+        ] {
+            assert!(
+                !is_public(ip.parse().unwrap()),
+                "{} should not be public",
+                ip
+            );
+        }
+    }
+
+    #[test]
+    fn loopback_addresses_are_not_public() {
+        for ip in &["127.0.0.1", "127.255.255.254", "::1"] {
+            assert!(
+                !is_public(ip.parse().unwrap()),
+                "{} should not be public",
+                ip
+            );
+        }
+    }
+
+    #[test]
+    fn link_local_addresses_are_not_public() {
+        for ip in &["169.254.169.254", "169.254.0.1", "fe80::1", "febf::1"] {
+            assert!(
+                !is_public(ip.parse().unwrap()),
+                "{} should not be public",
+                ip
+            );
+        }
+    }
+
+    #[test]
+    fn addresses_with_ipv4_inside_are_not_public() {
+        for ip in &[
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "::ffff:169.254.169.254",
+            "::ffff:1.1.1.1",
+            "::127.0.0.1",
+            "::1.1.1.1",
+            "::ffff:0:7f00:1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::101:101",
+            "64:ff9b:1::a00:1",
+            "2002:7f00:1::1",
+            "2002:101:101::1",
+        ] {
+            assert!(
+                !is_public(ip.parse().unwrap()),
+                "{} should not be public",
+                ip
+            );
+        }
+    }
+
+    #[test]
+    fn public_addresses_are_public() {
+        for ip in &["1.1.1.1", "8.8.8.8", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{} should be public", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn check_url_refuses_local_links() {
+        for url in &[
+            "http://127.0.0.1/stream.mp3",
+            "http://localhost:8080/stream.mp3",
+            "https://10.0.0.1/stream.mp3",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://[::1]/stream.mp3",
+            "http://[::ffff:127.0.0.1]/stream.mp3",
+            "http://[fe80::1]/stream.mp3",
+        ] {
+            assert!(check_url(url).await.is_err(), "{} should be refused", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn check_url_refuses_other_schemes() {
+        for url in &[
+            "file:///etc/passwd",
+            "ftp://1.1.1.1/a.mp3",
+            "rtmp://1.1.1.1/live",
+            "not a link",
+        ] {
+            assert!(check_url(url).await.is_err(), "{} should be refused", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn check_url_allows_public_addresses() {
+        assert!(check_url("http://1.1.1.1/stream.mp3").await.is_ok());
+        assert!(check_url("https://[2606:4700:4700::1111]/stream.mp3")
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn domains_cover_subdomains() {
+        let domains = vec!["example.com".to_string()];
+        assert!(matches_domain("example.com", &domains));
+        assert!(matches_domain("cdn.example.com", &domains));
+        assert!(!matches_domain("badexample.com", &domains));
+        assert!(!matches_domain("example.com.evil.net", &domains));
+    }
+
+    #[tokio::test]
+    async fn vet_link_refuses_a_redirect_to_loopback() {
+        let (target, reached) = inward_target().await;
+        let (url, requests) = serve_in_turn(
+            "127.0.0.2:0",
+            vec![format!(
+                "HTTP/1.1 302 Found\r\nLocation: {}stream.mp3\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                target
+            )],
+        )
+        .await;
+
+        let vetted = vet_link_against(&format!("{}stream.mp3", url), only_test_server).await;
+
+        assert_eq!(
+            vetted.unwrap_err(),
+            "I can't play links to private or local addresses."
+        );
+        assert_eq!(requests.await.unwrap(), vec!["GET /stream.mp3 HTTP/1.1"]);
+        assert!(!reached.await.unwrap(), "the redirect was followed");
+    }
+
+    #[tokio::test]
+    async fn vet_link_refuses_hls_manifests_pointing_inward() {
+        let (target, reached) = inward_target().await;
+        let manifest = format!(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXTINF:10,\n{}segment.ts\n#EXT-X-ENDLIST\n",
+            target
+        );
+        let (url, _) = serve_in_turn(
+            "127.0.0.2:0",
+            vec![format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                manifest.len(),
+                manifest
+            )],
+        )
+        .await;
+
+        let vetted = vet_link_against(&format!("{}live.m3u8", url), only_test_server).await;
+
+        assert_eq!(
+            vetted.unwrap_err(),
+            "I can't play playlists or stream manifests from links."
+        );
+        assert!(!reached.await.unwrap(), "the manifest was followed");
+    }
+
+    #[test]
+    fn ffmpeg_can_only_read_the_pipe_as_plain_media() {
+        assert_eq!(&LINK_INPUT_ARGS[..2], &["-protocol_whitelist", "pipe"]);
+        assert_eq!(LINK_INPUT_ARGS[2], "-format_whitelist");
+
+        let formats = LINK_INPUT_ARGS[3].split(',').collect::<Vec<_>>();
+        for playlist in &["hls", "applehttp", "concat", "dash"] {
+            assert!(!formats.contains(playlist), "{} is allowed", playlist);
+        }
+    }
+
+    #[tokio::test]
+    async fn vetted_links_are_fetched_from_the_checked_address() {
+        let (url, requests) = serve_in_turn(
+            "127.0.0.2:0",
+            vec!["HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()],
+        )
+        .await;
+        let address = Url::parse(&url)
+            .unwrap()
+            .socket_addrs(|| None)
+            .unwrap()
+            .remove(0);
+
+        // a domain that can't resolve, so the request only arrives if the checked address is used
+        let link = VettedLink {
+            url: Url::parse(&format!(
+                "http://maki.invalid:{}/stream.mp3",
+                address.port()
+            ))
+            .unwrap(),
+            address,
+        };
+
+        assert!(link.fetch().await.is_ok());
+        assert_eq!(requests.await.unwrap(), vec!["GET /stream.mp3 HTTP/1.1"]);
+    }
+}
@@ -0,0 +1,556 @@
+use serenity::framework::standard::{macros::command, Args, CommandResult};
+use serenity::http::{AttachmentType, Http};
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use parking_lot::Mutex;
+use songbird::{driver::DecodeMode, Call};
+use std::{
+    collections::HashMap,
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+use tokio::{
+    process::Command,
+    sync::{mpsc, Mutex as AsyncMutex},
+    task::JoinHandle,
+};
+
+use crate::{
+    commands::voice::{listeners, session::sessions, DJ_CHECK, WHITELISTED_GUILDS_CHECK},
+    utils::time::format_duration,
+};
+
+/// Songbird decodes everything to 48kHz stereo.
+const SAMPLE_RATE: u32 = 48_000;
+const CHANNELS: u16 = 2;
+/// One 20ms packet, in samples per channel.
+const FRAME_SAMPLES: u64 = 960;
+/// Discord's upload limit, less some room for the message itself.
+const UPLOAD_LIMIT: u64 = 8 * 1024 * 1024 - 64 * 1024;
+/// How long a recording can go on for before it's stopped and posted. A wav file can only hold
+/// a little over 6 hours of this audio.
+const MAX_RECORDING_LENGTH: Duration = Duration::from_secs(4 * 60 * 60);
+/// The most samples per channel a wav file's header can count.
+const MAX_WAV_FRAMES: u64 = (u32::MAX as u64 - 36) / (CHANNELS as u64 * 2);
+
+/// A 16-bit PCM wav file which is written to as audio comes in.
+struct WavWriter {
+    file: BufWriter<File>,
+    /// How many samples per channel have been written.
+    frames: u64,
+}
+
+impl WavWriter {
+    fn create(path: &Path) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let block_align = CHANNELS * 2;
+        file.write_all(b"RIFF")?;
+        // sizes are filled in once the recording is done
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&CHANNELS.to_le_bytes())?;
+        file.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        file.write_all(&(SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self { file, frames: 0 })
+    }
+
+    /// Writes a packet, padding with silence first so it lines up with when it was spoken.
+    ///
+    /// Fails rather than write more than the header can count.
+    fn write_at(&mut self, position: u64, audio: &[i16]) -> io::Result<()> {
+        let end = position.max(self.frames) + audio.len() as u64 / CHANNELS as u64;
+        if end > MAX_WAV_FRAMES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the recording is too long for a wav file",
+            ));
+        }
+
+        if position > self.frames + FRAME_SAMPLES {
+            let silence = vec![0u8; ((position - self.frames) * CHANNELS as u64 * 2) as usize];
+            self.file.write_all(&silence)?;
+            self.frames = position;
+        }
+
+        for sample in audio {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.frames += audio.len() as u64 / CHANNELS as u64;
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        // `write_at` keeps this in range
+        let data_len = (self.frames * CHANNELS as u64 * 2) as u32;
+
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + data_len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&data_len.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+/// A decoded packet on its way to the writer, with where in the recording it goes.
+struct Packet {
+    user_id: UserId,
+    position: u64,
+    audio: Vec<i16>,
+}
+
+/// Writes packets to each speaker's file as they come in, off of the voice event handler.
+///
+/// Returns the files it wrote once the sender is dropped and everything is flushed.
+fn spawn_writer(
+    dir: PathBuf,
+    mut packets: mpsc::UnboundedReceiver<Packet>,
+) -> JoinHandle<Vec<(UserId, PathBuf)>> {
+    tokio::task::spawn_blocking(move || {
+        let mut speakers = HashMap::<UserId, WavWriter>::new();
+
+        while let Some(packet) = packets.blocking_recv() {
+            let user_id = packet.user_id;
+            if !speakers.contains_key(&user_id) {
+                match WavWriter::create(&dir.join(format!("{}.wav", user_id))) {
+                    Ok(writer) => {
+                        speakers.insert(user_id, writer);
+                    }
+                    Err(why) => {
+                        warn!("Could not create a recording for {}: {:?}", user_id, why);
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(writer) = speakers.get_mut(&user_id) {
+                if let Err(why) = writer.write_at(packet.position, &packet.audio) {
+                    warn!("Could not write a recording for {}: {:?}", user_id, why);
+                }
+            }
+        }
+
+        let mut written = Vec::new();
+        for (user_id, writer) in speakers {
+            match writer.finish() {
+                Ok(_) => written.push((user_id, dir.join(format!("{}.wav", user_id)))),
+                Err(why) => warn!("Could not finish the recording for {}: {:?}", user_id, why),
+            }
+        }
+
+        written
+    })
+}
+
+struct Recording {
+    dir: PathBuf,
+    started: Instant,
+    packets: mpsc::UnboundedSender<Packet>,
+    writer: JoinHandle<Vec<(UserId, PathBuf)>>,
+}
+
+/// Records everyone in a guild's voice channel to their own file.
+///
+/// One of these is made for every call, and is fed by the call's [`Receiver`](super::Receiver).
+/// The call only decodes what it receives while a recording is going on, and nothing is
+/// written otherwise.
+pub struct Recorder {
+    guild_id: GuildId,
+    chan_id: ChannelId,
+    http: Arc<Http>,
+    /// Weak, since the call's event handlers hold on to the recorder.
+    call: Weak<AsyncMutex<Call>>,
+    ssrcs: Mutex<HashMap<u32, UserId>>,
+    recording: Mutex<Option<Recording>>,
+}
+
+impl Recorder {
+    pub fn new(
+        guild_id: GuildId,
+        chan_id: ChannelId,
+        http: Arc<Http>,
+        call: &Arc<AsyncMutex<Call>>,
+    ) -> Self {
+        Self {
+            guild_id,
+            chan_id,
+            http,
+            call: Arc::downgrade(call),
+            ssrcs: Mutex::new(HashMap::new()),
+            recording: Mutex::new(None),
+        }
+    }
+
+    /// Turns decoding of received audio on or off for the call.
+    async fn set_decoding(&self, decode: bool) {
+        let mode = if decode {
+            DecodeMode::Decode
+        } else {
+            DecodeMode::Decrypt
+        };
+
+        if let Some(call) = self.call.upgrade() {
+            let mut call = call.lock().await;
+            let config = call.config().clone().decode_mode(mode);
+            call.set_config(config);
+        }
+    }
+
+    pub fn map_ssrc(&self, ssrc: u32, user_id: UserId) {
+        self.ssrcs.lock().insert(ssrc, user_id);
+    }
+
+    /// Who an SSRC belongs to, if we've been told yet.
+    pub fn user(&self, ssrc: u32) -> Option<UserId> {
+        self.ssrcs.lock().get(&ssrc).copied()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.lock().is_some()
+    }
+
+    async fn start(self: &Arc<Self>) -> Result<PathBuf, String> {
+        let dir = self.start_writer()?;
+        self.set_decoding(true).await;
+
+        let recorder = Arc::downgrade(self);
+        let limited = dir.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(MAX_RECORDING_LENGTH).await;
+            if let Some(recorder) = recorder.upgrade() {
+                recorder.stop_at_limit(&limited).await;
+            }
+        });
+
+        Ok(dir)
+    }
+
+    /// Stops the recording in `dir` once it's gone on for as long as a recording can, if it's
+    /// still going.
+    async fn stop_at_limit(&self, dir: &Path) {
+        let current = self.recording.lock().as_ref().map(|r| r.dir.clone());
+        if current.as_deref() != Some(dir) {
+            return;
+        }
+
+        let _ = self
+            .chan_id
+            .say(
+                &self.http,
+                format!(
+                    "⏹️ Recordings can only go on for {} hours, so I'm stopping this one here.",
+                    MAX_RECORDING_LENGTH.as_secs() / 3600
+                ),
+            )
+            .await;
+        self.finish().await;
+    }
+
+    fn start_writer(&self) -> Result<PathBuf, String> {
+        let mut recording = self.recording.lock();
+        if recording.is_some() {
+            return Err("I'm already recording.".to_string());
+        }
+
+        let root = env::var("RECORDINGS_DIR").unwrap_or_else(|_| "recordings".to_string());
+        let dir = Path::new(&root).join(format!(
+            "{}-{}",
+            self.guild_id,
+            chrono::Utc::now().format("%Y%m%d-%H%M%S")
+        ));
+        fs::create_dir_all(&dir).map_err(|why| {
+            error!("Could not create recording directory {:?}: {:?}", dir, why);
+            "I couldn't start recording.".to_string()
+        })?;
+
+        let (packets, received) = mpsc::unbounded_channel();
+        *recording = Some(Recording {
+            dir: dir.clone(),
+            started: Instant::now(),
+            packets,
+            writer: spawn_writer(dir.clone(), received),
+        });
+
+        Ok(dir)
+    }
+
+    /// Hands a decoded packet to the writer for the speaker's file, if there's a recording going on.
+    pub fn write(&self, ssrc: u32, audio: &[i16]) {
+        let recording = self.recording.lock();
+        let recording = match recording.as_ref() {
+            Some(recording) => recording,
+            None => return,
+        };
+
+        // packets can show up before we know who they're from
+        let user_id = match self.ssrcs.lock().get(&ssrc) {
+            Some(user_id) => *user_id,
+            None => return,
+        };
+
+        let position = (recording.started.elapsed().as_secs_f64() * SAMPLE_RATE as f64) as u64;
+        let position = position.saturating_sub(FRAME_SAMPLES);
+
+        let _ = recording.packets.send(Packet {
+            user_id,
+            position,
+            audio: audio.to_vec(),
+        });
+    }
+
+    /// Lets someone who joins mid-recording know they're being recorded.
+    pub fn announce_join(&self, user_id: UserId) {
+        if !self.is_recording() {
+            return;
+        }
+
+        let chan_id = self.chan_id;
+        let http = self.http.clone();
+        tokio::spawn(async move {
+            let _ = chan_id
+                .say(
+                    &http,
+                    format!(
+                        "🔴 {}, just so you know, this channel is being recorded.",
+                        user_id.mention()
+                    ),
+                )
+                .await;
+        });
+    }
+
+    /// Stops the recording, mixes it down and posts the files.
+    ///
+    /// Returns false if there wasn't a recording to stop.
+    pub async fn finish(&self) -> bool {
+        let recording = match self.recording.lock().take() {
+            Some(recording) => recording,
+            None => return false,
+        };
+        let length = recording.started.elapsed();
+        self.set_decoding(false).await;
+
+        // the writer finishes up the files once it's out of packets
+        drop(recording.packets);
+        let speakers = recording.writer.await.unwrap_or_default();
+
+        if speakers.is_empty() {
+            let _ = fs::remove_dir_all(&recording.dir);
+            let _ = self
+                .chan_id
+                .say(
+                    &self.http,
+                    "⏹️ Stopped recording. Nobody said anything, so there's nothing to send.",
+                )
+                .await;
+            return true;
+        }
+
+        let files = encode(&recording.dir, &speakers).await;
+        let size = files
+            .iter()
+            .filter_map(|f| fs::metadata(f).ok())
+            .map(|m| m.len())
+            .sum::<u64>();
+
+        let summary = format!(
+            "⏹️ Stopped recording after {}, with {} speakers.",
+            format_duration(length),
+            speakers.len()
+        );
+
+        if !files.is_empty() && size <= UPLOAD_LIMIT {
+            let attachments = files
+                .iter()
+                .map(|f| AttachmentType::Path(f.as_path()))
+                .collect::<Vec<_>>();
+
+            if self
+                .chan_id
+                .send_files(&self.http, attachments, |m| m.content(&summary))
+                .await
+                .is_ok()
+            {
+                return true;
+            }
+        }
+
+        let dir_name = recording
+            .dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let location = match env::var("RECORDINGS_URL") {
+            Ok(url) => format!(
+                "You can get it at {}/{}/",
+                url.trim_end_matches('/'),
+                dir_name
+            ),
+            Err(_) => format!(
+                "It's too big to upload, so it's been kept as `{}`.",
+                dir_name
+            ),
+        };
+        let _ = self
+            .chan_id
+            .say(&self.http, format!("{} {}", summary, location))
+            .await;
+
+        true
+    }
+}
+
+/// Encodes each speaker and a mixdown of everyone to Ogg/Opus, keeping the wav files.
+async fn encode(dir: &Path, speakers: &[(UserId, PathBuf)]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+
+    let mix = dir.join("mix.ogg");
+    let mut mixdown = Command::new("ffmpeg");
+    mixdown.args(&["-y", "-loglevel", "error"]);
+    for (_, wav) in speakers {
+        mixdown.arg("-i").arg(wav);
+    }
+    mixdown
+        .arg("-filter_complex")
+        .arg(format!("amix=inputs={}:duration=longest", speakers.len()))
+        .args(&["-c:a", "libopus"])
+        .arg(&mix);
+    if matches!(mixdown.status().await, Ok(status) if status.success()) {
+        files.push(mix);
+    } else {
+        warn!("Could not mix down the recording in {:?}", dir);
+    }
+
+    for (user_id, wav) in speakers {
+        let ogg = dir.join(format!("{}.ogg", user_id));
+        let status = Command::new("ffmpeg")
+            .args(&["-y", "-loglevel", "error", "-i"])
+            .arg(wav)
+            .args(&["-c:a", "libopus"])
+            .arg(&ogg)
+            .status()
+            .await;
+
+        if matches!(status, Ok(status) if status.success()) {
+            files.push(ogg);
+        }
+    }
+
+    files
+}
+
+async fn guild_recorder(ctx: &Context, guild_id: GuildId) -> Option<Arc<Recorder>> {
+    sessions(ctx)
+        .await
+        .get(guild_id)
+        .map(|session| session.recorder.clone())
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds, dj)]
+#[aliases(rec)]
+#[description(
+    "Record everyone in the voice channel, each to their own file. Subcommands: `start`, `stop`"
+)]
+#[sub_commands(record_start, record_stop)]
+async fn record(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    record_start(ctx, msg, args).await
+}
+
+#[command("start")]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds, dj)]
+async fn record_start(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+
+    let (recorder, listening) = match (
+        guild_recorder(ctx, guild.id).await,
+        listeners(ctx, &guild).await,
+    ) {
+        (Some(recorder), Some((_, listening))) => (recorder, listening),
+        _ => {
+            msg.channel_id
+                .say(&ctx.http, "I need to be in a voice channel to record.")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    if let Err(why) = recorder.start().await {
+        msg.channel_id.say(&ctx.http, why).await?;
+
+        return Ok(());
+    }
+
+    let listening = listening
+        .iter()
+        .map(|u| u.mention().to_string())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    msg.channel_id
+        .say(
+            &ctx.http,
+            format!(
+                "🔴 {} started recording this voice channel. Everyone in it is being recorded: {}\nUse `record stop` to stop.",
+                msg.author.mention(),
+                listening
+            ),
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[command("stop")]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds, dj)]
+async fn record_stop(ctx: &Context, msg: &Message) -> CommandResult {
+    let stopped = match guild_recorder(ctx, msg.guild_id.unwrap()).await {
+        Some(recorder) => recorder.finish().await,
+        None => false,
+    };
+
+    if !stopped {
+        msg.channel_id
+            .say(&ctx.http, "I'm not recording right now.")
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wav_writer_refuses_more_than_the_header_can_count() {
+        let path = env::temp_dir().join(format!("maki-test-long-{}.wav", std::process::id()));
+        let mut writer = WavWriter::create(&path).unwrap();
+
+        assert!(writer.write_at(0, &[0; 4]).is_ok());
+        assert!(writer.write_at(MAX_WAV_FRAMES, &[0; 4]).is_err());
+        // nothing was padded out towards the refused packet
+        assert_eq!(writer.frames, 2);
+
+        writer.finish().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 44 + 8);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,171 @@
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use chrono::Utc;
+use sqlx::PgPool;
+use std::time::Duration;
+
+use crate::{
+    commands::voice::{play::get_source, session::sessions},
+    keys::ConnectionPool,
+    utils::{
+        queue::Request,
+        queue_store::{forget_session, saved_sessions, SavedSession},
+    },
+};
+
+/// How long someone has to accept picking a session back up.
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(300);
+const RESTORE_EMOJI: &str = "▶️";
+
+/// Offers to resume every voice session which was left behind by the last run.
+///
+/// Each offer runs in its own task, so a guild that never answers doesn't hold up the others.
+pub async fn offer_restore(ctx: Context, bot_id: UserId) {
+    let pool = {
+        let data = ctx.data.read().await;
+        data.get::<ConnectionPool>().unwrap().clone()
+    };
+
+    let sessions = match saved_sessions(&pool).await {
+        Ok(s) => s,
+        Err(why) => {
+            error!("Could not load saved voice sessions: {:?}", why);
+            return;
+        }
+    };
+
+    info!("Found {} saved voice sessions.", sessions.len());
+
+    for session in sessions {
+        let ctx = ctx.clone();
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            let guild_id = session.guild_id;
+            if let Err(why) = offer(&ctx, &pool, session, bot_id).await {
+                error!(
+                    "Could not restore voice session for guild {}: {:?}",
+                    guild_id, why
+                );
+                let _ = forget_session(&pool, guild_id).await;
+            }
+        });
+    }
+}
+
+async fn offer(
+    ctx: &Context,
+    pool: &PgPool,
+    session: SavedSession,
+    bot_id: UserId,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let tracks = match session.tracks.len() {
+        1 => "1 track".to_string(),
+        n => format!("{} tracks", n),
+    };
+    let mut message = session
+        .text_channel
+        .say(
+            &ctx.http,
+            format!(
+                "I was playing {} in 🔊 {} before I restarted. React with {} in the next 5 minutes to pick up where I left off.",
+                tracks,
+                session.voice_channel.mention(),
+                RESTORE_EMOJI
+            ),
+        )
+        .await?;
+    message
+        .react(ctx, ReactionType::Unicode(RESTORE_EMOJI.to_string()))
+        .await?;
+
+    let accepted = message
+        .await_reaction(ctx)
+        .timeout(RESTORE_TIMEOUT)
+        .filter(move |r| {
+            r.user_id != Some(bot_id) && r.emoji == ReactionType::Unicode(RESTORE_EMOJI.to_string())
+        })
+        .await
+        .is_some();
+
+    let _ = message.delete_reactions(ctx).await;
+
+    if !accepted {
+        forget_session(pool, session.guild_id).await?;
+        message
+            .edit(ctx, |m| {
+                m.content("Okay, I won't pick the old queue back up.")
+            })
+            .await?;
+        return Ok(());
+    }
+
+    restore(ctx, session).await
+}
+
+/// Rejoins the saved voice channel and refills the queue, seeking back into the first track.
+///
+/// The saved session is only written over once every track has been added back, so
+/// nothing is lost if the bot goes down partway through.
+async fn restore(
+    ctx: &Context,
+    session: SavedSession,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let voice = match sessions(ctx)
+        .await
+        .start(
+            ctx,
+            session.guild_id,
+            session.voice_channel,
+            session.text_channel,
+        )
+        .await?
+    {
+        Some(voice) => voice,
+        None => return Err("could not join the saved voice channel".into()),
+    };
+    let queue = &voice.queue;
+    queue.hold_store();
+
+    for (position, track) in session.tracks.into_iter().enumerate() {
+        if track.source_url.is_empty() {
+            continue;
+        }
+
+        let source = match get_source(session.guild_id, &track.source_url).await {
+            Ok(s) => s,
+            Err(why) => {
+                warn!("Could not restore track {}: {}", track.source_url, why);
+                continue;
+            }
+        };
+
+        let request = Request {
+            user: track.requester,
+            channel: track.requested_in.unwrap_or(session.text_channel),
+            at: track.requested_at.unwrap_or_else(Utc::now),
+        };
+
+        let mut handler = voice.call.lock().await;
+        queue.add_source(source, request, &mut handler);
+
+        // the saved position belongs to the track that was playing, and goes with it if it failed
+        if position == 0 {
+            if let Some(current) = queue.current() {
+                let _ = current.seek_time(session.elapsed);
+            }
+        }
+    }
+
+    queue.release_store();
+
+    session
+        .text_channel
+        .say(
+            &ctx.http,
+            format!("Picked the queue back up with {} tracks.", queue.len()),
+        )
+        .await?;
+
+    Ok(())
+}
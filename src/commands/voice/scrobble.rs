@@ -0,0 +1,582 @@
+use serenity::async_trait;
+use serenity::cache::Cache;
+use serenity::model::prelude::*;
+
+use chrono::Utc;
+use parking_lot::Mutex;
+use songbird::{
+    tracks::{PlayMode, TrackHandle},
+    Event, EventContext, EventHandler as VoiceEventHandler, Songbird,
+};
+use sqlx::PgPool;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    commands::{
+        music::lastfm::{scrobble, update_now_playing, FmError, ScrobbleTrack},
+        voice::{autoplay::lastfm_names, listeners_with},
+    },
+    utils::queue::TrackQueue,
+};
+
+/// How often the scrobbler looks at what's playing and who's listening.
+pub const SCROBBLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// last.fm doesn't take tracks shorter than this.
+const MIN_SCROBBLE_LENGTH: Duration = Duration::from_secs(30);
+/// A track counts once someone has listened to half of it, or this much of it.
+const SCROBBLE_AFTER: Duration = Duration::from_secs(4 * 60);
+/// A track which goes back to within this much of its start is taken to be playing again, as
+/// looping tracks do, rather than to have been rewound.
+const RESTART_WINDOW: Duration = Duration::from_secs(10);
+/// How often scrobbles which didn't go through are tried again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How many scrobbles are tried again at a time.
+const RETRY_BATCH: i64 = 50;
+/// last.fm doesn't take scrobbles older than this many days.
+const MAX_SCROBBLE_AGE: i64 = 14;
+
+/// Returns the last.fm session keys of whichever of `users` have scrobbling set up.
+async fn session_keys(
+    pool: &PgPool,
+    users: &[UserId],
+) -> Result<Vec<(UserId, String)>, sqlx::Error> {
+    let ids = users.iter().map(|u| u.0 as i64).collect::<Vec<_>>();
+    let users = sqlx::query!(
+        "
+    select id, lastfm_session as \"lastfm_session!\"
+    from users
+    where id = any($1) and lastfm_session is not null
+    ",
+        &ids
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(users
+        .into_iter()
+        .map(|u| (UserId(u.id as u64), u.lastfm_session))
+        .collect())
+}
+
+/// Forgets a user's last.fm session key, which turns scrobbling off until they set it up again.
+pub async fn forget_session(pool: &PgPool, user: UserId) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "
+    update users
+    set lastfm_session = null, lastfm_session_name = null
+    where id = $1
+    ",
+        user.0 as i64
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Puts a scrobble which didn't go through aside, to be tried again later.
+async fn save_for_retry(
+    pool: &PgPool,
+    user: UserId,
+    track: &ScrobbleTrack,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "
+    insert into scrobble_queue(user_id, artist, title, duration, started_at)
+    values($1, $2, $3, $4, $5)
+    ",
+        user.0 as i64,
+        track.artist,
+        track.title,
+        track.duration.map(|d| d.as_secs() as i32),
+        track.started_at
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// The track playing now, and how much of it has been listened to.
+struct Playing {
+    /// The track as it was first queued, which stays the same when filters or seeking recreate it.
+    origin: TrackHandle,
+    /// How far in it was at the last check.
+    position: Duration,
+    listening: Listening,
+    checked_at: Instant,
+}
+
+/// A track, and how long each listener has heard it for.
+struct Listening {
+    /// `None` when there's nothing last.fm would know the track by.
+    track: Option<ScrobbleTrack>,
+    /// Not counting pauses.
+    listened: HashMap<UserId, Duration>,
+}
+
+impl Listening {
+    /// The people who listened to enough of the track for it to count, if it counts at all.
+    fn scrobblers(&self) -> Vec<UserId> {
+        let duration = self.track.as_ref().and_then(|t| t.duration);
+        if duration.map_or(false, |d| d < MIN_SCROBBLE_LENGTH) {
+            return Vec::new();
+        }
+
+        // streams don't have a length to take half of
+        let needed = duration.map_or(SCROBBLE_AFTER, |d| (d / 2).min(SCROBBLE_AFTER));
+        let mut users = self
+            .listened
+            .iter()
+            .filter(|(_, listened)| **listened >= needed)
+            .map(|(user, _)| *user)
+            .collect::<Vec<_>>();
+        users.sort();
+
+        users
+    }
+
+    /// The track and who to scrobble it for, if anyone.
+    fn into_scrobble(self) -> Option<(Vec<UserId>, ScrobbleTrack)> {
+        let users = self.scrobblers();
+        match self.track {
+            Some(track) if !users.is_empty() => Some((users, track)),
+            _ => None,
+        }
+    }
+}
+
+/// Scrobbles what the bot plays to the last.fm accounts of whoever is listening and has set
+/// scrobbling up, and shows it as what they're listening to while it plays.
+///
+/// This is registered as a periodic event on the call. Each check runs in its own task, and
+/// talking to last.fm in another, so neither holds up the call's other events.
+#[derive(Clone)]
+pub struct Scrobbler(Arc<ScrobbleCheck>);
+
+struct ScrobbleCheck {
+    guild_id: GuildId,
+    cache: Arc<Cache>,
+    manager: Arc<Songbird>,
+    queue: TrackQueue,
+    pool: PgPool,
+    playing: Mutex<Option<Playing>>,
+    /// Set while a check is running, so a slow one doesn't get another started alongside it.
+    checking: AtomicBool,
+}
+
+impl Scrobbler {
+    pub fn new(
+        guild_id: GuildId,
+        cache: Arc<Cache>,
+        manager: Arc<Songbird>,
+        queue: TrackQueue,
+        pool: PgPool,
+    ) -> Self {
+        Self(Arc::new(ScrobbleCheck {
+            guild_id,
+            cache,
+            manager,
+            queue,
+            pool,
+            playing: Mutex::new(None),
+            checking: AtomicBool::new(false),
+        }))
+    }
+
+    /// Scrobbles the track which was playing when the session ended, as no check will
+    /// see it finish.
+    pub async fn end(&self) {
+        let finished = self.0.playing.lock().take();
+
+        if let Some((users, track)) = finished.and_then(|p| p.listening.into_scrobble()) {
+            self.0.scrobble_for(&users, &track).await;
+        }
+    }
+}
+
+impl ScrobbleCheck {
+    /// Everyone in the bot's channel who can hear it.
+    async fn listeners(&self) -> Vec<UserId> {
+        let guild = match self.cache.guild(self.guild_id) {
+            Some(guild) => guild,
+            None => return Vec::new(),
+        };
+
+        listeners_with(&self.manager, &guild)
+            .await
+            .map_or(Vec::new(), |(_, users)| users)
+            .into_iter()
+            .filter(|user| {
+                guild
+                    .voice_states
+                    .get(user)
+                    .map_or(false, |state| !state.deaf && !state.self_deaf)
+            })
+            .collect()
+    }
+
+    /// Shows a track as playing on the profiles of whichever of `users` have scrobbling set up.
+    async fn now_playing(&self, users: &[UserId], track: &ScrobbleTrack) {
+        let sessions = match session_keys(&self.pool, users).await {
+            Ok(sessions) => sessions,
+            Err(why) => {
+                warn!(
+                    "Could not read last.fm sessions in guild {}: {:?}",
+                    self.guild_id, why
+                );
+                return;
+            }
+        };
+
+        for (user, key) in sessions {
+            match update_now_playing(&key, track).await {
+                Err(FmError::InvalidSession) => {
+                    if let Err(why) = forget_session(&self.pool, user).await {
+                        warn!(
+                            "Could not forget the last.fm session of {}: {:?}",
+                            user, why
+                        );
+                    }
+                }
+                Err(why) => debug!(
+                    "Could not update what {} is playing on last.fm: {}",
+                    user, why
+                ),
+                Ok(()) => {}
+            }
+        }
+    }
+
+    /// Scrobbles a track to whichever of `users` have scrobbling set up, keeping the ones
+    /// which don't go through to try again later.
+    async fn scrobble_for(&self, users: &[UserId], track: &ScrobbleTrack) {
+        let pool = &self.pool;
+        let sessions = match session_keys(pool, users).await {
+            Ok(sessions) => sessions,
+            Err(why) => {
+                warn!(
+                    "Could not read last.fm sessions in guild {}: {:?}",
+                    self.guild_id, why
+                );
+                return;
+            }
+        };
+
+        for (user, key) in sessions {
+            let result = match scrobble(&key, track).await {
+                Err(FmError::Temporary(why)) => {
+                    debug!("Scrobble for {} will be tried again: {}", user, why);
+                    save_for_retry(pool, user, track).await
+                }
+                Err(FmError::InvalidSession) => forget_session(pool, user).await,
+                Err(FmError::Rejected(why)) => {
+                    debug!("last.fm didn't take a scrobble for {}: {}", user, why);
+                    Ok(())
+                }
+                Ok(()) => Ok(()),
+            };
+
+            if let Err(why) = result {
+                warn!("Could not keep track of a scrobble for {}: {:?}", user, why);
+            }
+        }
+    }
+
+    /// Counts who has been listening since the last check, and scrobbles the track that was
+    /// playing if it has changed or started over since.
+    async fn check(self: &Arc<Self>) {
+        let snapshot = self.queue.snapshot();
+        let current = snapshot.current.zip(snapshot.tracks.into_iter().next());
+        let state = match &current {
+            Some((handle, _)) => handle.get_info().await.ok(),
+            None => None,
+        };
+        let position = state.as_ref().map_or(Duration::default(), |s| s.position);
+        let current = current.map(|(handle, track)| (self.queue.origin(&handle), track));
+        let listeners = self.listeners().await;
+
+        let (finished, started) = {
+            let mut playing = self.playing.lock();
+
+            let same_track = match (&*playing, &current) {
+                (Some(playing), Some((origin, _))) => playing.origin.uuid() == origin.uuid(),
+                (None, None) => true,
+                _ => false,
+            };
+            let restarted = playing
+                .as_ref()
+                .map_or(false, |p| started_over(p.position, position));
+
+            if same_track && !restarted {
+                if let Some(playing) = playing.as_mut() {
+                    let elapsed = playing.checked_at.elapsed();
+                    playing.checked_at = Instant::now();
+                    playing.position = position;
+
+                    if state
+                        .as_ref()
+                        .map_or(false, |s| s.playing == PlayMode::Play)
+                    {
+                        for user in &listeners {
+                            *playing.listening.listened.entry(*user).or_default() += elapsed;
+                        }
+                    }
+                }
+
+                (None, None)
+            } else {
+                let next = current.map(|(origin, track)| Playing {
+                    origin,
+                    position,
+                    listening: Listening {
+                        track: lastfm_names(&track).map(|(artist, title)| ScrobbleTrack {
+                            artist,
+                            title,
+                            duration: track.duration,
+                            started_at: Utc::now()
+                                - chrono::Duration::from_std(position)
+                                    .unwrap_or_else(|_| chrono::Duration::zero()),
+                        }),
+                        listened: HashMap::new(),
+                    },
+                    checked_at: Instant::now(),
+                });
+                let started = next.as_ref().and_then(|n| n.listening.track.clone());
+
+                (std::mem::replace(&mut *playing, next), started)
+            }
+        };
+
+        let scrobble = finished.and_then(|p| p.listening.into_scrobble());
+        let now_playing = started.filter(|_| !listeners.is_empty());
+
+        if scrobble.is_some() || now_playing.is_some() {
+            let this = self.clone();
+            tokio::spawn(async move {
+                if let Some((users, track)) = scrobble {
+                    this.scrobble_for(&users, &track).await;
+                }
+                if let Some(started) = now_playing {
+                    this.now_playing(&listeners, &started).await;
+                }
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl VoiceEventHandler for Scrobbler {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        if self.0.checking.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+
+        let check = self.0.clone();
+        tokio::spawn(async move {
+            check.check().await;
+            check.checking.store(false, Ordering::Release);
+        });
+
+        None
+    }
+}
+
+/// Whether a track went back to its start between two checks, rather than being rewound.
+fn started_over(last: Duration, now: Duration) -> bool {
+    now < last && now <= RESTART_WINDOW
+}
+
+/// What's done with a scrobble which was tried again.
+#[derive(Debug, PartialEq)]
+enum Retried {
+    /// last.fm is still having trouble, so it and the rest are left for next time.
+    Later,
+    /// The user took back the bot's access, so their session is forgotten.
+    ForgetSession,
+    /// It went through, or never will.
+    Done,
+}
+
+impl From<Result<(), FmError>> for Retried {
+    fn from(result: Result<(), FmError>) -> Self {
+        match result {
+            Err(FmError::Temporary(_)) => Retried::Later,
+            Err(FmError::InvalidSession) => Retried::ForgetSession,
+            _ => Retried::Done,
+        }
+    }
+}
+
+/// Tries the scrobbles which didn't go through again, every so often, for as long as the bot runs.
+pub async fn retry_scrobbles(pool: PgPool) {
+    loop {
+        tokio::time::sleep(RETRY_INTERVAL).await;
+
+        if let Err(why) = retry_batch(&pool).await {
+            warn!("Could not retry scrobbles: {:?}", why);
+        }
+    }
+}
+
+async fn retry_batch(pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "
+    delete from scrobble_queue
+    where started_at < $1
+    ",
+        Utc::now() - chrono::Duration::days(MAX_SCROBBLE_AGE)
+    )
+    .execute(pool)
+    .await?;
+
+    let queued = sqlx::query!(
+        "
+    select q.id, q.user_id, q.artist, q.title, q.duration, q.started_at, u.lastfm_session
+    from scrobble_queue q
+    join users u on u.id = q.user_id
+    order by q.started_at
+    limit $1
+    ",
+        RETRY_BATCH
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for queued in queued {
+        let user = UserId(queued.user_id as u64);
+        let track = ScrobbleTrack {
+            artist: queued.artist,
+            title: queued.title,
+            duration: queued.duration.map(|d| Duration::from_secs(d as u64)),
+            started_at: queued.started_at,
+        };
+
+        // people who turned scrobbling off since don't get it sent
+        if let Some(key) = &queued.lastfm_session {
+            match Retried::from(scrobble(key, &track).await) {
+                Retried::Later => break,
+                Retried::ForgetSession => forget_session(pool, user).await?,
+                Retried::Done => {}
+            }
+        }
+
+        sqlx::query!(
+            "
+        delete from scrobble_queue
+        where id = $1
+        ",
+            queued.id
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: UserId = UserId(1);
+    const B: UserId = UserId(2);
+    const C: UserId = UserId(3);
+
+    fn listening(duration: Option<u64>, listened: &[(UserId, u64)]) -> Listening {
+        Listening {
+            track: Some(ScrobbleTrack {
+                artist: "Daft Punk".to_string(),
+                title: "One More Time".to_string(),
+                duration: duration.map(Duration::from_secs),
+                started_at: Utc::now(),
+            }),
+            listened: listened
+                .iter()
+                .map(|(user, secs)| (*user, Duration::from_secs(*secs)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn tracks_count_once_half_has_been_heard() {
+        let playing = listening(Some(200), &[(A, 100), (B, 99), (C, 180)]);
+
+        assert_eq!(playing.scrobblers(), vec![A, C]);
+    }
+
+    #[test]
+    fn long_tracks_count_after_four_minutes() {
+        let playing = listening(Some(3600), &[(A, 240), (B, 239)]);
+
+        assert_eq!(playing.scrobblers(), vec![A]);
+    }
+
+    #[test]
+    fn short_tracks_never_count() {
+        let playing = listening(Some(29), &[(A, 29)]);
+
+        assert!(playing.scrobblers().is_empty());
+        assert!(playing.into_scrobble().is_none());
+    }
+
+    #[test]
+    fn streams_count_after_four_minutes() {
+        let playing = listening(None, &[(A, 240), (B, 120)]);
+
+        assert_eq!(playing.scrobblers(), vec![A]);
+    }
+
+    #[test]
+    fn only_named_tracks_are_scrobbled() {
+        let (users, track) = listening(Some(200), &[(A, 150), (B, 10)])
+            .into_scrobble()
+            .unwrap();
+        assert_eq!(users, vec![A]);
+        assert_eq!(track.title, "One More Time");
+
+        let unnamed = Listening {
+            track: None,
+            ..listening(Some(200), &[(A, 150)])
+        };
+        assert!(unnamed.into_scrobble().is_none());
+        assert!(listening(Some(200), &[]).into_scrobble().is_none());
+    }
+
+    #[test]
+    fn going_back_to_the_start_is_playing_again() {
+        let secs = Duration::from_secs;
+
+        // a looping track comes back around
+        assert!(started_over(secs(200), secs(3)));
+        assert!(started_over(secs(200), secs(10)));
+        // seeking back into the middle isn't a new listen
+        assert!(!started_over(secs(200), secs(60)));
+        // and neither is carrying on
+        assert!(!started_over(secs(5), secs(10)));
+    }
+
+    #[test]
+    fn retries_wait_out_trouble_and_forget_taken_back_sessions() {
+        assert_eq!(
+            Retried::from(Err(FmError::Temporary("Service Offline".to_string()))),
+            Retried::Later
+        );
+        assert_eq!(
+            Retried::from(Err(FmError::InvalidSession)),
+            Retried::ForgetSession
+        );
+        assert_eq!(
+            Retried::from(Err(FmError::Rejected("Invalid parameters".to_string()))),
+            Retried::Done
+        );
+        assert_eq!(Retried::from(Ok(())), Retried::Done);
+    }
+}
@@ -0,0 +1,239 @@
+use serenity::framework::standard::CommandError;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use parking_lot::Mutex;
+use songbird::{Call, CoreEvent, Event, Songbird};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::{
+    commands::voice::{
+        announce::Announcer,
+        autoplay::Autoplayer,
+        inactivity::{InactivityWatcher, INACTIVITY_CHECK_INTERVAL},
+        limits::fair_queue,
+        record::Recorder,
+        scrobble::{Scrobbler, SCROBBLE_CHECK_INTERVAL},
+        stats::{StatsFlusher, VoiceStats, STATS_FLUSH_INTERVAL},
+        transition::{Transitioner, TRANSITION_CHECK_INTERVAL},
+        Receiver,
+    },
+    keys::{ConnectionPool, VoiceSessions},
+    utils::{queue::TrackQueue, queue_store::QueueStore},
+};
+
+/// Everything the bot keeps for a guild while it's in voice there.
+pub struct VoiceSession {
+    pub guild_id: GuildId,
+    pub voice_channel: ChannelId,
+    /// The channel the session was started from, which is where it talks.
+    pub text_channel: ChannelId,
+    pub call: Arc<AsyncMutex<Call>>,
+    pub queue: TrackQueue,
+    pub recorder: Arc<Recorder>,
+    pub stats: Arc<VoiceStats>,
+    pub scrobbler: Scrobbler,
+}
+
+/// Keeps a [`VoiceSession`] for every guild the bot is in voice in.
+///
+/// The map is only locked for as long as it takes to look a session up, so nothing
+/// one guild does can hold up another. Guild settings aren't kept here, and are read
+/// when they're used, so changes apply to running sessions straight away.
+#[derive(Default)]
+pub struct SessionManager {
+    sessions: Mutex<HashMap<GuildId, Arc<VoiceSession>>>,
+    /// Held while a guild's session is being set up, so two commands can't set it up at once.
+    starting: Mutex<HashMap<GuildId, Arc<AsyncMutex<()>>>>,
+}
+
+impl SessionManager {
+    /// Returns a guild's session, if the bot is in voice there.
+    pub fn get(&self, guild_id: GuildId) -> Option<Arc<VoiceSession>> {
+        self.sessions.lock().get(&guild_id).cloned()
+    }
+
+    /// Returns a guild's queue, if the bot is in voice there.
+    pub fn queue(&self, guild_id: GuildId) -> Option<TrackQueue> {
+        self.get(guild_id).map(|session| session.queue.clone())
+    }
+
+    /// Joins `voice` and sets up the queue, recorder, stats and event handlers that go with it.
+    ///
+    /// If the guild already has a session, that one is returned instead. Returns `None` if
+    /// the channel couldn't be joined, after saying so in `text`.
+    pub async fn start(
+        self: &Arc<Self>,
+        ctx: &Context,
+        guild_id: GuildId,
+        voice: ChannelId,
+        text: ChannelId,
+    ) -> Result<Option<Arc<VoiceSession>>, CommandError> {
+        let starting = self.starting.lock().entry(guild_id).or_default().clone();
+        let _starting = starting.lock().await;
+
+        if let Some(session) = self.get(guild_id) {
+            return Ok(Some(session));
+        }
+
+        let manager = songbird::get(ctx)
+            .await
+            .expect("Songbird Voice client placed in at initialisation.")
+            .clone();
+        let pool = {
+            let data = ctx.data.read().await;
+            data.get::<ConnectionPool>().unwrap().clone()
+        };
+
+        let (call, conn_result) = manager.join(guild_id, voice).await;
+
+        if conn_result.is_err() {
+            text
+                .say(&ctx.http, "I couldn't join the channel. Make sure (or get someone to make sure) that I have permissions to join and speak.")
+                .await?;
+
+            return Ok(None);
+        }
+
+        let queue = TrackQueue::with_store(QueueStore::new(pool.clone(), guild_id, voice, text));
+        queue.bind_call(&call);
+        match fair_queue(&pool, guild_id).await {
+            Ok(fair) => queue.set_fair_mode(fair),
+            Err(why) => warn!("Could not read fair mode for guild {}: {:?}", guild_id, why),
+        }
+        Announcer::new(guild_id, text, ctx.http.clone(), pool.clone()).spawn(queue.announcements());
+        Autoplayer::new(
+            guild_id,
+            text,
+            ctx.cache.current_user_id(),
+            ctx.http.clone(),
+            pool.clone(),
+            self.clone(),
+        )
+        .spawn(queue.run_outs());
+
+        let session = Arc::new(VoiceSession {
+            guild_id,
+            voice_channel: voice,
+            text_channel: text,
+            call: call.clone(),
+            queue: queue.clone(),
+            recorder: Arc::new(Recorder::new(guild_id, text, ctx.http.clone(), &call)),
+            stats: Arc::new(VoiceStats::new(guild_id, pool.clone())),
+            scrobbler: Scrobbler::new(
+                guild_id,
+                ctx.cache.clone(),
+                manager.clone(),
+                queue.clone(),
+                pool.clone(),
+            ),
+        });
+
+        {
+            // NOTE: this skips listening for the actual connection result.
+            let mut handler = call.lock().await;
+
+            handler.add_global_event(
+                Event::Periodic(INACTIVITY_CHECK_INTERVAL, None),
+                InactivityWatcher::new(
+                    guild_id,
+                    text,
+                    ctx.http.clone(),
+                    ctx.cache.clone(),
+                    manager.clone(),
+                    self.clone(),
+                    queue.clone(),
+                    pool.clone(),
+                ),
+            );
+
+            handler.add_global_event(
+                Event::Periodic(SCROBBLE_CHECK_INTERVAL, None),
+                session.scrobbler.clone(),
+            );
+
+            handler.add_global_event(
+                Event::Periodic(TRANSITION_CHECK_INTERVAL, None),
+                Transitioner::new(guild_id, queue, pool),
+            );
+
+            handler.add_global_event(
+                Event::Periodic(STATS_FLUSH_INTERVAL, None),
+                StatsFlusher(session.stats.clone()),
+            );
+
+            for event in [
+                CoreEvent::SpeakingStateUpdate,
+                CoreEvent::SpeakingUpdate,
+                CoreEvent::VoicePacket,
+                CoreEvent::ClientConnect,
+                CoreEvent::ClientDisconnect,
+            ] {
+                handler.add_global_event(
+                    event.into(),
+                    Receiver::new(session.recorder.clone(), session.stats.clone()),
+                );
+            }
+        }
+
+        // people already in the channel don't connect again, so they're counted from now
+        if let Some(guild) = ctx.cache.guild(guild_id) {
+            let bot_id = ctx.cache.current_user_id();
+            for state in guild.voice_states.values() {
+                if state.channel_id == Some(voice) && state.user_id != bot_id {
+                    session.stats.joined(state.user_id);
+                }
+            }
+        }
+
+        self.sessions.lock().insert(guild_id, session.clone());
+
+        text.say(
+            &ctx.http,
+            &format!(
+                "Joined 🔊 {}, bound to #️⃣ {}",
+                voice.mention(),
+                text.mention()
+            ),
+        )
+        .await?;
+
+        Ok(Some(session))
+    }
+
+    /// Leaves voice in a guild and wraps its session up. The queue is stopped, and any
+    /// recording, stats and scrobble are finished.
+    ///
+    /// Returns whether there was a session to end.
+    pub async fn end(&self, manager: &Songbird, guild_id: GuildId) -> bool {
+        let session = self.sessions.lock().remove(&guild_id);
+
+        if let Some(session) = &session {
+            session.queue.stop();
+            session.recorder.finish().await;
+            session.stats.end().await;
+            session.scrobbler.end().await;
+        }
+
+        if manager.get(guild_id).is_some() {
+            if let Err(why) = manager.remove(guild_id).await {
+                warn!("Could not leave voice in guild {}: {:?}", guild_id, why);
+            }
+        }
+
+        session.is_some()
+    }
+}
+
+/// Returns the bot's voice sessions.
+pub async fn sessions(ctx: &Context) -> Arc<SessionManager> {
+    let data = ctx.data.read().await;
+
+    data.get::<VoiceSessions>().unwrap().clone()
+}
+
+/// Returns a guild's queue, if the bot is in voice there.
+pub async fn guild_queue(ctx: &Context, guild_id: GuildId) -> Option<TrackQueue> {
+    sessions(ctx).await.queue(guild_id)
+}
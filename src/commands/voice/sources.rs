@@ -1,15 +1,1210 @@
-use std::time::Duration;
+use aspotify::{
+    ArtistSimplified, Image, Page, PlaylistItem, PlaylistItemType, Track, TrackSimplified,
+};
+use parking_lot::Mutex;
+use reqwest::StatusCode;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use songbird::input::Metadata;
+use std::{collections::HashMap, env, time::Duration};
+use tokio::process::Command;
 
-struct ReturnSource {
-    title: String,
-    artist: String, 
-    source_url: String,
-    thumbnail: String,
-    duration: Duration
+use crate::commands::music::spotify::{spotify_api_url, spotify_client, spotify_token};
+
+/// How many tracks Spotify gives back per page of an album or playlist.
+const SPOTIFY_PAGE_SIZE: usize = 50;
+
+/// Lists the Audius discovery nodes which are up.
+const AUDIUS_PROVIDERS_URL: &str = "https://api.audius.co";
+/// Audius asks every app to identify itself.
+const AUDIUS_APP_NAME: &str = "maki";
+
+lazy_static! {
+    static ref AUDIUS_PROVIDER: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// A track resolved through a provider's API, with everything needed to play and show it.
+pub struct ReturnSource {
+    pub title: String,
+    pub artist: String,
+    /// The page the track was linked from, used to find it again later.
+    pub source_url: String,
+    /// Where the audio itself can be streamed from.
+    pub stream_url: String,
+    pub thumbnail: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+impl ReturnSource {
+    /// Fills in what's shown for the track with what the provider said about it.
+    pub fn fill_metadata(self, metadata: &mut Metadata) {
+        metadata.title = Some(self.title);
+        metadata.artist = Some(self.artist);
+        metadata.thumbnail = self.thumbnail;
+        metadata.duration = self.duration;
+        metadata.source_url = Some(self.source_url);
+    }
+}
+
+/// The Audius discovery node to use, which can be set with `AUDIUS_API_URL`.
+///
+/// Otherwise one is picked from the list at api.audius.co, and kept for as long as the bot runs.
+async fn audius_provider(client: &reqwest::Client) -> Result<String, String> {
+    if let Ok(url) = env::var("AUDIUS_API_URL") {
+        return Ok(url.trim_end_matches('/').to_string());
+    }
+    if let Some(url) = AUDIUS_PROVIDER.lock().clone() {
+        return Ok(url);
+    }
+
+    let resp = client
+        .get(AUDIUS_PROVIDERS_URL)
+        .send()
+        .await
+        .map_err(|why| {
+            error!("Could not get Audius discovery nodes: {:?}", why);
+            "I couldn't reach Audius right now.".to_string()
+        })?
+        .json::<Value>()
+        .await
+        .map_err(|_| "I couldn't reach Audius right now.".to_string())?;
+
+    let url = resp
+        .get("data")
+        .and_then(|d| d.get(0))
+        .and_then(|u| u.as_str())
+        .ok_or_else(|| "I couldn't reach Audius right now.".to_string())?
+        .trim_end_matches('/')
+        .to_string();
+
+    *AUDIUS_PROVIDER.lock() = Some(url.clone());
+
+    Ok(url)
+}
+
+/// Resolves an `audius.co` track link through the discovery API.
+pub async fn audius_track(url: &str) -> Result<ReturnSource, String> {
+    let client = reqwest::Client::new();
+    let provider = audius_provider(&client).await?;
+
+    audius_track_from(&client, &provider, url).await
+}
+
+async fn audius_track_from(
+    client: &reqwest::Client,
+    provider: &str,
+    url: &str,
+) -> Result<ReturnSource, String> {
+    // `resolve` redirects to the track itself
+    let resp = client
+        .get(&format!("{}/v1/resolve", provider))
+        .query(&[("url", url), ("app_name", AUDIUS_APP_NAME)])
+        .send()
+        .await
+        .map_err(|why| {
+            error!("Could not reach Audius: {:?}", why);
+            "I couldn't reach Audius right now.".to_string()
+        })?;
+
+    if !resp.status().is_success() {
+        return Err("I couldn't find that track on Audius.".to_string());
+    }
+
+    let resp = resp
+        .json::<Value>()
+        .await
+        .map_err(|_| "Audius sent back something I didn't understand.".to_string())?;
+    let track = resp
+        .get("data")
+        .ok_or_else(|| "I couldn't find that track on Audius.".to_string())?;
+
+    let id = track
+        .get("id")
+        .and_then(|i| i.as_str())
+        .ok_or_else(|| "That Audius link isn't a track.".to_string())?;
+    let title = track
+        .get("title")
+        .and_then(|t| t.as_str())
+        .unwrap_or("Unknown");
+    let user = track.get("user");
+    let artist = user
+        .and_then(|u| u.get("name"))
+        .or_else(|| user.and_then(|u| u.get("handle")))
+        .and_then(|a| a.as_str())
+        .unwrap_or("unknown");
+    let thumbnail = track.get("artwork").and_then(|a| {
+        ["480x480", "1000x1000", "150x150"]
+            .iter()
+            .find_map(|size| a.get(size)?.as_str())
+    });
+
+    Ok(ReturnSource {
+        title: title.to_string(),
+        artist: artist.to_string(),
+        source_url: url.to_string(),
+        stream_url: format!(
+            "{}/v1/tracks/{}/stream?app_name={}",
+            provider, id, AUDIUS_APP_NAME
+        ),
+        thumbnail: thumbnail.map(|t| t.to_string()),
+        duration: track
+            .get("duration")
+            .and_then(|d| d.as_f64())
+            .map(Duration::from_secs_f64),
+    })
+}
+
+/// Where youtube-dl's search should look.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchSite {
+    YouTube,
+    SoundCloud,
+}
+
+impl SearchSite {
+    fn prefix(self) -> &'static str {
+        match self {
+            SearchSite::YouTube => "ytsearch",
+            SearchSite::SoundCloud => "scsearch",
+        }
+    }
+}
+
+/// A single result from a youtube-dl search.
+#[derive(Clone, Debug)]
+pub struct SearchResult {
+    pub title: String,
+    pub uploader: Option<String>,
+    pub url: String,
+    pub duration: Option<Duration>,
+}
+
+/// The youtube-dl executable to run, which can be swapped out with `YOUTUBE_DL`.
+pub fn youtube_dl_path() -> String {
+    env::var("YOUTUBE_DL").unwrap_or_else(|_| "youtube-dl".to_string())
+}
+
+/// Searches a site through youtube-dl's search prefixes, returning up to `count` results.
+pub async fn search(
+    query: &str,
+    site: SearchSite,
+    count: usize,
+) -> Result<Vec<SearchResult>, String> {
+    search_with(&youtube_dl_path(), query, site, count).await
+}
+
+async fn search_with(
+    youtube_dl: &str,
+    query: &str,
+    site: SearchSite,
+    count: usize,
+) -> Result<Vec<SearchResult>, String> {
+    let output = Command::new(youtube_dl)
+        .arg("-j")
+        .arg("--no-playlist")
+        .arg("--ignore-errors")
+        .arg(format!("{}{}:{}", site.prefix(), count, query))
+        .output()
+        .await
+        .map_err(|why| {
+            error!("Could not run youtube-dl: {:?}", why);
+            "I couldn't search for that right now.".to_string()
+        })?;
+
+    // youtube-dl prints one JSON object per result
+    let results = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter_map(|v| {
+            Some(SearchResult {
+                title: v.get("title")?.as_str()?.to_string(),
+                uploader: v
+                    .get("uploader")
+                    .and_then(|u| u.as_str())
+                    .map(|u| u.to_string()),
+                url: v
+                    .get("webpage_url")
+                    .or_else(|| v.get("url"))?
+                    .as_str()?
+                    .to_string(),
+                duration: v
+                    .get("duration")
+                    .and_then(|d| d.as_f64())
+                    .map(Duration::from_secs_f64),
+            })
+        })
+        .take(count)
+        .collect::<Vec<_>>();
+
+    if results.is_empty() && !output.status.success() {
+        warn!(
+            "youtube-dl search failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(results)
+}
+
+/// A track in a playlist, which only gets resolved into a source once it's queued.
+///
+/// Spotify entries keep their Spotify URL and metadata, and are matched to a
+/// playable YouTube result when they're resolved.
+#[derive(Clone, Debug, Default)]
+pub struct PlaylistEntry {
+    pub url: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub thumbnail: Option<String>,
+}
+
+/// Whether a URL points at a YouTube or SoundCloud playlist rather than a single track.
+pub fn is_playlist_url(url: &str) -> bool {
+    let host = url.split('/').nth(2).unwrap_or("");
+
+    match host {
+        "www.youtube.com" | "youtube.com" | "m.youtube.com" | "music.youtube.com" => {
+            url.contains("list=")
+        }
+        "soundcloud.com" | "www.soundcloud.com" | "m.soundcloud.com" => url.contains("/sets/"),
+        _ => false,
+    }
+}
+
+/// Expands a playlist URL into its entries, without resolving any of them.
+pub async fn playlist_entries(url: &str) -> Result<Vec<PlaylistEntry>, String> {
+    playlist_entries_with(&youtube_dl_path(), url).await
+}
+
+async fn playlist_entries_with(youtube_dl: &str, url: &str) -> Result<Vec<PlaylistEntry>, String> {
+    let output = Command::new(youtube_dl)
+        .arg("-J")
+        .arg("--flat-playlist")
+        .arg("--yes-playlist")
+        .arg(url)
+        .output()
+        .await
+        .map_err(|why| {
+            error!("Could not run youtube-dl: {:?}", why);
+            "I couldn't load that playlist right now.".to_string()
+        })?;
+
+    let playlist = serde_json::from_slice::<Value>(&output.stdout).map_err(|_| {
+        warn!(
+            "youtube-dl playlist failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        "I couldn't load that playlist. Is it public?".to_string()
+    })?;
+
+    let entries = match playlist.get("entries").and_then(|e| e.as_array()) {
+        Some(entries) => entries,
+        None => return Err("That playlist doesn't have anything in it.".to_string()),
+    };
+
+    Ok(entries
+        .iter()
+        .filter_map(|entry| {
+            let url = entry.get("url")?.as_str()?;
+            // flat YouTube entries only carry the video id
+            let url = if url.starts_with("http") {
+                url.to_string()
+            } else {
+                format!("https://www.youtube.com/watch?v={}", url)
+            };
+
+            Some(PlaylistEntry {
+                url,
+                title: entry
+                    .get("title")
+                    .and_then(|t| t.as_str())
+                    .map(|t| t.to_string()),
+                ..Default::default()
+            })
+        })
+        .collect())
+}
+
+/// Parses an `.m3u` playlist. Only entries with http(s) URLs are kept.
+pub fn parse_m3u(content: &str) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    let mut title = None;
+
+    for line in content.lines().map(|l| l.trim()) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<length>,<title>
+            title = info
+                .splitn(2, ',')
+                .nth(1)
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty());
+        } else if line.is_empty() || line.starts_with('#') {
+            continue;
+        } else {
+            if line.starts_with("http://") || line.starts_with("https://") {
+                entries.push(PlaylistEntry {
+                    url: line.to_string(),
+                    title: title.take(),
+                    ..Default::default()
+                });
+            }
+            title = None;
+        }
+    }
+
+    entries
+}
+
+/// Parses a `.pls` playlist. Only entries with http(s) URLs are kept.
+pub fn parse_pls(content: &str) -> Vec<PlaylistEntry> {
+    let mut files = Vec::new();
+    let mut titles = HashMap::new();
+
+    for line in content.lines().map(|l| l.trim()) {
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim().to_lowercase(), value.trim()),
+            None => continue,
+        };
+
+        if let Some(n) = key.strip_prefix("file").and_then(|n| n.parse::<u32>().ok()) {
+            files.push((n, value.to_string()));
+        } else if let Some(n) = key
+            .strip_prefix("title")
+            .and_then(|n| n.parse::<u32>().ok())
+        {
+            titles.insert(n, value.to_string());
+        }
+    }
+
+    files.sort_by_key(|(n, _)| *n);
+    files
+        .into_iter()
+        .filter(|(_, url)| url.starts_with("http://") || url.starts_with("https://"))
+        .map(|(n, url)| PlaylistEntry {
+            url,
+            title: titles.remove(&n),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Whether a URL is an `open.spotify.com` track, album or playlist link.
+pub fn is_spotify_url(url: &str) -> bool {
+    spotify_item(url).is_some()
+}
+
+/// Splits a Spotify link into the kind of item it is and its id.
+fn spotify_item(url: &str) -> Option<(&str, &str)> {
+    let mut parts = url.split('/').skip(2);
+    if parts.next()? != "open.spotify.com" {
+        return None;
+    }
+
+    // links can have a locale before the item, like `/intl-de/track/...`
+    let mut parts = parts.skip_while(|p| p.starts_with("intl-"));
+    let kind = parts.next()?;
+    let id = parts.next()?.split('?').next()?;
+
+    match kind {
+        "track" | "album" | "playlist" if !id.is_empty() => Some((kind, id)),
+        _ => None,
+    }
 }
 
+/// The parts of the Web API resolving Spotify links needs, read with the shared client's token.
+struct SpotifyCatalog {
+    http: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+/// Just the cover of an album, out of everything the Web API sends about it.
+#[derive(Deserialize)]
+struct AlbumCover {
+    images: Vec<Image>,
+}
+
+impl SpotifyCatalog {
+    fn new(url: &str, token: String) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<T, String> {
+        let resp = self
+            .http
+            .get(&format!("{}{}", self.url, path))
+            .bearer_auth(&self.token)
+            .query(query)
+            .send()
+            .await
+            .map_err(|why| {
+                error!("Could not reach Spotify: {:?}", why);
+                "I couldn't reach Spotify right now.".to_string()
+            })?;
 
-async fn audius_track(artist: String, slug: String, id: i64) {
-const AUDIUS_PROVIDER: &str = "https://dn-usa.audius.metadata.fyi/";  // will select from api.audius.co later?
-let client = reqwest::Client::new();
-}
\ No newline at end of file
+        match resp.status() {
+            status if status.is_success() => resp.json::<T>().await.map_err(|why| {
+                warn!("Could not read Spotify's answer to {}: {:?}", path, why);
+                "Spotify sent back something I didn't understand.".to_string()
+            }),
+            StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND => {
+                Err("I couldn't get that from Spotify. Is it public?".to_string())
+            }
+            StatusCode::TOO_MANY_REQUESTS => {
+                Err("Spotify is getting too many requests, try again in a bit.".to_string())
+            }
+            status => {
+                warn!("Spotify answered {} with {}", path, status);
+                Err("I couldn't reach Spotify right now.".to_string())
+            }
+        }
+    }
+
+    async fn track(&self, id: &str) -> Result<Track, String> {
+        self.get(&format!("/tracks/{}", id), &[]).await
+    }
+
+    async fn album_cover(&self, id: &str) -> Result<Option<String>, String> {
+        let album = self
+            .get::<AlbumCover>(&format!("/albums/{}", id), &[])
+            .await?;
+
+        Ok(album.images.into_iter().next().map(|i| i.url))
+    }
+
+    async fn album_tracks(&self, id: &str, offset: usize) -> Result<Page<TrackSimplified>, String> {
+        let (limit, offset) = (SPOTIFY_PAGE_SIZE.to_string(), offset.to_string());
+
+        self.get(
+            &format!("/albums/{}/tracks", id),
+            &[("limit", &limit), ("offset", &offset)],
+        )
+        .await
+    }
+
+    async fn playlist_items(&self, id: &str, offset: usize) -> Result<Page<PlaylistItem>, String> {
+        let (limit, offset) = (SPOTIFY_PAGE_SIZE.to_string(), offset.to_string());
+
+        self.get(
+            &format!("/playlists/{}/tracks", id),
+            &[
+                ("limit", &limit),
+                ("offset", &offset),
+                ("additional_types", "track"),
+            ],
+        )
+        .await
+    }
+}
+
+fn artist_names(artists: &[ArtistSimplified]) -> Option<String> {
+    Some(
+        artists
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+    .filter(|a| !a.is_empty())
+}
+
+/// Turns a Spotify track into an entry. Local tracks have no link, so they're left out.
+fn track_entry(track: &Track) -> Option<PlaylistEntry> {
+    Some(PlaylistEntry {
+        url: track.external_urls.get("spotify")?.clone(),
+        title: Some(track.name.clone()),
+        artist: artist_names(&track.artists),
+        thumbnail: track.album.images.first().map(|i| i.url.clone()),
+    })
+}
+
+/// Turns a track from an album's listing into an entry, with the album's cover.
+fn album_track_entry(track: &TrackSimplified, cover: Option<&str>) -> Option<PlaylistEntry> {
+    Some(PlaylistEntry {
+        url: track.external_urls.get("spotify")?.clone(),
+        title: Some(track.name.clone()),
+        artist: artist_names(&track.artists),
+        thumbnail: cover.map(|c| c.to_string()),
+    })
+}
+
+/// Turns a page of a playlist into entries. Episodes, and tracks which were removed, are left out.
+fn playlist_page_entries(items: &[PlaylistItem]) -> Vec<PlaylistEntry> {
+    items
+        .iter()
+        .filter_map(|item| match &item.item {
+            Some(PlaylistItemType::Track(track)) => track_entry(track),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Resolves a Spotify track, album or playlist link into entries, reading at most `limit`
+/// tracks. Also returns how many tracks there are in total.
+pub async fn spotify_entries(
+    url: &str,
+    limit: usize,
+) -> Result<(Vec<PlaylistEntry>, usize), String> {
+    let client = spotify_client().ok_or_else(|| "Spotify links aren't set up here.".to_string())?;
+    let token = spotify_token(client).await.map_err(|why| {
+        warn!("Could not get a Spotify token: {:?}", why);
+        "I couldn't reach Spotify right now.".to_string()
+    })?;
+
+    spotify_entries_from(&SpotifyCatalog::new(&spotify_api_url(), token), url, limit).await
+}
+
+async fn spotify_entries_from(
+    client: &SpotifyCatalog,
+    url: &str,
+    limit: usize,
+) -> Result<(Vec<PlaylistEntry>, usize), String> {
+    let (kind, id) =
+        spotify_item(url).ok_or_else(|| "That isn't a Spotify link I know.".to_string())?;
+
+    if kind == "track" {
+        let track = client.track(id).await?;
+        let entry = track_entry(&track)
+            .ok_or_else(|| "That Spotify track can't be played here.".to_string())?;
+
+        return Ok((vec![entry], 1));
+    }
+
+    let cover = match kind {
+        "album" => client.album_cover(id).await?,
+        _ => None,
+    };
+
+    let mut entries = Vec::new();
+    let mut total = 0;
+    let mut offset = 0;
+    while entries.len() < limit {
+        let (found, read) = match kind {
+            "album" => {
+                let page = client.album_tracks(id, offset).await?;
+                total = page.total;
+                let read = page.items.len();
+                (
+                    page.items
+                        .iter()
+                        .filter_map(|t| album_track_entry(t, cover.as_deref()))
+                        .collect::<Vec<_>>(),
+                    read,
+                )
+            }
+            _ => {
+                let page = client.playlist_items(id, offset).await?;
+                total = page.total;
+                (playlist_page_entries(&page.items), page.items.len())
+            }
+        };
+
+        entries.extend(found);
+        offset += read;
+        if read == 0 || offset >= total {
+            break;
+        }
+    }
+
+    entries.truncate(limit);
+    Ok((entries, total.max(entries.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::{
+        fake_program, fake_program_args, serve_in_turn, serve_json_heads, serve_json_in_turn,
+    };
+
+    const SEARCH_OUTPUT: &str = r#"{"title": "Song One", "uploader": "Artist", "webpage_url": "https://www.youtube.com/watch?v=one", "duration": 215.0}
+not json
+{"title": "Song Two", "url": "https://www.youtube.com/watch?v=two"}
+{"uploader": "No Title", "webpage_url": "https://www.youtube.com/watch?v=three"}
+{"title": "Song Four", "webpage_url": "https://www.youtube.com/watch?v=four"}
+"#;
+
+    #[tokio::test]
+    async fn search_reads_results_from_youtube_dl() {
+        let ytdl = fake_program("youtube-dl-search", SEARCH_OUTPUT);
+
+        let results = search_with(ytdl.to_str().unwrap(), "some query", SearchSite::YouTube, 5)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fake_program_args(&ytdl),
+            vec![
+                "-j",
+                "--no-playlist",
+                "--ignore-errors",
+                "ytsearch5:some query"
+            ]
+        );
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].title, "Song One");
+        assert_eq!(results[0].uploader.as_deref(), Some("Artist"));
+        assert_eq!(results[0].url, "https://www.youtube.com/watch?v=one");
+        assert_eq!(results[0].duration, Some(Duration::from_secs(215)));
+        // falls back to `url` when there's no page url
+        assert_eq!(results[1].url, "https://www.youtube.com/watch?v=two");
+        assert_eq!(results[1].duration, None);
+        assert_eq!(results[2].title, "Song Four");
+    }
+
+    #[tokio::test]
+    async fn search_stops_at_the_count() {
+        let ytdl = fake_program("youtube-dl-search-count", SEARCH_OUTPUT);
+
+        let results = search_with(ytdl.to_str().unwrap(), "other", SearchSite::SoundCloud, 1)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fake_program_args(&ytdl).last().map(|a| a.as_str()),
+            Some("scsearch1:other")
+        );
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].title, "Song One");
+    }
+
+    #[tokio::test]
+    async fn search_with_no_output_finds_nothing() {
+        let ytdl = fake_program("youtube-dl-search-empty", "");
+
+        let results = search_with(ytdl.to_str().unwrap(), "nothing", SearchSite::YouTube, 3)
+            .await
+            .unwrap();
+
+        assert!(results.is_empty());
+    }
+
+    #[tokio::test]
+    async fn playlist_entries_reads_a_flat_playlist() {
+        let ytdl = fake_program(
+            "youtube-dl-playlist",
+            r#"{"_type": "playlist", "title": "Mix", "entries": [
+                {"_type": "url", "url": "dQw4w9WgXcQ", "title": "First"},
+                {"_type": "url", "url": "https://soundcloud.com/artist/second", "title": "Second"},
+                {"_type": "url", "title": "No Url"},
+                {"_type": "url", "url": "abc123"}
+            ]}"#,
+        );
+
+        let entries = playlist_entries_with(
+            ytdl.to_str().unwrap(),
+            "https://www.youtube.com/playlist?list=PL1",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            fake_program_args(&ytdl),
+            vec![
+                "-J",
+                "--flat-playlist",
+                "--yes-playlist",
+                "https://www.youtube.com/playlist?list=PL1"
+            ]
+        );
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries[0].url,
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ"
+        );
+        assert_eq!(entries[0].title.as_deref(), Some("First"));
+        assert_eq!(entries[1].url, "https://soundcloud.com/artist/second");
+        assert_eq!(entries[2].url, "https://www.youtube.com/watch?v=abc123");
+        assert_eq!(entries[2].title, None);
+    }
+
+    #[tokio::test]
+    async fn playlist_entries_refuses_what_isnt_a_playlist() {
+        let ytdl = fake_program("youtube-dl-not-playlist", r#"{"title": "Just a video"}"#);
+        let single = playlist_entries_with(
+            ytdl.to_str().unwrap(),
+            "https://www.youtube.com/playlist?list=PL2",
+        )
+        .await;
+
+        let ytdl = fake_program(
+            "youtube-dl-broken-playlist",
+            "ERROR: this playlist does not exist",
+        );
+        let broken = playlist_entries_with(
+            ytdl.to_str().unwrap(),
+            "https://www.youtube.com/playlist?list=PL3",
+        )
+        .await;
+
+        assert!(single.is_err());
+        assert!(broken.is_err());
+    }
+
+    #[test]
+    fn playlist_urls() {
+        assert!(is_playlist_url("https://www.youtube.com/playlist?list=PL1"));
+        assert!(is_playlist_url(
+            "https://music.youtube.com/watch?v=a&list=PL1"
+        ));
+        assert!(is_playlist_url("https://soundcloud.com/artist/sets/album"));
+        assert!(!is_playlist_url("https://www.youtube.com/watch?v=a"));
+        assert!(!is_playlist_url("https://soundcloud.com/artist/track"));
+        assert!(!is_playlist_url("https://example.com/list=1"));
+    }
+
+    #[test]
+    fn parses_m3u() {
+        let entries = parse_m3u(
+            "#EXTM3U
+#EXTINF:123, First Song
+https://example.com/one.mp3
+
+#EXTINF:-1,
+http://example.com/radio
+#EXTINF:60,Local File
+/home/someone/music/local.mp3
+file:///etc/passwd
+https://example.com/three.ogg
+",
+        );
+
+        let urls = entries.iter().map(|e| e.url.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            vec![
+                "https://example.com/one.mp3",
+                "http://example.com/radio",
+                "https://example.com/three.ogg"
+            ]
+        );
+        assert_eq!(entries[0].title.as_deref(), Some("First Song"));
+        assert_eq!(entries[1].title, None);
+        // a skipped entry's title doesn't carry over to the next one
+        assert_eq!(entries[2].title, None);
+    }
+
+    #[test]
+    fn parses_pls() {
+        let entries = parse_pls(
+            "[playlist]
+File2=https://example.com/two.mp3
+Title2=Second
+File1=http://example.com/one.mp3
+File3=/local/three.mp3
+Title3=Local
+Title1 = First
+NumberOfEntries=3
+Version=2
+",
+        );
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].url, "http://example.com/one.mp3");
+        assert_eq!(entries[0].title.as_deref(), Some("First"));
+        assert_eq!(entries[1].url, "https://example.com/two.mp3");
+        assert_eq!(entries[1].title.as_deref(), Some("Second"));
+    }
+
+    /// A track as the Spotify Web API sends it.
+    fn spotify_track(id: &str, name: &str, local: bool) -> Value {
+        let external_urls = if local {
+            serde_json::json!({})
+        } else {
+            serde_json::json!({ "spotify": format!("https://open.spotify.com/track/{}", id) })
+        };
+        // local tracks don't have an id on Spotify
+        let track_id = if local { Value::Null } else { Value::from(id) };
+
+        serde_json::json!({
+            "album": {
+                "album_type": "album",
+                "artists": [spotify_artist("1", "First Artist")],
+                "available_markets": ["GB", "US"],
+                "external_urls": { "spotify": "https://open.spotify.com/album/album1" },
+                "href": "https://api.spotify.com/v1/albums/album1",
+                "id": "album1",
+                "images": [
+                    { "height": 640, "url": "https://i.scdn.co/image/large", "width": 640 },
+                    { "height": 64, "url": "https://i.scdn.co/image/small", "width": 64 }
+                ],
+                "name": "The Album",
+                "release_date": "2021-10-01",
+                "release_date_precision": "day",
+                "total_tracks": 10,
+                "type": "album",
+                "uri": "spotify:album:album1"
+            },
+            "artists": [spotify_artist("1", "First Artist"), spotify_artist("2", "Second Artist")],
+            "available_markets": ["GB", "US"],
+            "disc_number": 1,
+            "duration_ms": 215000,
+            "explicit": false,
+            "external_ids": { "isrc": "GBUM71029604" },
+            "external_urls": external_urls,
+            "href": format!("https://api.spotify.com/v1/tracks/{}", id),
+            "id": track_id,
+            "is_local": local,
+            "name": name,
+            "popularity": 50,
+            "preview_url": null,
+            "track_number": 1,
+            "type": "track",
+            "uri": format!("spotify:track:{}", id)
+        })
+    }
+
+    fn spotify_artist(id: &str, name: &str) -> Value {
+        serde_json::json!({
+            "external_urls": { "spotify": format!("https://open.spotify.com/artist/{}", id) },
+            "href": format!("https://api.spotify.com/v1/artists/{}", id),
+            "id": id,
+            "name": name,
+            "type": "artist",
+            "uri": format!("spotify:artist:{}", id)
+        })
+    }
+
+    /// A track as an album's listing has it, without the album.
+    fn spotify_album_track(id: &str, name: &str) -> Value {
+        let mut track = spotify_track(id, name, false);
+        track.as_object_mut().unwrap().remove("album");
+        track
+    }
+
+    fn spotify_playlist_item(track: Value) -> Value {
+        serde_json::json!({
+            "added_at": "2021-10-01T12:00:00Z",
+            "added_by": {
+                "external_urls": { "spotify": "https://open.spotify.com/user/someone" },
+                "href": "https://api.spotify.com/v1/users/someone",
+                "id": "someone",
+                "type": "user",
+                "uri": "spotify:user:someone"
+            },
+            "is_local": false,
+            "primary_color": null,
+            "track": track,
+            "video_thumbnail": { "url": null }
+        })
+    }
+
+    #[test]
+    fn splits_spotify_links() {
+        assert_eq!(
+            spotify_item("https://open.spotify.com/track/abc"),
+            Some(("track", "abc"))
+        );
+        assert_eq!(
+            spotify_item("https://open.spotify.com/album/abc?si=123"),
+            Some(("album", "abc"))
+        );
+        assert_eq!(
+            spotify_item("https://open.spotify.com/intl-de/playlist/abc"),
+            Some(("playlist", "abc"))
+        );
+        assert_eq!(spotify_item("https://open.spotify.com/artist/abc"), None);
+        assert_eq!(spotify_item("https://open.spotify.com/track/"), None);
+        assert_eq!(spotify_item("https://example.com/track/abc"), None);
+        assert!(is_spotify_url("https://open.spotify.com/track/abc"));
+        assert!(!is_spotify_url("https://www.youtube.com/watch?v=abc"));
+    }
+
+    #[test]
+    fn spotify_tracks_become_entries() {
+        let track = serde_json::from_value::<Track>(spotify_track("t1", "A Song", false)).unwrap();
+        let entry = track_entry(&track).unwrap();
+
+        assert_eq!(entry.url, "https://open.spotify.com/track/t1");
+        assert_eq!(entry.title.as_deref(), Some("A Song"));
+        assert_eq!(entry.artist.as_deref(), Some("First Artist, Second Artist"));
+        assert_eq!(
+            entry.thumbnail.as_deref(),
+            Some("https://i.scdn.co/image/large")
+        );
+
+        let local = serde_json::from_value::<Track>(spotify_track("t2", "Local", true)).unwrap();
+        assert!(track_entry(&local).is_none());
+    }
+
+    #[test]
+    fn spotify_album_tracks_get_the_cover() {
+        let track =
+            serde_json::from_value::<TrackSimplified>(spotify_album_track("t1", "Album Song"))
+                .unwrap();
+
+        let entry = album_track_entry(&track, Some("https://i.scdn.co/image/cover")).unwrap();
+        assert_eq!(entry.url, "https://open.spotify.com/track/t1");
+        assert_eq!(entry.title.as_deref(), Some("Album Song"));
+        assert_eq!(
+            entry.thumbnail.as_deref(),
+            Some("https://i.scdn.co/image/cover")
+        );
+        assert_eq!(album_track_entry(&track, None).unwrap().thumbnail, None);
+    }
+
+    #[test]
+    fn spotify_playlist_pages_skip_what_cant_be_played() {
+        let items = serde_json::from_value::<Vec<PlaylistItem>>(serde_json::json!([
+            spotify_playlist_item(spotify_track("t1", "One", false)),
+            // removed from Spotify
+            spotify_playlist_item(Value::Null),
+            spotify_playlist_item(spotify_track("t3", "Local", true)),
+            spotify_playlist_item(spotify_track("t4", "Four", false)),
+        ]))
+        .unwrap();
+
+        let entries = playlist_page_entries(&items);
+        let titles = entries
+            .iter()
+            .filter_map(|e| e.title.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(titles, vec!["One", "Four"]);
+    }
+
+    /// A catalog for the mock Web API at `url`.
+    fn test_catalog(url: &str) -> SpotifyCatalog {
+        SpotifyCatalog::new(url, "test-token".to_string())
+    }
+
+    fn spotify_page(items: Vec<Value>, offset: usize, total: usize) -> String {
+        serde_json::json!({
+            "href": "https://api.spotify.com/v1/playlists/p1/tracks",
+            "items": items,
+            "limit": SPOTIFY_PAGE_SIZE,
+            "next": null,
+            "offset": offset,
+            "previous": null,
+            "total": total
+        })
+        .to_string()
+    }
+
+    /// A playlist page holding tracks `from..to`.
+    fn spotify_playlist_page(from: usize, to: usize, total: usize) -> String {
+        let items = (from..to)
+            .map(|i| {
+                spotify_playlist_item(spotify_track(&format!("t{}", i), &i.to_string(), false))
+            })
+            .collect();
+
+        spotify_page(items, from, total)
+    }
+
+    #[tokio::test]
+    async fn resolves_spotify_tracks() {
+        let track = spotify_track("t1", "A Song", false).to_string();
+        let (url, requests) = serve_json_heads(&[&track]).await;
+
+        let (entries, total) = spotify_entries_from(
+            &test_catalog(&url),
+            "https://open.spotify.com/track/t1?si=x",
+            10,
+        )
+        .await
+        .unwrap();
+
+        let requests = requests.await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("GET /tracks/t1 HTTP/1.1\r\n"));
+        assert!(
+            requests[0]
+                .lines()
+                .any(|h| h.eq_ignore_ascii_case("authorization: Bearer test-token")),
+            "the token should be sent with {}",
+            requests[0]
+        );
+        assert_eq!(total, 1);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].url, "https://open.spotify.com/track/t1");
+        assert_eq!(entries[0].title.as_deref(), Some("A Song"));
+    }
+
+    #[tokio::test]
+    async fn resolves_spotify_albums_with_their_cover() {
+        let album = r#"{"name": "The Album", "images": [{"height": 640, "url": "https://i.scdn.co/image/cover", "width": 640}]}"#;
+        let tracks = spotify_page(
+            vec![
+                spotify_album_track("t1", "One"),
+                spotify_album_track("t2", "Two"),
+            ],
+            0,
+            2,
+        );
+        let (url, requests) = serve_json_in_turn(&[album, &tracks]).await;
+
+        let (entries, total) =
+            spotify_entries_from(&test_catalog(&url), "https://open.spotify.com/album/a1", 10)
+                .await
+                .unwrap();
+
+        assert_eq!(
+            requests.await.unwrap(),
+            vec![
+                "GET /albums/a1 HTTP/1.1",
+                "GET /albums/a1/tracks?limit=50&offset=0 HTTP/1.1"
+            ]
+        );
+        assert_eq!(total, 2);
+        let titles = entries
+            .iter()
+            .filter_map(|e| e.title.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(titles, vec!["One", "Two"]);
+        assert!(entries
+            .iter()
+            .all(|e| e.thumbnail.as_deref() == Some("https://i.scdn.co/image/cover")));
+    }
+
+    #[tokio::test]
+    async fn pages_through_spotify_playlists() {
+        let first = spotify_playlist_page(0, SPOTIFY_PAGE_SIZE, 60);
+        let second = spotify_playlist_page(SPOTIFY_PAGE_SIZE, 60, 60);
+        let (url, requests) = serve_json_in_turn(&[&first, &second]).await;
+
+        let (entries, total) = spotify_entries_from(
+            &test_catalog(&url),
+            "https://open.spotify.com/playlist/p1",
+            100,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            requests.await.unwrap(),
+            vec![
+                "GET /playlists/p1/tracks?limit=50&offset=0&additional_types=track HTTP/1.1",
+                "GET /playlists/p1/tracks?limit=50&offset=50&additional_types=track HTTP/1.1"
+            ]
+        );
+        assert_eq!(total, 60);
+        assert_eq!(entries.len(), 60);
+        assert_eq!(entries[0].title.as_deref(), Some("0"));
+        assert_eq!(entries[59].title.as_deref(), Some("59"));
+    }
+
+    #[tokio::test]
+    async fn stops_reading_spotify_playlists_at_the_limit() {
+        let first = spotify_playlist_page(0, SPOTIFY_PAGE_SIZE, 120);
+        let (url, requests) = serve_json_in_turn(&[&first]).await;
+
+        let (entries, total) = spotify_entries_from(
+            &test_catalog(&url),
+            "https://open.spotify.com/playlist/p1",
+            30,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(requests.await.unwrap().len(), 1);
+        assert_eq!(total, 120);
+        assert_eq!(entries.len(), 30);
+    }
+
+    #[tokio::test]
+    async fn spotify_errors_are_passed_on() {
+        let answer = |status: &str| {
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{{}}",
+                status
+            )
+        };
+        let (url, requests) = serve_in_turn(
+            "127.0.0.1:0",
+            vec![
+                answer("404 Not Found"),
+                answer("429 Too Many Requests"),
+                answer("401 Unauthorized"),
+            ],
+        )
+        .await;
+        let catalog = test_catalog(&url);
+
+        let missing =
+            spotify_entries_from(&catalog, "https://open.spotify.com/track/gone", 10).await;
+        let busy = spotify_entries_from(&catalog, "https://open.spotify.com/album/a1", 10).await;
+        let refused =
+            spotify_entries_from(&catalog, "https://open.spotify.com/playlist/p1", 10).await;
+
+        assert_eq!(requests.await.unwrap().len(), 3);
+        assert_eq!(
+            missing.unwrap_err(),
+            "I couldn't get that from Spotify. Is it public?"
+        );
+        assert_eq!(
+            busy.unwrap_err(),
+            "Spotify is getting too many requests, try again in a bit."
+        );
+        assert_eq!(refused.unwrap_err(), "I couldn't reach Spotify right now.");
+    }
+
+    #[tokio::test]
+    async fn spotify_entries_refuses_other_links() {
+        // nothing's listening here, so any request would fail differently
+        let catalog = test_catalog("http://127.0.0.1:9");
+
+        assert_eq!(
+            spotify_entries_from(&catalog, "https://open.spotify.com/show/abc", 10)
+                .await
+                .unwrap_err(),
+            "That isn't a Spotify link I know."
+        );
+        assert_eq!(
+            spotify_entries_from(&catalog, "https://example.com/track/abc", 10)
+                .await
+                .unwrap_err(),
+            "That isn't a Spotify link I know."
+        );
+    }
+
+    #[tokio::test]
+    async fn resolves_audius_tracks_with_their_details() {
+        let track = r#"{"data": {
+            "id": "D7KyD",
+            "title": "A Song",
+            "duration": 215,
+            "artwork": {"150x150": "https://audius.test/150.jpg", "480x480": "https://audius.test/480.jpg"},
+            "user": {"handle": "artist", "name": "The Artist"}
+        }}"#;
+        let (url, requests) = serve_json_in_turn(&[track]).await;
+
+        let resolved = audius_track_from(
+            &reqwest::Client::new(),
+            url.trim_end_matches('/'),
+            "https://audius.co/artist/a-song",
+        )
+        .await;
+
+        assert_eq!(
+            requests.await.unwrap(),
+            vec!["GET /v1/resolve?url=https%3A%2F%2Faudius.co%2Fartist%2Fa-song&app_name=maki HTTP/1.1"]
+        );
+        let resolved = resolved.unwrap();
+        assert_eq!(
+            resolved.stream_url,
+            format!("{}v1/tracks/D7KyD/stream?app_name=maki", url)
+        );
+
+        let mut metadata = Metadata::default();
+        resolved.fill_metadata(&mut metadata);
+        assert_eq!(metadata.title.as_deref(), Some("A Song"));
+        assert_eq!(metadata.artist.as_deref(), Some("The Artist"));
+        assert_eq!(
+            metadata.thumbnail.as_deref(),
+            Some("https://audius.test/480.jpg")
+        );
+        assert_eq!(metadata.duration, Some(Duration::from_secs(215)));
+        assert_eq!(
+            metadata.source_url.as_deref(),
+            Some("https://audius.co/artist/a-song")
+        );
+    }
+}
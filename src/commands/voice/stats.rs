@@ -0,0 +1,459 @@
+use serenity::async_trait;
+use serenity::framework::standard::{macros::command, Args, CommandError, CommandResult};
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use chrono::{NaiveDate, Utc};
+use parking_lot::Mutex;
+use songbird::{Event, EventContext, EventHandler as VoiceEventHandler};
+use sqlx::PgPool;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    commands::voice::WHITELISTED_GUILDS_CHECK, keys::ConnectionPool, utils::time::format_duration,
+};
+
+/// How often stats are written to the database while the bot is in voice.
+pub const STATS_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Activity {
+    present_since: Option<Instant>,
+    speaking_since: Option<Instant>,
+    /// Time which hasn't been written to the database yet.
+    talk: Duration,
+    channel: Duration,
+}
+
+impl Activity {
+    /// Moves time from whatever is going on right now into the unwritten totals.
+    fn settle(&mut self, now: Instant) {
+        if let Some(since) = self.present_since.as_mut() {
+            self.channel += now - *since;
+            *since = now;
+        }
+        if let Some(since) = self.speaking_since.as_mut() {
+            self.talk += now - *since;
+            *since = now;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Tracker {
+    users: HashMap<UserId, Activity>,
+    peak_speakers: usize,
+}
+
+/// Keeps track of talk time, time in the channel and peak concurrent speakers for one voice session.
+///
+/// Fed by the call's [`Receiver`](super::Receiver), and written to the database every
+/// [`STATS_FLUSH_INTERVAL`] by a [`StatsFlusher`].
+pub struct VoiceStats {
+    guild_id: GuildId,
+    pool: PgPool,
+    tracker: Mutex<Tracker>,
+    /// The database id of this session, made on the first write.
+    session: tokio::sync::Mutex<Option<i32>>,
+}
+
+impl VoiceStats {
+    pub fn new(guild_id: GuildId, pool: PgPool) -> Self {
+        Self {
+            guild_id,
+            pool,
+            tracker: Mutex::new(Tracker::default()),
+            session: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn joined(&self, user_id: UserId) {
+        let mut tracker = self.tracker.lock();
+        let activity = tracker.users.entry(user_id).or_default();
+        activity.present_since.get_or_insert_with(Instant::now);
+    }
+
+    pub fn left(&self, user_id: UserId) {
+        let mut tracker = self.tracker.lock();
+        if let Some(activity) = tracker.users.get_mut(&user_id) {
+            activity.settle(Instant::now());
+            activity.present_since = None;
+            activity.speaking_since = None;
+        }
+    }
+
+    pub fn speaking(&self, user_id: UserId, speaking: bool) {
+        let now = Instant::now();
+        let mut tracker = self.tracker.lock();
+
+        let activity = tracker.users.entry(user_id).or_default();
+        // in case they got in without a connect event being seen
+        activity.present_since.get_or_insert(now);
+        if speaking {
+            activity.speaking_since.get_or_insert(now);
+        } else {
+            activity.settle(now);
+            activity.speaking_since = None;
+        }
+
+        let speakers = tracker
+            .users
+            .values()
+            .filter(|a| a.speaking_since.is_some())
+            .count();
+        tracker.peak_speakers = tracker.peak_speakers.max(speakers);
+    }
+
+    /// Writes everything since the last flush to the database.
+    pub async fn flush(&self) -> Result<(), sqlx::Error> {
+        let mut session = self.session.lock().await;
+
+        let (deltas, peak) = {
+            let now = Instant::now();
+            let mut tracker = self.tracker.lock();
+            let deltas = tracker
+                .users
+                .iter_mut()
+                .filter_map(|(user_id, activity)| {
+                    activity.settle(now);
+                    let talk = std::mem::take(&mut activity.talk);
+                    let channel = std::mem::take(&mut activity.channel);
+
+                    if talk.as_millis() == 0 && channel.as_millis() == 0 {
+                        None
+                    } else {
+                        Some((*user_id, talk, channel))
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            (deltas, tracker.peak_speakers as i32)
+        };
+
+        let session_id = match *session {
+            Some(id) => id,
+            None if deltas.is_empty() => return Ok(()),
+            None => {
+                let id = sqlx::query!(
+                    "
+                insert into voice_stat_sessions(guild_id)
+                values($1)
+                returning id
+                ",
+                    self.guild_id.0 as i64
+                )
+                .fetch_one(&self.pool)
+                .await?
+                .id;
+
+                *session = Some(id);
+                id
+            }
+        };
+
+        sqlx::query!(
+            "
+        update voice_stat_sessions
+        set peak_speakers = greatest(peak_speakers, $1)
+        where id = $2
+        ",
+            peak,
+            session_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // time is added up per day, so a long session only counts towards the days it was on
+        let day = Utc::today().naive_utc();
+        let opted_out = opted_out(&self.pool).await?;
+        for (user_id, talk, channel) in deltas {
+            if opted_out.contains(&user_id) {
+                continue;
+            }
+
+            sqlx::query!(
+                "
+            insert into voice_stats(session_id, guild_id, user_id, talk_time, channel_time, day)
+            values($1, $2, $3, $4, $5, $6)
+            on conflict (session_id, user_id, day) do update
+            set talk_time = voice_stats.talk_time + $4,
+                channel_time = voice_stats.channel_time + $5,
+                updated_at = now()
+            ",
+                session_id,
+                self.guild_id.0 as i64,
+                user_id.0 as i64,
+                talk.as_millis() as i64,
+                channel.as_millis() as i64,
+                day
+            )
+            .execute(&self.pool)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Wraps the session up, once the bot leaves.
+    pub async fn end(&self) {
+        {
+            let now = Instant::now();
+            let mut tracker = self.tracker.lock();
+            for activity in tracker.users.values_mut() {
+                activity.settle(now);
+                activity.present_since = None;
+                activity.speaking_since = None;
+            }
+        }
+
+        if let Err(why) = self.flush().await {
+            warn!(
+                "Could not write voice stats for guild {}: {:?}",
+                self.guild_id, why
+            );
+        }
+
+        if let Some(id) = *self.session.lock().await {
+            let _ = sqlx::query!(
+                "
+            update voice_stat_sessions
+            set ended_at = now()
+            where id = $1
+            ",
+                id
+            )
+            .execute(&self.pool)
+            .await;
+        }
+    }
+}
+
+/// Periodically writes a call's [`VoiceStats`] to the database.
+///
+/// The write happens in its own task, so a slow database doesn't hold up the call's other events.
+pub struct StatsFlusher(pub Arc<VoiceStats>);
+
+#[async_trait]
+impl VoiceEventHandler for StatsFlusher {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        let stats = self.0.clone();
+        tokio::spawn(async move {
+            if let Err(why) = stats.flush().await {
+                warn!(
+                    "Could not write voice stats for guild {}: {:?}",
+                    stats.guild_id, why
+                );
+            }
+        });
+
+        None
+    }
+}
+
+async fn opted_out(pool: &PgPool) -> Result<HashSet<UserId>, sqlx::Error> {
+    let users = sqlx::query!(
+        "
+    select user_id
+    from voice_stats_opt_out
+    "
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(users
+        .into_iter()
+        .map(|u| UserId(u.user_id as u64))
+        .collect())
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(whitelisted_guilds)]
+#[aliases(vstats)]
+#[usage("[session|week|all]")]
+#[description("Show who's been talking the most in voice.\nSubcommands: `optout`, `optin`")]
+#[sub_commands(voicestats_optout, voicestats_optin)]
+async fn voicestats(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+
+    let data = ctx.data.read().await;
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    let (period, session, since) = match args.rest().trim().to_lowercase().as_str() {
+        "" | "session" => {
+            let session = sqlx::query!(
+                "
+            select id
+            from voice_stat_sessions
+            where guild_id = $1
+            order by started_at desc
+            limit 1
+            ",
+                guild_id.0 as i64
+            )
+            .fetch_optional(pool)
+            .await?;
+
+            match session {
+                Some(session) => (
+                    "the last session",
+                    Some(session.id),
+                    NaiveDate::from_ymd(1970, 1, 1),
+                ),
+                None => {
+                    msg.channel_id
+                        .say(
+                            &ctx.http,
+                            "There aren't any voice stats for this server yet.",
+                        )
+                        .await?;
+
+                    return Ok(());
+                }
+            }
+        }
+        "week" => (
+            "the past week",
+            None,
+            Utc::today().naive_utc() - chrono::Duration::days(6),
+        ),
+        "all" => ("all time", None, NaiveDate::from_ymd(1970, 1, 1)),
+        _ => {
+            return Err(CommandError::from(
+                "h-You can see stats for the `session`, the `week` or `all` time.",
+            ))
+        }
+    };
+
+    let users = sqlx::query!(
+        "
+    select user_id, sum(talk_time)::bigint as \"talk_time!\", sum(channel_time)::bigint as \"channel_time!\"
+    from voice_stats
+    where guild_id = $1
+    and ($2::int4 is null or session_id = $2)
+    and day >= $3
+    and user_id not in (select user_id from voice_stats_opt_out)
+    group by user_id
+    order by 2 desc
+    limit 10
+    ",
+        guild_id.0 as i64,
+        session,
+        since
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let peak = sqlx::query!(
+        "
+    select max(peak_speakers) as peak
+    from voice_stat_sessions
+    where guild_id = $1
+    and ($2::int4 is null or id = $2)
+    and (coalesce(ended_at, now()) at time zone 'utc')::date >= $3
+    ",
+        guild_id.0 as i64,
+        session,
+        since
+    )
+    .fetch_one(pool)
+    .await?
+    .peak
+    .unwrap_or(0);
+
+    let mut description = String::new();
+    for (i, user) in users.iter().enumerate() {
+        description.push_str(&format!(
+            "`{}.` {} talked for {}, in voice for {}\n",
+            i + 1,
+            UserId(user.user_id as u64).mention(),
+            format_duration(Duration::from_millis(user.talk_time as u64)),
+            format_duration(Duration::from_millis(user.channel_time as u64))
+        ));
+    }
+    if description.is_empty() {
+        description = "Nobody's said anything yet.".to_string();
+    }
+
+    msg.channel_id
+        .send_message(&ctx.http, |m| {
+            m.embed(|e| {
+                e.title(format!("🎙️ Voice stats for {}", period))
+                    .color(0xb90000)
+                    .description(description)
+                    .footer(|f| {
+                        f.text(format!(
+                            "Most people talking at once: {} · updated every minute",
+                            peak
+                        ))
+                    })
+            })
+        })
+        .await?;
+
+    Ok(())
+}
+
+#[command("optout")]
+#[description("Stop tracking your voice activity, and forget what was already tracked.")]
+async fn voicestats_optout(ctx: &Context, msg: &Message) -> CommandResult {
+    let data = ctx.data.read().await;
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    sqlx::query!(
+        "
+    insert into voice_stats_opt_out(user_id)
+    values($1)
+    on conflict (user_id) do nothing
+    ",
+        msg.author.id.0 as i64
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query!(
+        "
+    delete from voice_stats
+    where user_id = $1
+    ",
+        msg.author.id.0 as i64
+    )
+    .execute(pool)
+    .await?;
+
+    msg.channel_id
+        .say(
+            &ctx.http,
+            "Your voice activity won't be tracked anymore, and what was already tracked is gone.",
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[command("optin")]
+#[description("Start tracking your voice activity again.")]
+async fn voicestats_optin(ctx: &Context, msg: &Message) -> CommandResult {
+    let data = ctx.data.read().await;
+    let pool = data.get::<ConnectionPool>().unwrap();
+
+    sqlx::query!(
+        "
+    delete from voice_stats_opt_out
+    where user_id = $1
+    ",
+        msg.author.id.0 as i64
+    )
+    .execute(pool)
+    .await?;
+
+    msg.channel_id
+        .say(&ctx.http, "Your voice activity will be tracked again.")
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,186 @@
+use serenity::async_trait;
+use serenity::model::prelude::*;
+
+use parking_lot::Mutex;
+use songbird::{
+    tracks::{PlayMode, TrackHandle},
+    Event, EventContext, EventHandler as VoiceEventHandler,
+};
+use sqlx::PgPool;
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::utils::queue::TrackQueue;
+
+/// How often the current track is checked for whether the next one should be readied.
+pub const TRANSITION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_PRELOAD: Duration = Duration::from_secs(10);
+/// How long each volume step of a crossfade lasts.
+const RAMP_STEP: Duration = Duration::from_millis(100);
+pub const MAX_CROSSFADE: i32 = 12;
+
+/// How long before the end of a track the next one starts loading, which can be set with `PRELOAD_SECONDS`.
+fn preload_time() -> Duration {
+    env::var("PRELOAD_SECONDS")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_PRELOAD)
+}
+
+/// Returns how long the guild has tracks overlap for, if it has crossfade turned on.
+pub async fn crossfade(pool: &PgPool, guild_id: GuildId) -> Result<Option<Duration>, sqlx::Error> {
+    let guild = sqlx::query!(
+        "
+    select crossfade
+    from guilds
+    where id = $1
+    ",
+        guild_id.0 as i64
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(guild
+        .and_then(|g| g.crossfade)
+        .filter(|c| *c > 0)
+        .map(|c| Duration::from_secs(c as u64)))
+}
+
+/// Fades one track out while fading the other in.
+async fn ramp(out: TrackHandle, into: TrackHandle, length: Duration) {
+    let volume = out.get_info().await.map_or(1.0, |state| state.volume);
+    let steps = (length.as_millis() / RAMP_STEP.as_millis()).max(1);
+
+    for step in 1..=steps {
+        let progress = step as f32 / steps as f32;
+        let _ = out.set_volume(volume * (1.0 - progress));
+        let _ = into.set_volume(volume * progress);
+
+        tokio::time::sleep(RAMP_STEP).await;
+    }
+}
+
+/// Readies the next track before the current one ends, and crossfades between them if the guild wants that.
+///
+/// Each check runs in its own task, so asking the driver about the track and reading the
+/// settings doesn't hold up the call's other events.
+pub struct Transitioner(Arc<TransitionCheck>);
+
+struct TransitionCheck {
+    guild_id: GuildId,
+    queue: TrackQueue,
+    pool: PgPool,
+    preload: Duration,
+    /// The track the crossfade setting was last read for, and what it was.
+    crossfade: Mutex<(Option<TrackHandle>, Option<Duration>)>,
+    /// Set while a check is running, so a slow one doesn't get another started alongside it.
+    checking: AtomicBool,
+}
+
+impl Transitioner {
+    pub fn new(guild_id: GuildId, queue: TrackQueue, pool: PgPool) -> Self {
+        Self(Arc::new(TransitionCheck {
+            guild_id,
+            queue,
+            pool,
+            preload: preload_time(),
+            crossfade: Mutex::new((None, None)),
+            checking: AtomicBool::new(false),
+        }))
+    }
+}
+
+impl TransitionCheck {
+    /// The crossfade setting, read again whenever a new track starts so changes apply from the next one.
+    async fn crossfade_for(&self, current: &TrackHandle) -> Option<Duration> {
+        {
+            let cached = self.crossfade.lock();
+            if cached
+                .0
+                .as_ref()
+                .map_or(false, |c| c.uuid() == current.uuid())
+            {
+                return cached.1;
+            }
+        }
+
+        let length = match crossfade(&self.pool, self.guild_id).await {
+            Ok(length) => length,
+            Err(why) => {
+                warn!(
+                    "Could not read the crossfade for guild {}: {:?}",
+                    self.guild_id, why
+                );
+                None
+            }
+        };
+        *self.crossfade.lock() = (Some(current.clone()), length);
+
+        length
+    }
+
+    /// Starts loading the next track, or fading into it, once the current one is close enough to its end.
+    async fn check(&self) {
+        let queue = &self.queue;
+
+        let (current, length, _) = match queue.upcoming() {
+            Some(upcoming) => upcoming,
+            None => return,
+        };
+        let state = match current.get_info().await {
+            Ok(state) if state.playing == PlayMode::Play => state,
+            _ => return,
+        };
+        // streams without a length can't be readied ahead of time
+        let length = match length {
+            Some(length) => length.div_f64(queue.filters().tempo()),
+            None => return,
+        };
+        let remaining = length.checked_sub(state.position).unwrap_or_default();
+
+        let crossfade = self.crossfade_for(&current).await;
+
+        // a crossfade needs the next track ready by the time it starts
+        let preload = self
+            .preload
+            .max(crossfade.unwrap_or_default() + TRANSITION_CHECK_INTERVAL * 2);
+        if remaining <= preload {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.preload_next().await });
+        }
+
+        if let Some(crossfade) = crossfade {
+            if remaining <= crossfade {
+                if let Some((out, into)) = queue.start_crossfade() {
+                    let _ = into.set_volume(0.0);
+                    let _ = into.play();
+                    tokio::spawn(ramp(out, into, remaining));
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl VoiceEventHandler for Transitioner {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        if self.0.checking.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+
+        let check = self.0.clone();
+        tokio::spawn(async move {
+            check.check().await;
+            check.checking.store(false, Ordering::Release);
+        });
+
+        None
+    }
+}
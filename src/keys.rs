@@ -1,4 +1,4 @@
-use serenity::{model::id::GuildId, client::bridge::gateway::ShardManager};
+use serenity::client::bridge::gateway::ShardManager;
 
 use serenity::prelude::*;
 
@@ -10,9 +10,7 @@ use tokio::sync::Mutex;
 
 use chrono::{DateTime, Utc};
 
-use crate::utils::queue::TrackQueue;
-
-use tokio::sync::RwLock;
+use crate::commands::voice::session::SessionManager;
 
 // A container type is created for inserting into the Client's `data`, which
 // allows for data to be accessible across all events and framework commands, or
@@ -40,8 +38,8 @@ impl TypeMapKey for ConnectionPool {
     type Value = PgPool;
 }
 
-pub struct VoiceQueue;
+pub struct VoiceSessions;
 
-impl TypeMapKey for VoiceQueue {
-    type Value = Arc<RwLock<HashMap<GuildId, TrackQueue>>>;
+impl TypeMapKey for VoiceSessions {
+    type Value = Arc<SessionManager>;
 }
@@ -4,14 +4,14 @@ extern crate log;
 #[macro_use]
 extern crate lazy_static;
 
-use songbird::SerenityInit;
+use songbird::{driver::DecodeMode, SerenityInit};
 
 use serenity::{
     async_trait,
     framework::standard::{
         help_commands,
         macros::{group, help, hook},
-        Args, CommandGroup, CommandResult, DispatchError, HelpOptions, StandardFramework,
+        Args, CommandGroup, CommandResult, DispatchError, HelpOptions, Reason, StandardFramework,
     },
     http::Http,
     model::{channel::Message, event::ResumedEvent, gateway::Ready, id::UserId, prelude::GuildId, prelude::GatewayIntents},
@@ -19,7 +19,10 @@ use serenity::{
 use std::{
     collections::{HashMap, HashSet},
     env,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 use chrono::Utc;
@@ -43,14 +46,28 @@ use commands::moderator::*;
 use commands::music::lastfm::*;
 use commands::music::spotify::*;
 use commands::settings::*;
+use commands::voice::allowlist::*;
+use commands::voice::controls::*;
+use commands::voice::edit::*;
+use commands::voice::filters::*;
+use commands::voice::history::*;
+use commands::voice::library::*;
 use commands::voice::play::*;
+use commands::voice::record::*;
+use commands::voice::stats::*;
+use commands::voice::restore::offer_restore;
+use commands::voice::scrobble::retry_scrobbles;
+use commands::voice::session::SessionManager;
 
 use utils::db::get_pool;
 
 // This imports `typemap`'s `Key` as `TypeMapKey`.
 use serenity::prelude::*;
 
-struct Handler;
+struct Handler {
+    // only offer to restore voice sessions on the first ready, not on reconnects
+    restore_offered: AtomicBool,
+}
 
 #[async_trait]
 impl EventHandler for Handler {
@@ -78,6 +95,10 @@ impl EventHandler for Handler {
             }
             None => error!("Unable to insert boot time into client data."),
         };
+
+        if !self.restore_offered.swap(true, Ordering::SeqCst) {
+            tokio::spawn(offer_restore(ctx.clone(), ready.user.id));
+        }
     }
 
     async fn resume(&self, _: Context, _: ResumedEvent) {
@@ -86,7 +107,7 @@ impl EventHandler for Handler {
 }
 
 #[group]
-#[commands(activity, nickname, quit, shorten)]
+#[commands(activity, nickname, quit, shorten, musicguilds, rescan)]
 #[description = "admin/bot management stuff."]
 struct Admin;
 
@@ -116,7 +137,11 @@ struct Gif;
 struct Music;
 
 #[group]
-#[commands(join, play, skip, queue, leave)]
+#[commands(
+    join, play, skip, queue, leave, loop_mode, remove, removemine, move_track, shuffle, clear,
+    dedupe, pause, resume, seek, nowplaying, filter, history, previous, mostplayed, record,
+    voicestats
+)]
 #[description = "play music in a voice channel."]
 struct Voice;
 
@@ -254,6 +279,10 @@ async fn dispatch_error(ctx: &Context, msg: &Message, error: DispatchError, _cmd
             };
             let _ = msg.channel_id.say(&ctx.http, ret).await;
         }
+        DispatchError::CheckFailed(_, Reason::User(reason))
+        | DispatchError::CheckFailed(_, Reason::UserAndLog { user: reason, .. }) => {
+            let _ = msg.channel_id.say(&ctx.http, reason).await;
+        }
         _ => {
             error!("Dispatch error: {:?}", error);
         }
@@ -391,9 +420,12 @@ async fn main() {
         .group(&SETTINGS_GROUP);
 
     let mut client = Client::builder(&token)
-        .event_handler(Handler)
+        .event_handler(Handler {
+            restore_offered: AtomicBool::new(false),
+        })
         .framework(framework)
-        .register_songbird()
+        // incoming audio is only decoded while it's being recorded, see `Recorder`
+        .register_songbird_from_config(songbird::Config::default().decode_mode(DecodeMode::Decrypt))
         .intents({
             let mut intents = GatewayIntents::all();
             intents.remove(GatewayIntents::GUILD_PRESENCES);
@@ -410,7 +442,9 @@ async fn main() {
         data.insert::<ShardManagerContainer>(Arc::clone(&client.shard_manager));
         let pool = get_pool().await.unwrap();
         data.insert::<ConnectionPool>(pool.clone());
-        data.insert::<VoiceQueue>(Arc::new(tokio::sync::RwLock::new(HashMap::default())));
+        data.insert::<VoiceSessions>(Arc::new(SessionManager::default()));
+
+        tokio::spawn(retry_scrobbles(pool));
     }
 
     // clone for use inside process
@@ -1,4 +1,8 @@
 pub mod db;
 pub mod html;
 pub mod user;
-pub mod queue;
\ No newline at end of file
+pub mod queue;
+pub mod queue_store;
+pub mod time;
+#[cfg(test)]
+pub mod testing;
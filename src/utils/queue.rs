@@ -1,7 +1,13 @@
 // This file was taken from https://github.com/serenity-rs/songbird/blob/next/src/tracks/queue.rs.
 
 use parking_lot::Mutex;
-use serenity::async_trait;
+use serenity::{
+    async_trait,
+    model::{
+        id::{ChannelId, GuildId, UserId},
+        misc::Mentionable,
+    },
+};
 use songbird::{
     //driver::Driver,
     events::{Event, EventContext, EventData, EventHandler, TrackEvent},
@@ -10,8 +16,27 @@ use songbird::{
     Call,
 };
 
-use std::{collections::VecDeque, ops::Deref, sync::Arc};
-use tokio::sync::MutexGuard;
+use chrono::{DateTime, Utc};
+use chrono_humanize::HumanTime;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    ops::Deref,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+use tokio::sync::{mpsc, watch, Mutex as AsyncMutex, MutexGuard};
+
+use crate::{
+    commands::voice::{
+        filters::{filtered_source, Filters},
+        play::get_source,
+    },
+    utils::queue_store::{PlayedTrack, QueueSnapshot, QueueStore, StoredTrack},
+};
+
+/// How many finished tracks each queue remembers.
+pub const HISTORY_LENGTH: usize = 50;
 
 /// A simple queue for several audio sources, designed to
 /// play in sequence.
@@ -26,11 +51,43 @@ pub struct TrackQueue {
     inner: Arc<Mutex<TrackQueueCore>>,
 }
 
+/// Who asked for a track, and where and when they did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub user: UserId,
+    /// The text channel the track was asked for in.
+    pub channel: ChannelId,
+    pub at: DateTime<Utc>,
+}
+
+impl Request {
+    /// A request made just now.
+    pub fn new(user: UserId, channel: ChannelId) -> Self {
+        Self {
+            user,
+            channel,
+            at: Utc::now(),
+        }
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in {}, {}",
+            self.user.mention(),
+            self.channel.mention(),
+            HumanTime::from(self.at - Utc::now())
+        )
+    }
+}
+
 /// Reference to a track which is known to be part of a queue.
 ///
 /// Instances *should not* be moved from one queue to another.
 #[derive(Debug)]
-pub struct Queued(TrackHandle, Metadata);
+pub struct Queued(TrackHandle, Metadata, Request);
 
 impl Deref for Queued {
     type Target = TrackHandle;
@@ -45,6 +102,48 @@ impl Queued {
     pub fn handle(&self) -> TrackHandle {
         self.0.clone()
     }
+
+    /// Metadata of the source this track was created from.
+    pub fn metadata(&self) -> &Metadata {
+        &self.1
+    }
+
+    /// The user who added this track.
+    pub fn requester(&self) -> UserId {
+        self.2.user
+    }
+
+    /// Who added this track, and where and when they did.
+    pub fn request(&self) -> &Request {
+        &self.2
+    }
+}
+
+/// What happens to a track once it finishes playing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopMode {
+    /// The track is dropped and the queue moves on.
+    Off,
+    /// The current track repeats until it is skipped.
+    Track,
+    /// The track is recreated from its source and put back at the end of the queue.
+    Queue,
+}
+
+impl Default for LoopMode {
+    fn default() -> Self {
+        LoopMode::Off
+    }
+}
+
+impl fmt::Display for LoopMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoopMode::Off => write!(f, "off"),
+            LoopMode::Track => write!(f, "track"),
+            LoopMode::Queue => write!(f, "queue"),
+        }
+    }
 }
 
 #[derive(Debug, Default)]
@@ -55,7 +154,33 @@ impl Queued {
 ///
 /// [`TrackQueue`]: TrackQueue
 struct TrackQueueCore {
+    /// The guild the queue plays in, which is only known once it has a store.
+    guild_id: GuildId,
     tracks: VecDeque<Queued>,
+    store: Option<watch::Sender<QueueSnapshot>>,
+    /// Whether changes are kept from the store for now, while a saved queue is being restored.
+    store_held: bool,
+    loop_mode: LoopMode,
+    /// Whether upcoming tracks take turns between the people who added them.
+    fair: bool,
+    call: Option<Weak<AsyncMutex<Call>>>,
+    skip_votes: HashSet<UserId>,
+    filters: Filters,
+    /// Tracks which have been made with the current filters.
+    filtered: Vec<TrackHandle>,
+    /// Recreated tracks, each with the track it was first queued as.
+    origins: Vec<(TrackHandle, TrackHandle)>,
+    /// The next track, once it has started loading ahead of time.
+    preloaded: Option<TrackHandle>,
+    /// The current track, once it has started fading into the next one.
+    crossfading: Option<TrackHandle>,
+    /// Tracks which finished playing, newest first.
+    history: VecDeque<PlayedTrack>,
+    history_store: Option<mpsc::UnboundedSender<PlayedTrack>>,
+    /// Told about each track the queue moves on to, or `None` once it runs out.
+    announcer: Option<mpsc::UnboundedSender<Option<StoredTrack>>>,
+    /// Told about the last track played whenever the queue runs out.
+    run_out: Option<mpsc::UnboundedSender<StoredTrack>>,
 }
 
 struct QueueHandler {
@@ -87,17 +212,54 @@ impl EventHandler for QueueHandler {
             return None;
         }
 
-        let _old = inner.tracks.pop_front();
+        let old = inner.tracks.pop_front();
+        inner.skip_votes.clear();
+        if let Some(old) = &old {
+            inner.remember(old);
+        }
+        let ended = old.as_ref().map(StoredTrack::from);
 
         info!("Queued track ended: {:?}.", ctx);
         info!("{} tracks remain.", inner.tracks.len());
 
+        if inner.loop_mode == LoopMode::Queue {
+            if let (Some(old), Some(call)) = (old, inner.call.as_ref().and_then(Weak::upgrade)) {
+                let queue = TrackQueue {
+                    inner: self.remote_lock.clone(),
+                };
+                tokio::spawn(queue.requeue(old, call));
+            }
+        }
+
+        // Tracks are queued without filters, so the next one has to be made again with them
+        // unless it was already preloaded that way.
+        if inner
+            .tracks
+            .front()
+            .map_or(false, |next| inner.needs_filters(next))
+        {
+            let queue = TrackQueue {
+                inner: self.remote_lock.clone(),
+            };
+            tokio::spawn(async move {
+                let _ = queue.recreate_current(Duration::default(), false).await;
+            });
+
+            inner.announce();
+            inner.sync();
+            return None;
+        }
+
         // Keep going until we find one track which works, or we run out.
         let mut keep_looking = true;
         while keep_looking && !inner.tracks.is_empty() {
             if let Some(new) = inner.tracks.front() {
                 keep_looking = new.play().is_err();
 
+                if !keep_looking && inner.loop_mode == LoopMode::Track {
+                    let _ = new.enable_loop();
+                }
+
                 // Discard files which cannot be used for whatever reason.
                 if keep_looking {
                     warn!("Track in Queue couldn't be played...");
@@ -106,6 +268,15 @@ impl EventHandler for QueueHandler {
             }
         }
 
+        inner.announce();
+        // a looping queue is about to get the track back
+        if inner.tracks.is_empty() && inner.loop_mode != LoopMode::Queue {
+            if let (Some(run_out), Some(ended)) = (&inner.run_out, ended) {
+                let _ = run_out.send(ended);
+            }
+        }
+        inner.sync();
+
         None
     }
 }
@@ -115,20 +286,75 @@ impl TrackQueue {
     pub fn new() -> Self {
         Self {
             inner: Arc::new(Mutex::new(TrackQueueCore {
+                guild_id: GuildId::default(),
+                tracks: VecDeque::new(),
+                store: None,
+                store_held: false,
+                loop_mode: LoopMode::Off,
+                fair: false,
+                call: None,
+                skip_votes: HashSet::new(),
+                filters: Filters::default(),
+                filtered: Vec::new(),
+                origins: Vec::new(),
+                preloaded: None,
+                crossfading: None,
+                history: VecDeque::new(),
+                history_store: None,
+                announcer: None,
+                run_out: None,
+            })),
+        }
+    }
+
+    /// Create a new, empty, track queue which mirrors its entries and history into `store`.
+    pub fn with_store(store: QueueStore) -> Self {
+        let history_store = store.spawn_history();
+
+        Self {
+            inner: Arc::new(Mutex::new(TrackQueueCore {
+                guild_id: store.guild_id(),
                 tracks: VecDeque::new(),
+                store: Some(store.spawn()),
+                store_held: false,
+                loop_mode: LoopMode::Off,
+                fair: false,
+                call: None,
+                skip_votes: HashSet::new(),
+                filters: Filters::default(),
+                filtered: Vec::new(),
+                origins: Vec::new(),
+                preloaded: None,
+                crossfading: None,
+                history: VecDeque::new(),
+                history_store: Some(history_store),
+                announcer: None,
+                run_out: None,
             })),
         }
     }
 
+    /// Binds the queue to the call it plays in.
+    ///
+    /// This is needed for tracks to be recreated in [`LoopMode::Queue`].
+    pub fn bind_call(&self, call: &Arc<AsyncMutex<Call>>) {
+        let mut inner = self.inner.lock();
+
+        inner.call = Some(Arc::downgrade(call));
+    }
+
     /// Adds an audio source to the queue, to be played in the channel managed by `handler`.
+    ///
+    /// Returns where in the queue the track was put.
     pub fn add_source(
         &self,
         source: Input,
+        request: Request,
         handler: &mut MutexGuard<Call>,
-    ) {
+    ) -> usize {
         let meta = source.metadata.clone();
         let (audio, _) = tracks::create_player(source);
-        self.add(audio, *meta, handler);
+        self.add(audio, *meta, request, handler)
     }
 
     /// Adds a [`Track`] object to the queue, to be played in the channel managed by `handler`.
@@ -142,22 +368,54 @@ impl TrackQueue {
         &self,
         mut track: Track,
         metadata: Metadata,
+        request: Request,
         handler: &mut MutexGuard<Call>,
-    ) {
-        self.add_raw(&mut track, metadata);
+    ) -> usize {
+        let position = self.add_raw(&mut track, metadata, request);
         handler.play(track);
+
+        position
     }
 
     #[inline]
-    pub(crate) fn add_raw(&self, track: &mut Track, metadata: Metadata) {
+    pub(crate) fn add_raw(&self, track: &mut Track, metadata: Metadata, request: Request) -> usize {
+        self.insert(track, metadata, request, false)
+    }
+
+    /// Adds an audio source to the front of the queue, to be played straight away.
+    ///
+    /// The current track is paused, and carries on from where it was once this one ends.
+    pub fn add_source_front(
+        &self,
+        source: Input,
+        request: Request,
+        handler: &mut MutexGuard<Call>,
+    ) {
+        let meta = source.metadata.clone();
+        let (mut track, _) = tracks::create_player(source);
+        self.insert(&mut track, *meta, request, true);
+        handler.play(track);
+    }
+
+    fn insert(
+        &self,
+        track: &mut Track,
+        metadata: Metadata,
+        request: Request,
+        front: bool,
+    ) -> usize {
         info!("Track added to queue.");
         let remote_lock = self.inner.clone();
         let mut inner = self.inner.lock();
 
         let track_handle = track.handle.clone();
 
-        if !inner.tracks.is_empty() {
+        let plays_now = front || inner.tracks.is_empty();
+        let filtered = plays_now && !inner.filters.is_empty();
+        if !plays_now || filtered {
             track.pause();
+        } else if inner.loop_mode == LoopMode::Track {
+            let _ = track_handle.enable_loop();
         }
 
         let pos = track.position().to_owned();
@@ -171,7 +429,30 @@ impl TrackQueue {
                 pos,
             );
 
-        inner.tracks.push_back(Queued(track_handle, metadata));
+        let position = if front {
+            if let Some(current) = inner.tracks.front() {
+                let _ = current.pause();
+            }
+            inner.skip_votes.clear();
+            0
+        } else if inner.fair && !plays_now {
+            inner.fair_position(request.user)
+        } else {
+            inner.tracks.len()
+        };
+        inner
+            .tracks
+            .insert(position, Queued(track_handle, metadata, request));
+        inner.sync();
+
+        if filtered {
+            let queue = self.clone();
+            tokio::spawn(async move {
+                let _ = queue.recreate_current(Duration::default(), false).await;
+            });
+        }
+
+        position
     }
 
     /// Returns a handle to the currently playing track.
@@ -181,6 +462,27 @@ impl TrackQueue {
         inner.tracks.front().map(|h| h.handle())
     }
 
+    /// Returns the user who added the currently playing track.
+    pub fn current_requester(&self) -> Option<UserId> {
+        let inner = self.inner.lock();
+
+        inner.tracks.front().map(|q| q.requester())
+    }
+
+    /// Returns who added the currently playing track, and where and when they did.
+    pub fn current_request(&self) -> Option<Request> {
+        let inner = self.inner.lock();
+
+        inner.tracks.front().map(|q| *q.request())
+    }
+
+    /// Returns the stored metadata of the currently playing track.
+    pub fn current_metadata(&self) -> Option<Metadata> {
+        let inner = self.inner.lock();
+
+        inner.tracks.front().map(|q| q.metadata().clone())
+    }
+
     /// Attempts to remove a track from the specified index.
     ///
     /// The returned entry can be readded to *this* queue via [`modify_queue`].
@@ -190,6 +492,45 @@ impl TrackQueue {
         self.modify_queue(|vq| vq.remove(index))
     }
 
+    /// Returns how many tracks someone has waiting in the queue, not counting the current one.
+    pub fn pending_for(&self, user: UserId) -> usize {
+        let inner = self.inner.lock();
+
+        inner
+            .tracks
+            .iter()
+            .skip(1)
+            .filter(|q| q.requester() == user)
+            .count()
+    }
+
+    /// Takes every track someone added out of the queue, apart from the current one.
+    ///
+    /// The removed tracks are stopped.
+    pub fn remove_requested_by(&self, user: UserId) -> Vec<Queued> {
+        let removed = self.modify_queue(|vq| {
+            let mut removed = Vec::new();
+            let mut kept = VecDeque::with_capacity(vq.len());
+            for (i, track) in vq.drain(..).enumerate() {
+                // never pull the current track out from under the player
+                if i != 0 && track.requester() == user {
+                    removed.push(track);
+                } else {
+                    kept.push_back(track);
+                }
+            }
+            *vq = kept;
+            removed
+        });
+
+        for track in &removed {
+            // an error here just means the track is already gone.
+            let _ = track.stop();
+        }
+
+        removed
+    }
+
     /// Returns the number of tracks currently in the queue.
     pub fn len(&self) -> usize {
         let inner = self.inner.lock();
@@ -213,7 +554,14 @@ impl TrackQueue {
         F: FnOnce(&mut VecDeque<Queued>) -> O,
     {
         let mut inner = self.inner.lock();
-        func(&mut inner.tracks)
+        let front = inner.tracks.front().map(|q| q.uuid());
+        let out = func(&mut inner.tracks);
+        if inner.tracks.front().map(|q| q.uuid()) != front {
+            inner.skip_votes.clear();
+        }
+        inner.sync();
+
+        out
     }
 
     /// Pause the track at the head of the queue.
@@ -248,6 +596,9 @@ impl TrackQueue {
             // a difference: an error just implies it's already gone.
             let _ = track.stop();
         }
+
+        inner.skip_votes.clear();
+        inner.sync();
     }
 
     /// Skip to the next track in the queue, if it exists.
@@ -257,6 +608,351 @@ impl TrackQueue {
         inner.stop_current()
     }
 
+    /// Adds a vote to skip the current track, returning every vote cast so far.
+    ///
+    /// Votes are cleared whenever the current track changes.
+    pub fn vote_skip(&self, user: UserId) -> HashSet<UserId> {
+        let mut inner = self.inner.lock();
+
+        inner.skip_votes.insert(user);
+        inner.skip_votes.clone()
+    }
+
+    /// Returns what happens to tracks once they finish.
+    pub fn loop_mode(&self) -> LoopMode {
+        let inner = self.inner.lock();
+
+        inner.loop_mode
+    }
+
+    /// Changes what happens to tracks once they finish, applying it to the current track.
+    pub fn set_loop_mode(&self, mode: LoopMode) -> TrackResult<()> {
+        let mut inner = self.inner.lock();
+
+        inner.loop_mode = mode;
+        match inner.tracks.front() {
+            Some(current) if mode == LoopMode::Track => current.enable_loop(),
+            Some(current) => current.disable_loop(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns whether upcoming tracks take turns between the people who added them.
+    pub fn fair_mode(&self) -> bool {
+        let inner = self.inner.lock();
+
+        inner.fair
+    }
+
+    /// Stops sending changes to the store until [`release_store`] is called.
+    ///
+    /// This keeps a half-restored queue from replacing the saved one it's being restored from.
+    ///
+    /// [`release_store`]: TrackQueue::release_store
+    pub fn hold_store(&self) {
+        let mut inner = self.inner.lock();
+
+        inner.store_held = true;
+    }
+
+    /// Sends changes to the store again, starting with the queue as it is now.
+    pub fn release_store(&self) {
+        let mut inner = self.inner.lock();
+
+        inner.store_held = false;
+        inner.sync();
+    }
+
+    /// Turns fair mode on or off.
+    ///
+    /// In fair mode, each new track goes after everyone else's track of the same turn, so
+    /// one person adding a lot of tracks can't keep others waiting. Turning it on puts the
+    /// tracks already waiting into that order.
+    ///
+    /// The store is only told if that moved anything. A session is started in fair mode
+    /// before a saved queue is restored into it, and an empty queue sent to the store then
+    /// would forget the saved one.
+    pub fn set_fair_mode(&self, fair: bool) {
+        let mut inner = self.inner.lock();
+
+        inner.fair = fair;
+        if fair && inner.fair_order() {
+            inner.sync();
+        }
+    }
+
+    /// Starts telling the returned receiver about each track the queue moves on to once the
+    /// one before it ends, and sends `None` when it runs out. Tracks which start playing
+    /// straight away, because the queue was empty or they were put in front, aren't sent.
+    ///
+    /// Only the latest receiver is told.
+    pub fn announcements(&self) -> mpsc::UnboundedReceiver<Option<StoredTrack>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut inner = self.inner.lock();
+
+        inner.announcer = Some(tx);
+        rx
+    }
+
+    /// Starts telling the returned receiver about the last track played whenever the queue
+    /// finishes its last track. Stopping the queue doesn't count as running out.
+    ///
+    /// Only the latest receiver is told.
+    pub fn run_outs(&self) -> mpsc::UnboundedReceiver<StoredTrack> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut inner = self.inner.lock();
+
+        inner.run_out = Some(tx);
+        rx
+    }
+
+    /// Returns the tracks which finished playing in this queue, newest first.
+    pub fn history(&self) -> Vec<PlayedTrack> {
+        let inner = self.inner.lock();
+
+        inner.history.iter().cloned().collect()
+    }
+
+    /// Returns the filters applied to every track.
+    pub fn filters(&self) -> Filters {
+        let inner = self.inner.lock();
+
+        inner.filters.clone()
+    }
+
+    /// Changes the filters applied to every track.
+    ///
+    /// The current track keeps playing as it was until it's recreated with [`recreate_current`].
+    ///
+    /// [`recreate_current`]: TrackQueue::recreate_current
+    pub fn set_filters(&self, filters: Filters) {
+        let mut inner = self.inner.lock();
+
+        inner.filters = filters;
+        inner.filtered.clear();
+        inner.preloaded = None;
+    }
+
+    /// Swaps the current track for a fresh one with the queue's filters, starting at `position`.
+    ///
+    /// If it can't be made, the old track carries on instead.
+    pub async fn recreate_current(&self, position: Duration, paused: bool) -> Result<(), String> {
+        match self.current() {
+            Some(current) => self.recreate(current, position, paused).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Swaps a queued track for a fresh one with the queue's filters.
+    ///
+    /// Tracks other than the current one stay paused, but start loading straight away.
+    async fn recreate(
+        &self,
+        old: TrackHandle,
+        position: Duration,
+        paused: bool,
+    ) -> Result<(), String> {
+        let (guild_id, metadata, request, call, filters) = {
+            let inner = self.inner.lock();
+            let queued = match inner.tracks.iter().find(|q| q.uuid() == old.uuid()) {
+                Some(queued) => queued,
+                None => return Ok(()),
+            };
+            let call = match inner.call.as_ref().and_then(Weak::upgrade) {
+                Some(call) => call,
+                None => return Ok(()),
+            };
+
+            (
+                inner.guild_id,
+                queued.metadata().clone(),
+                *queued.request(),
+                call,
+                inner.filters.clone(),
+            )
+        };
+
+        let source = match &metadata.source_url {
+            Some(url) => filtered_source(guild_id, url, &filters).await,
+            None => Err("This track can't be played with filters.".to_string()),
+        };
+        let source = match source {
+            Ok(source) => source,
+            Err(why) => {
+                warn!(
+                    "Could not recreate {:?} in guild {}: {}",
+                    metadata.source_url, guild_id, why
+                );
+                if !paused {
+                    let _ = old.play();
+                }
+
+                return Err(why);
+            }
+        };
+
+        let (mut track, handle) = tracks::create_player(source);
+        if paused {
+            track.pause();
+        }
+
+        let pos = track.position().to_owned();
+        track
+            .events
+            .as_mut()
+            .expect("Queue inspecting EventStore on new Track: did not exist.")
+            .add_event(
+                EventData::new(
+                    Event::Track(TrackEvent::End),
+                    QueueHandler {
+                        remote_lock: self.inner.clone(),
+                    },
+                ),
+                pos,
+            );
+
+        let current = {
+            let mut inner = self.inner.lock();
+
+            // the queue might have moved on while the source was loading
+            let index = match inner.tracks.iter().position(|q| q.uuid() == old.uuid()) {
+                Some(index) => index,
+                None => return Ok(()),
+            };
+            inner.tracks[index] = Queued(handle.clone(), metadata, request);
+
+            if index == 0 && inner.loop_mode == LoopMode::Track {
+                let _ = handle.enable_loop();
+            } else if index > 0 {
+                track.pause();
+            }
+            if inner
+                .preloaded
+                .as_ref()
+                .map_or(false, |p| p.uuid() == old.uuid())
+            {
+                inner.preloaded = Some(handle.clone());
+            }
+
+            inner.filtered.push(handle.clone());
+            let origin = inner.origin(&old);
+            inner.origins.push((handle.clone(), origin));
+            let TrackQueueCore {
+                tracks,
+                filtered,
+                origins,
+                ..
+            } = &mut *inner;
+            filtered.retain(|f| tracks.iter().any(|q| q.uuid() == f.uuid()));
+            origins.retain(|(h, _)| tracks.iter().any(|q| q.uuid() == h.uuid()));
+
+            inner.sync();
+            index == 0
+        };
+
+        // the old track's end event is ignored, as it isn't at the front anymore
+        let _ = old.stop();
+        call.lock().await.play(track);
+
+        if position > Duration::default() {
+            let _ = handle.seek_time(position);
+        }
+        if !current {
+            let _ = handle.make_playable();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the current track, its length and the track after it, for as long as the queue is going to move on.
+    pub fn upcoming(&self) -> Option<(TrackHandle, Option<Duration>, TrackHandle)> {
+        let inner = self.inner.lock();
+
+        if inner.loop_mode == LoopMode::Track {
+            return None;
+        }
+
+        let current = inner.tracks.get(0)?;
+        let next = inner.tracks.get(1)?;
+
+        Some((current.handle(), current.metadata().duration, next.handle()))
+    }
+
+    /// Starts loading the next track, so it can play as soon as the current one ends.
+    ///
+    /// This only happens once per track.
+    pub async fn preload_next(&self) {
+        let (next, needs_filters) = {
+            let mut inner = self.inner.lock();
+            let next = match inner.tracks.get(1) {
+                Some(next) => next.handle(),
+                None => return,
+            };
+
+            if inner
+                .preloaded
+                .as_ref()
+                .map_or(false, |p| p.uuid() == next.uuid())
+            {
+                return;
+            }
+            inner.preloaded = Some(next.clone());
+
+            let needs_filters = inner.needs_filters(&next);
+            (next, needs_filters)
+        };
+
+        if needs_filters {
+            if let Err(why) = self.recreate(next, Duration::default(), true).await {
+                warn!("Could not preload the next track: {}", why);
+            }
+        } else {
+            let _ = next.make_playable();
+        }
+    }
+
+    /// Marks the current track as fading out, returning it along with the track to fade into.
+    ///
+    /// Returns `None` once a crossfade has already started, or if the next track isn't ready for one.
+    pub fn start_crossfade(&self) -> Option<(TrackHandle, TrackHandle)> {
+        let mut inner = self.inner.lock();
+
+        let current = inner.tracks.get(0)?.handle();
+        let next = inner.tracks.get(1)?.handle();
+
+        // the next track would be made again from the start once it's current
+        if inner.needs_filters(&next) {
+            return None;
+        }
+        if inner
+            .crossfading
+            .as_ref()
+            .map_or(false, |c| c.uuid() == current.uuid())
+        {
+            return None;
+        }
+        inner.crossfading = Some(current.clone());
+
+        Some((current, next))
+    }
+
+    /// Recreates a finished track from its source, and puts it at the back of the queue.
+    async fn requeue(self, old: Queued, call: Arc<AsyncMutex<Call>>) {
+        let url = match &old.metadata().source_url {
+            Some(url) => url.clone(),
+            None => return,
+        };
+
+        let guild_id = self.inner.lock().guild_id;
+        match get_source(guild_id, &url).await {
+            Ok(source) => {
+                let mut handler = call.lock().await;
+                self.add_source(source, *old.request(), &mut handler);
+            }
+            Err(why) => warn!("Could not requeue {}: {}", url, why),
+        }
+    }
+
     /// Returns a list of currently queued tracks.
     ///
     /// Does not allow for modification of the queue, instead returns a snapshot of the queue at the time of calling.
@@ -269,9 +965,83 @@ impl TrackQueue {
 
         inner.tracks.iter().map(|q| q.handle()).collect()
     }
+
+    /// Returns what's in the queue and who asked for it, without keeping the queue locked.
+    pub fn snapshot(&self) -> QueueSnapshot {
+        let inner = self.inner.lock();
+
+        inner.snapshot()
+    }
+
+    /// Returns the track a recreated track was first queued as, so it can be told apart from
+    /// a new one. Tracks which were never recreated are their own origin.
+    pub fn origin(&self, handle: &TrackHandle) -> TrackHandle {
+        let inner = self.inner.lock();
+
+        inner.origin(handle)
+    }
 }
 
 impl TrackQueueCore {
+    fn origin(&self, handle: &TrackHandle) -> TrackHandle {
+        self.origins
+            .iter()
+            .find(|(h, _)| h.uuid() == handle.uuid())
+            .map_or_else(|| handle.clone(), |(_, origin)| origin.clone())
+    }
+
+    /// Adds a finished track to the history, and sends it on to the store.
+    fn remember(&mut self, queued: &Queued) {
+        let played = PlayedTrack {
+            track: StoredTrack::from(queued),
+            played_at: Utc::now(),
+        };
+
+        if let Some(store) = &self.history_store {
+            let _ = store.send(played.clone());
+        }
+
+        self.history.push_front(played);
+        self.history.truncate(HISTORY_LENGTH);
+    }
+
+    /// Where a new track from `user` goes in fair mode.
+    fn fair_position(&self, user: UserId) -> usize {
+        let requesters = self
+            .tracks
+            .iter()
+            .map(Queued::requester)
+            .collect::<Vec<_>>();
+
+        fair_slot(&requesters, user)
+    }
+
+    /// Puts upcoming tracks in turns, keeping the order each person added theirs in.
+    ///
+    /// Returns whether any track moved.
+    fn fair_order(&mut self) -> bool {
+        let requesters = self
+            .tracks
+            .iter()
+            .map(Queued::requester)
+            .collect::<Vec<_>>();
+        let order = fair_permutation(&requesters);
+        if order.iter().enumerate().all(|(i, j)| i == *j) {
+            return false;
+        }
+
+        let mut upcoming = self.tracks.drain(1..).map(Some).collect::<Vec<_>>();
+        self.tracks
+            .extend(order.into_iter().filter_map(|i| upcoming[i].take()));
+
+        true
+    }
+
+    /// Whether a track still has to be made again to pick up the queue's filters.
+    fn needs_filters(&self, handle: &TrackHandle) -> bool {
+        !self.filters.is_empty() && !self.filtered.iter().any(|f| f.uuid() == handle.uuid())
+    }
+
     /// Skip to the next track in the queue, if it exists.
     fn stop_current(&self) -> TrackResult<()> {
         if let Some(handle) = self.tracks.front() {
@@ -280,4 +1050,145 @@ impl TrackQueueCore {
             Ok(())
         }
     }
+
+    fn snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            tracks: self.tracks.iter().map(StoredTrack::from).collect(),
+            current: self.tracks.front().map(|q| q.handle()),
+        }
+    }
+
+    /// Tells the announcer about the track now at the front of the queue.
+    fn announce(&self) {
+        if let Some(announcer) = &self.announcer {
+            let _ = announcer.send(self.tracks.front().map(StoredTrack::from));
+        }
+    }
+
+    /// Sends the current state of the queue to its store, if it has one.
+    fn sync(&self) {
+        if self.store_held {
+            return;
+        }
+
+        if let Some(store) = &self.store {
+            let _ = store.send(self.snapshot());
+        }
+    }
+}
+
+/// Which turn each upcoming track is in, given who asked for each track in the queue.
+///
+/// The track playing now counts as its requester's first turn, so their upcoming tracks
+/// start from the second.
+fn fair_turns(requesters: &[UserId]) -> Vec<usize> {
+    let mut taken = HashMap::new();
+    if let Some(head) = requesters.first() {
+        taken.insert(*head, 1);
+    }
+
+    requesters
+        .iter()
+        .skip(1)
+        .map(|user| {
+            let turns = taken.entry(*user).or_insert(0);
+            *turns += 1;
+            *turns - 1
+        })
+        .collect()
+}
+
+/// Where a new track from `user` goes in a queue requested by `requesters`, which is after
+/// every upcoming track in the same turn as it or an earlier one.
+fn fair_slot(requesters: &[UserId], user: UserId) -> usize {
+    let turns = fair_turns(requesters);
+    let turn = requesters.iter().filter(|r| **r == user).count();
+
+    turns
+        .iter()
+        .rposition(|t| *t <= turn)
+        // upcoming tracks start after the one playing
+        .map_or(1, |i| i + 2)
+}
+
+/// The order upcoming tracks go in to take turns, as indices into the upcoming tracks.
+fn fair_permutation(requesters: &[UserId]) -> Vec<usize> {
+    let mut order = fair_turns(requesters)
+        .into_iter()
+        .enumerate()
+        .map(|(i, turn)| (turn, i))
+        .collect::<Vec<_>>();
+    // the sort is stable, so tracks in the same turn keep their order
+    order.sort_by_key(|(turn, _)| *turn);
+
+    order.into_iter().map(|(_, i)| i).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    const A: UserId = UserId(1);
+    const B: UserId = UserId(2);
+    const C: UserId = UserId(3);
+
+    #[test]
+    fn playing_track_counts_as_a_turn() {
+        // A is playing, so B's track goes before A's next one
+        assert_eq!(fair_turns(&[A, A, B]), vec![1, 0]);
+        assert_eq!(fair_permutation(&[A, A, B]), vec![1, 0]);
+    }
+
+    #[test]
+    fn tracks_take_turns_in_order_added() {
+        assert_eq!(fair_permutation(&[C, A, A, A, B, B]), vec![0, 3, 1, 4, 2]);
+        assert_eq!(fair_permutation(&[A, B, C, B, C]), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn restoring_in_fair_mode_keeps_the_saved_queue() {
+        let (store, mut saved) = watch::channel(QueueSnapshot::default());
+        let queue = TrackQueue {
+            inner: Arc::new(Mutex::new(TrackQueueCore {
+                store: Some(store),
+                ..Default::default()
+            })),
+        };
+
+        // the order a restore goes in: the session starts in fair mode, then the store is held
+        queue.set_fair_mode(true);
+        queue.hold_store();
+
+        assert!(queue.fair_mode());
+        assert!(
+            saved.changed().now_or_never().is_none(),
+            "the empty queue was sent to the store"
+        );
+    }
+
+    #[test]
+    fn fair_order_keeps_an_empty_or_single_queue() {
+        assert!(fair_permutation(&[]).is_empty());
+        assert!(fair_permutation(&[A]).is_empty());
+    }
+
+    #[test]
+    fn new_track_goes_after_its_turn() {
+        // only the playing track, so it's next
+        assert_eq!(fair_slot(&[A], B), 1);
+        // A already had a turn with the playing track, so B goes first
+        assert_eq!(fair_slot(&[A, A], B), 1);
+        assert_eq!(fair_slot(&[A, A], A), 2);
+        // B's second track waits for C's first
+        assert_eq!(fair_slot(&[A, B, C], B), 3);
+        assert_eq!(fair_slot(&[A, B, C], C), 3);
+        assert_eq!(fair_slot(&[A, B, C], A), 3);
+    }
+
+    #[test]
+    fn new_track_fits_in_before_later_turns() {
+        // B has two waiting, so C's first goes after B's first
+        assert_eq!(fair_slot(&[A, B, B], C), 2);
+    }
 }
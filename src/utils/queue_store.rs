@@ -0,0 +1,394 @@
+use chrono::{DateTime, Utc};
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use songbird::tracks::TrackHandle;
+use sqlx::PgPool;
+use tokio::sync::{mpsc, watch};
+
+use std::time::Duration;
+
+use crate::utils::queue::{Queued, Request};
+
+/// How often the playback position of the current track gets written back.
+const ELAPSED_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+/// How many days of play history are kept for each guild, which is what `mostplayed` counts.
+const HISTORY_KEPT_DAYS: i64 = 90;
+
+/// A queue entry as it is kept in the database.
+#[derive(Clone, Debug)]
+pub struct StoredTrack {
+    pub source_url: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub thumbnail: Option<String>,
+    pub duration: Option<Duration>,
+    pub requester: UserId,
+    /// Where and when the track was asked for, which older entries don't have.
+    pub requested_in: Option<ChannelId>,
+    pub requested_at: Option<DateTime<Utc>>,
+}
+
+impl From<&Queued> for StoredTrack {
+    fn from(queued: &Queued) -> Self {
+        let meta = queued.metadata();
+        Self {
+            source_url: meta.source_url.clone().unwrap_or_default(),
+            title: meta.title.clone().or_else(|| meta.track.clone()),
+            artist: meta.artist.clone(),
+            thumbnail: meta.thumbnail.clone(),
+            duration: meta.duration,
+            requester: queued.requester(),
+            requested_in: Some(queued.request().channel),
+            requested_at: Some(queued.request().at),
+        }
+    }
+}
+
+impl StoredTrack {
+    /// Who asked for the track, if it was saved with where and when they did.
+    pub fn request(&self) -> Option<Request> {
+        Some(Request {
+            user: self.requester,
+            channel: self.requested_in?,
+            at: self.requested_at?,
+        })
+    }
+}
+
+/// A track which finished playing, as it's kept in the play history.
+#[derive(Clone, Debug)]
+pub struct PlayedTrack {
+    pub track: StoredTrack,
+    pub played_at: DateTime<Utc>,
+}
+
+/// State of a [`TrackQueue`] sent to its store whenever it changes.
+///
+/// [`TrackQueue`]: crate::utils::queue::TrackQueue
+#[derive(Clone, Debug, Default)]
+pub struct QueueSnapshot {
+    pub tracks: Vec<StoredTrack>,
+    pub current: Option<TrackHandle>,
+}
+
+/// A voice session which was still running when the bot went down.
+#[derive(Debug)]
+pub struct SavedSession {
+    pub guild_id: GuildId,
+    pub voice_channel: ChannelId,
+    pub text_channel: ChannelId,
+    pub elapsed: Duration,
+    pub tracks: Vec<StoredTrack>,
+}
+
+/// Mirrors a guild's queue into Postgres, so it can be picked up again after a restart.
+#[derive(Clone, Debug)]
+pub struct QueueStore {
+    pool: PgPool,
+    guild_id: GuildId,
+    voice_channel: ChannelId,
+    text_channel: ChannelId,
+}
+
+impl QueueStore {
+    pub fn new(
+        pool: PgPool,
+        guild_id: GuildId,
+        voice_channel: ChannelId,
+        text_channel: ChannelId,
+    ) -> Self {
+        Self {
+            pool,
+            guild_id,
+            voice_channel,
+            text_channel,
+        }
+    }
+
+    pub fn guild_id(&self) -> GuildId {
+        self.guild_id
+    }
+
+    /// Starts the background writer for this store.
+    ///
+    /// Snapshots sent through the returned channel are written in order, and the
+    /// position of the current track is saved periodically. The writer stops once
+    /// the sender is dropped.
+    pub(crate) fn spawn(self) -> watch::Sender<QueueSnapshot> {
+        let (tx, mut rx) = watch::channel(QueueSnapshot::default());
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(ELAPSED_SAVE_INTERVAL);
+
+            loop {
+                tokio::select! {
+                    changed = rx.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+
+                        let snapshot = rx.borrow().clone();
+                        if let Err(why) = self.write(&snapshot).await {
+                            error!("Could not save queue for guild {}: {:?}", self.guild_id, why);
+                        }
+                    }
+                    _ = ticker.tick() => {
+                        let current = rx.borrow().current.clone();
+                        if let Some(handle) = current {
+                            if let Ok(info) = handle.get_info().await {
+                                if let Err(why) = self.write_elapsed(&handle, info.position).await {
+                                    error!("Could not save track position for guild {}: {:?}", self.guild_id, why);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        });
+
+        tx
+    }
+
+    /// Starts the background writer for the guild's play history.
+    ///
+    /// Tracks sent through the returned channel are written as they come in, and anything
+    /// older than [`HISTORY_KEPT_DAYS`] is cleared out of the guild's history as they are.
+    /// The writer stops once the sender is dropped.
+    pub(crate) fn spawn_history(&self) -> mpsc::UnboundedSender<PlayedTrack> {
+        let (tx, mut rx) = mpsc::unbounded_channel::<PlayedTrack>();
+        let pool = self.pool.clone();
+        let guild_id = self.guild_id;
+
+        tokio::spawn(async move {
+            while let Some(played) = rx.recv().await {
+                let track = played.track;
+                let written = sqlx::query!(
+                    "
+                insert into play_history(guild_id, source_url, title, artist, thumbnail, duration, requester, played_at)
+                values($1, $2, $3, $4, $5, $6, $7, $8)
+                ",
+                    guild_id.0 as i64,
+                    track.source_url,
+                    track.title,
+                    track.artist,
+                    track.thumbnail,
+                    track.duration.map(|d| d.as_millis() as i64),
+                    track.requester.0 as i64,
+                    played.played_at
+                )
+                .execute(&pool)
+                .await;
+
+                let written = match written {
+                    Ok(_) => sqlx::query!(
+                        "
+                delete from play_history
+                where guild_id = $1 and played_at < $2
+                ",
+                        guild_id.0 as i64,
+                        played.played_at - chrono::Duration::days(HISTORY_KEPT_DAYS)
+                    )
+                    .execute(&pool)
+                    .await
+                    .map(|_| ()),
+                    Err(why) => Err(why),
+                };
+
+                if let Err(why) = written {
+                    error!(
+                        "Could not save play history for guild {}: {:?}",
+                        guild_id, why
+                    );
+                }
+            }
+        });
+
+        tx
+    }
+
+    /// Replaces the saved queue. An empty queue forgets the session entirely.
+    ///
+    /// The saved position is reset whenever the track at the front changes, so it's never
+    /// applied to a track it wasn't taken from.
+    async fn write(&self, snapshot: &QueueSnapshot) -> Result<(), sqlx::Error> {
+        let tracks = &snapshot.tracks;
+        if tracks.is_empty() {
+            return forget_session(&self.pool, self.guild_id).await;
+        }
+
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            "
+        insert into voice_sessions(guild_id, voice_channel, text_channel, current_track)
+        values($1, $2, $3, $4)
+        on conflict (guild_id) do update
+        set voice_channel = $2, text_channel = $3, current_track = $4,
+            elapsed = case when voice_sessions.current_track is distinct from $4 then 0 else voice_sessions.elapsed end,
+            updated_at = now()
+        ",
+            self.guild_id.0 as i64,
+            self.voice_channel.0 as i64,
+            self.text_channel.0 as i64,
+            snapshot.current.as_ref().map(|h| h.uuid().to_string())
+        )
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query!(
+            "
+        delete from voice_queue
+        where guild_id = $1
+        ",
+            self.guild_id.0 as i64
+        )
+        .execute(&mut tx)
+        .await?;
+
+        for (i, track) in tracks.iter().enumerate() {
+            sqlx::query!(
+                "
+            insert into voice_queue(guild_id, position, source_url, title, artist, thumbnail, duration, requester, requested_in, requested_at)
+            values($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            ",
+                self.guild_id.0 as i64,
+                i as i32,
+                track.source_url,
+                track.title,
+                track.artist,
+                track.thumbnail,
+                track.duration.map(|d| d.as_millis() as i64),
+                track.requester.0 as i64,
+                track.requested_in.map(|c| c.0 as i64),
+                track.requested_at
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        tx.commit().await
+    }
+
+    /// Saves how far into `track` playback is, unless the saved queue has since moved on from it.
+    async fn write_elapsed(
+        &self,
+        track: &TrackHandle,
+        elapsed: Duration,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "
+        update voice_sessions
+        set elapsed = $1, updated_at = now()
+        where guild_id = $2 and current_track = $3
+        ",
+            elapsed.as_millis() as i64,
+            self.guild_id.0 as i64,
+            track.uuid().to_string()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Loads every session that was left behind by the last run.
+pub async fn saved_sessions(pool: &PgPool) -> Result<Vec<SavedSession>, sqlx::Error> {
+    let sessions = sqlx::query!(
+        "
+    select guild_id, voice_channel, text_channel, elapsed
+    from voice_sessions
+    "
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut saved = Vec::with_capacity(sessions.len());
+    for session in sessions {
+        let tracks = sqlx::query!(
+            "
+        select source_url, title, artist, thumbnail, duration, requester, requested_in, requested_at
+        from voice_queue
+        where guild_id = $1
+        order by position
+        ",
+            session.guild_id
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|t| StoredTrack {
+            source_url: t.source_url,
+            title: t.title,
+            artist: t.artist,
+            thumbnail: t.thumbnail,
+            duration: t.duration.map(|d| Duration::from_millis(d as u64)),
+            requester: UserId(t.requester as u64),
+            requested_in: t.requested_in.map(|c| ChannelId(c as u64)),
+            requested_at: t.requested_at,
+        })
+        .collect();
+
+        saved.push(SavedSession {
+            guild_id: GuildId(session.guild_id as u64),
+            voice_channel: ChannelId(session.voice_channel as u64),
+            text_channel: ChannelId(session.text_channel as u64),
+            elapsed: Duration::from_millis(session.elapsed as u64),
+            tracks,
+        });
+    }
+
+    Ok(saved)
+}
+
+/// Loads the most recently played tracks in a guild, newest first.
+pub async fn play_history(
+    pool: &PgPool,
+    guild_id: GuildId,
+    limit: i64,
+) -> Result<Vec<PlayedTrack>, sqlx::Error> {
+    let tracks = sqlx::query!(
+        "
+    select source_url, title, artist, thumbnail, duration, requester, played_at
+    from play_history
+    where guild_id = $1
+    order by played_at desc
+    limit $2
+    ",
+        guild_id.0 as i64,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(tracks
+        .into_iter()
+        .map(|t| PlayedTrack {
+            track: StoredTrack {
+                source_url: t.source_url,
+                title: t.title,
+                artist: t.artist,
+                thumbnail: t.thumbnail,
+                duration: t.duration.map(|d| Duration::from_millis(d as u64)),
+                requester: UserId(t.requester as u64),
+                requested_in: None,
+                requested_at: None,
+            },
+            played_at: t.played_at,
+        })
+        .collect())
+}
+
+/// Drops a guild's saved session, along with its queue.
+pub async fn forget_session(pool: &PgPool, guild_id: GuildId) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "
+    delete from voice_sessions
+    where guild_id = $1
+    ",
+        guild_id.0 as i64
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
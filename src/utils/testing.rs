@@ -0,0 +1,165 @@
+//! Stand-ins for the programs and APIs the bot talks to, for tests.
+
+use std::{
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+/// Writes a shell script which prints `output` whatever it's run with, and returns its path.
+///
+/// The arguments it was last run with are kept next to it, one per line, and can be read
+/// with [`fake_program_args`].
+pub fn fake_program(name: &str, output: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("maki-test-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let output_path = dir.join("output");
+    fs::write(&output_path, output).unwrap();
+
+    let path = dir.join(name);
+    fs::write(
+        &path,
+        format!(
+            "#!/bin/sh\nprintf '%s\\n' \"$@\" > '{}'\ncat '{}'\n",
+            dir.join("args").display(),
+            output_path.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+    path
+}
+
+/// The arguments a [`fake_program`] was last run with.
+pub fn fake_program_args(path: &Path) -> Vec<String> {
+    fs::read_to_string(path.with_file_name("args"))
+        .unwrap_or_default()
+        .lines()
+        .map(|l| l.to_string())
+        .collect()
+}
+
+/// Answers the next HTTP request on a local port with `body` as JSON.
+///
+/// Returns the address to send the request to, and the request line it was sent with.
+pub async fn serve_json(body: &str) -> (String, JoinHandle<String>) {
+    let (url, requests) = serve_json_in_turn(&[body]).await;
+
+    (
+        url,
+        tokio::spawn(async move { requests.await.unwrap().remove(0) }),
+    )
+}
+
+/// Answers the next few HTTP requests on a local port with each of `bodies` in turn.
+///
+/// Returns the address to send the requests to, and the request lines in the order they came.
+pub async fn serve_json_in_turn(bodies: &[&str]) -> (String, JoinHandle<Vec<String>>) {
+    let (url, heads) = serve_json_heads(bodies).await;
+
+    (url, request_lines(heads))
+}
+
+/// Like [`serve_json_in_turn`], but gives back the whole head of each request, headers included.
+pub async fn serve_json_heads(bodies: &[&str]) -> (String, JoinHandle<Vec<String>>) {
+    let responses = bodies
+        .iter()
+        .map(|body| {
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        })
+        .collect::<Vec<_>>();
+
+    serve_heads("127.0.0.1:0", responses).await
+}
+
+/// Answers the next few HTTP requests on `address` with each of `responses`, which are sent as
+/// they are, status line and headers included.
+///
+/// Returns the address to send the requests to, and the request lines in the order they came.
+pub async fn serve_in_turn(
+    address: &str,
+    responses: Vec<String>,
+) -> (String, JoinHandle<Vec<String>>) {
+    let (url, heads) = serve_heads(address, responses).await;
+
+    (url, request_lines(heads))
+}
+
+/// Answers requests like [`serve_in_turn`], giving back the whole head of each one.
+async fn serve_heads(address: &str, responses: Vec<String>) -> (String, JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind(address).await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+
+    let handle = tokio::spawn(async move {
+        let mut requests = Vec::with_capacity(responses.len());
+        for response in responses {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            requests.push(read_request(&mut socket).await);
+
+            socket.write_all(response.as_bytes()).await.unwrap();
+            let _ = socket.shutdown().await;
+        }
+
+        requests
+    });
+
+    (url, handle)
+}
+
+/// Keeps only the request line of each head.
+fn request_lines(heads: JoinHandle<Vec<String>>) -> JoinHandle<Vec<String>> {
+    tokio::spawn(async move {
+        heads
+            .await
+            .unwrap()
+            .iter()
+            .map(|head| head.lines().next().unwrap_or_default().to_string())
+            .collect()
+    })
+}
+
+/// Reads a whole request, body included, and returns its head.
+async fn read_request(socket: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    let head_end = loop {
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        let read = socket.read(&mut buf).await.unwrap();
+        if read == 0 {
+            break request.len();
+        }
+        request.extend_from_slice(&buf[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&request[..head_end]).to_string();
+    let length = head
+        .lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+
+    // closing with some of the body unread would reset the connection under the client
+    while request.len() < head_end + length {
+        let read = socket.read(&mut buf).await.unwrap();
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    head
+}
@@ -1,2 +1,95 @@
+use std::time::Duration;
 
-use chrono::{DateTime, Duration, TimeZone, Utc};
+/// Formats a duration as `m:ss`, or `h:mm:ss` once it goes past an hour.
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, (secs % 3600) / 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// Why a timestamp couldn't be read.
+#[derive(Debug, PartialEq)]
+pub enum TimestampError {
+    /// It isn't a timestamp at all.
+    Invalid,
+    /// It is one, but too big to be a point in any track.
+    OutOfRange,
+}
+
+/// Parses a timestamp like `90`, `1:30` or `1:01:30` into a duration.
+pub fn parse_timestamp(s: &str) -> Result<Duration, TimestampError> {
+    let mut secs: u64 = 0;
+    let parts = s.trim().split(':').collect::<Vec<_>>();
+    if parts.is_empty() || parts.len() > 3 {
+        return Err(TimestampError::Invalid);
+    }
+
+    for (i, part) in parts.iter().enumerate() {
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(TimestampError::Invalid);
+        }
+        let value = part
+            .parse::<u64>()
+            .map_err(|_| TimestampError::OutOfRange)?;
+        // everything after the first part is capped at 59
+        if i > 0 && value >= 60 {
+            return Err(TimestampError::Invalid);
+        }
+        secs = secs
+            .checked_mul(60)
+            .and_then(|s| s.checked_add(value))
+            .ok_or(TimestampError::OutOfRange)?;
+    }
+
+    Ok(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_timestamp("1:30"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_timestamp(" 1:01:30 "), Ok(Duration::from_secs(3690)));
+        assert_eq!(parse_timestamp("0:00"), Ok(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn rejects_malformed_timestamps() {
+        assert_eq!(parse_timestamp(""), Err(TimestampError::Invalid));
+        assert_eq!(parse_timestamp("1:60"), Err(TimestampError::Invalid));
+        assert_eq!(parse_timestamp("1:2:3:4"), Err(TimestampError::Invalid));
+        assert_eq!(parse_timestamp("-1"), Err(TimestampError::Invalid));
+        assert_eq!(parse_timestamp("+5"), Err(TimestampError::Invalid));
+        assert_eq!(parse_timestamp("1::30"), Err(TimestampError::Invalid));
+        assert_eq!(parse_timestamp("abc"), Err(TimestampError::Invalid));
+    }
+
+    #[test]
+    fn rejects_timestamps_that_overflow() {
+        assert_eq!(
+            parse_timestamp("18446744073709551616"),
+            Err(TimestampError::OutOfRange)
+        );
+        assert_eq!(
+            parse_timestamp("18446744073709551615:00"),
+            Err(TimestampError::OutOfRange)
+        );
+        assert_eq!(
+            parse_timestamp("307445734561825861:59:59"),
+            Err(TimestampError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(format_duration(Duration::from_secs(5)), "0:05");
+        assert_eq!(format_duration(Duration::from_secs(90)), "1:30");
+        assert_eq!(format_duration(Duration::from_secs(3690)), "1:01:30");
+    }
+}